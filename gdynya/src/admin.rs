@@ -0,0 +1,130 @@
+//! State only admins change, kept in the store.
+
+use axum::http::StatusCode;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    HttpError,
+    api_schema::CrateName,
+    auth::{
+        Principal, now,
+        rules::{glob_match, normalize},
+    },
+};
+
+/// A crate name nobody may publish under, new or existing.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct BlockedName {
+    /// Normalized crate name.
+    pub name: String,
+    /// Shown to whoever tries to publish.
+    pub reason: String,
+    pub blocked_by: Option<String>,
+    pub blocked_at: u64,
+}
+
+impl BlockedName {
+    pub fn new(name: &CrateName, reason: String, principal: &Principal) -> Self {
+        Self {
+            name: name.normalized.clone(),
+            reason,
+            blocked_by: principal.login.clone(),
+            blocked_at: now(),
+        }
+    }
+}
+
+/// Body of `PUT /api/v1/admin/freezes/{id}`.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct FreezeRequest {
+    pub crates: Vec<String>,
+    pub starts_at: u64,
+    pub ends_at: u64,
+    #[serde(default)]
+    pub exempt: Vec<String>,
+    pub message: String,
+}
+
+/// A time during which matching crates may not be published, e.g. a release freeze.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct FreezeWindow {
+    pub id: String,
+    /// Crate names or globs such as `acme-*`.
+    pub crates: Vec<String>,
+    /// Unix time, inclusive.
+    pub starts_at: u64,
+    /// Unix time, exclusive.
+    pub ends_at: u64,
+    /// Logins that may publish anyway, e.g. the release managers.
+    pub exempt: Vec<String>,
+    /// Shown to whoever tries to publish.
+    pub message: String,
+    pub created_by: Option<String>,
+    pub created_at: u64,
+}
+
+impl FreezeWindow {
+    pub fn new(id: &str, req: FreezeRequest, principal: &Principal) -> Result<Self, HttpError> {
+        let invalid = |message: &str| HttpError {
+            error_type: StatusCode::BAD_REQUEST,
+            message: message.to_string(),
+            verbose_message: message.to_string(),
+            contexts: Vec::new(),
+            retry_after: None,
+        };
+        // the ID is part of a store key
+        if id.is_empty()
+            || !id
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        {
+            return Err(invalid(
+                "freeze IDs may only contain ASCII letters, digits, '-' and '_'",
+            ));
+        }
+        if req.crates.is_empty() {
+            return Err(invalid("a freeze needs at least one crate or pattern"));
+        }
+        if req.ends_at <= req.starts_at {
+            return Err(invalid("a freeze must end after it starts"));
+        }
+        Ok(Self {
+            id: id.to_string(),
+            crates: req.crates,
+            starts_at: req.starts_at,
+            ends_at: req.ends_at,
+            exempt: req.exempt,
+            message: req.message,
+            created_by: principal.login.clone(),
+            created_at: now(),
+        })
+    }
+
+    pub fn covers(&self, name: &CrateName, at: u64) -> bool {
+        (self.starts_at..self.ends_at).contains(&at)
+            && self
+                .crates
+                .iter()
+                .any(|pattern| glob_match(&normalize(pattern), &name.normalized))
+    }
+
+    pub fn exempts(&self, principal: &Principal) -> bool {
+        principal
+            .login
+            .as_ref()
+            .is_some_and(|login| self.exempt.contains(login))
+    }
+
+    /// The freeze that keeps `principal` from publishing `name` right now, if any.
+    pub fn stopping<'a>(
+        freezes: &'a [Self],
+        name: &CrateName,
+        principal: &Principal,
+    ) -> Option<&'a Self> {
+        let at = now();
+        freezes
+            .iter()
+            .find(|freeze| freeze.covers(name, at) && !freeze.exempts(principal))
+    }
+}
@@ -56,6 +56,14 @@ impl Config {
             auth_required: true,
         }
     }
+
+    /// Cargo only sends credentials for downloads and index fetches when this is set.
+    pub fn with_auth_required(self, auth_required: bool) -> Self {
+        Self {
+            auth_required,
+            ..self
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
@@ -218,11 +226,14 @@ pub struct RegistryUser {
     pub name: String,
 }
 
+/// Hex SHA-256 of a `.crate` file, as in the index `cksum` field.
+pub fn cksum(body: &[u8]) -> String {
+    use digest::Digest;
+    hex::encode(Sha256::digest(body))
+}
+
 impl GetIndexResponse {
     pub fn new(index: &PostIndexRequest, body: &[u8]) -> Self {
-        use digest::Digest;
-        let mut hasher = Sha256::new();
-        hasher.update(&body);
         Self {
             name: index.name.clone(),
             vers: index.vers.clone(),
@@ -244,7 +255,7 @@ impl GetIndexResponse {
             features: index.features.clone(),
             links: index.links.clone(),
             yanked: false,
-            cksum: hex::encode(hasher.finalize()),
+            cksum: cksum(body),
             v: 2,
             rust_version: index.rust_version.clone(),
         }
@@ -0,0 +1,289 @@
+//! Lasting record of changes to the registry, kept in the store next to the crates.
+//!
+//! Every mutating request leaves one record, whether it succeeded or not.
+
+use axum::http::StatusCode;
+use futures_util::Stream;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    HttpError,
+    api_schema::CrateName,
+    auth::{Principal, Provider, now},
+    redact::redact,
+    store::{AuditOrder, Store},
+};
+
+/// Records an export reads from the store at a time.
+pub const EXPORT_PAGE: usize = 1000;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    Publish,
+    Yank,
+    Unyank,
+    AddOwners,
+    RemoveOwners,
+    /// Replace every owner of a crate.
+    SetOwners,
+    DeleteVersion,
+    Block,
+    Unblock,
+    Freeze,
+    Unfreeze,
+    FlushAuthCache,
+    CreateToken,
+    RevokeToken,
+    /// Issue a publish token for a CI OIDC token.
+    ExchangeOidcToken,
+    RegisterKey,
+    RevokeKey,
+    EnableMaintenance,
+    DisableMaintenance,
+}
+
+impl Action {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Publish => "publish",
+            Self::Yank => "yank",
+            Self::Unyank => "unyank",
+            Self::AddOwners => "add_owners",
+            Self::RemoveOwners => "remove_owners",
+            Self::SetOwners => "set_owners",
+            Self::DeleteVersion => "delete_version",
+            Self::Block => "block",
+            Self::Unblock => "unblock",
+            Self::Freeze => "freeze",
+            Self::Unfreeze => "unfreeze",
+            Self::FlushAuthCache => "flush_auth_cache",
+            Self::CreateToken => "create_token",
+            Self::RevokeToken => "revoke_token",
+            Self::ExchangeOidcToken => "exchange_oidc_token",
+            Self::RegisterKey => "register_key",
+            Self::RevokeKey => "revoke_key",
+            Self::EnableMaintenance => "enable_maintenance",
+            Self::DisableMaintenance => "disable_maintenance",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    #[default]
+    Success,
+    /// Rejected with 401 or 403.
+    Denied,
+    Failed,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct AuditRecord {
+    /// Sorts like the records were written.
+    pub id: String,
+    pub at: u64,
+    /// `None` when the credential could not be resolved to anyone.
+    pub login: Option<String>,
+    pub provider: Provider,
+    /// Registry token ID or PASETO key ID the request was made with.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token_id: Option<String>,
+    /// Client address, see [`crate::axum_aux::ClientIp`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ip: Option<String>,
+    /// Done through `/api/v1/admin`, bypassing the rules.
+    pub admin: bool,
+    pub action: Action,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub crate_name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<semver::Version>,
+    /// Whatever else the action was about, e.g. the new owners.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+    #[serde(default)]
+    pub outcome: Outcome,
+    /// Why it was denied or failed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl AuditRecord {
+    /// A successful `action` by nobody in particular, until told otherwise.
+    pub fn new(action: Action) -> Self {
+        let (id, at) = stamp();
+        Self {
+            id,
+            at,
+            login: None,
+            provider: Provider::Anonymous,
+            token_id: None,
+            ip: None,
+            admin: false,
+            action,
+            crate_name: None,
+            version: None,
+            detail: None,
+            outcome: Outcome::Success,
+            error: None,
+        }
+    }
+
+    /// Date the record to now, when the request is over, so that records are written in
+    /// about the order of their IDs.
+    pub fn finished(self) -> Self {
+        let (id, at) = stamp();
+        Self { id, at, ..self }
+    }
+
+    pub fn by(self, principal: &Principal) -> Self {
+        Self {
+            login: principal.login.clone(),
+            provider: principal.provider,
+            token_id: principal.token_id.clone(),
+            ..self
+        }
+    }
+
+    pub fn by_admin(self) -> Self {
+        Self {
+            admin: true,
+            ..self
+        }
+    }
+
+    pub fn from_ip(self, ip: Option<String>) -> Self {
+        Self { ip, ..self }
+    }
+
+    pub fn with_crate(self, name: &CrateName) -> Self {
+        Self {
+            crate_name: Some(name.normalized.clone()),
+            ..self
+        }
+    }
+
+    pub fn with_version(self, version: &semver::Version) -> Self {
+        Self {
+            version: Some(version.clone()),
+            ..self
+        }
+    }
+
+    pub fn with_detail(self, detail: impl Into<String>) -> Self {
+        Self {
+            detail: Some(detail.into()),
+            ..self
+        }
+    }
+
+    /// Record how the request ended.
+    pub fn with_result<T>(self, result: &Result<T, HttpError>) -> Self {
+        let Err(e) = result else {
+            return Self {
+                outcome: Outcome::Success,
+                error: None,
+                ..self
+            };
+        };
+        let outcome = match e.error_type {
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => Outcome::Denied,
+            _ => Outcome::Failed,
+        };
+        Self {
+            outcome,
+            error: Some(redact(&e.message).into_owned()),
+            ..self
+        }
+    }
+}
+
+fn stamp() -> (String, u64) {
+    let at = now();
+    // the suffix keeps records written within the same second apart
+    let id = format!("{at:020}-{}", hex::encode(rand::rng().random::<[u8; 4]>()));
+    (id, at)
+}
+
+/// Which records to return; every given condition must hold.
+#[derive(Deserialize, Default, Clone, Debug)]
+pub struct AuditFilter {
+    #[serde(rename = "crate")]
+    pub crate_name: Option<CrateName>,
+    pub login: Option<String>,
+    pub action: Option<Action>,
+    pub outcome: Option<Outcome>,
+    pub admin: Option<bool>,
+    /// Unix time, inclusive.
+    pub since: Option<u64>,
+    /// Unix time, exclusive.
+    pub until: Option<u64>,
+}
+
+impl AuditFilter {
+    pub fn matches(&self, record: &AuditRecord) -> bool {
+        self.crate_name
+            .as_ref()
+            .is_none_or(|name| record.crate_name.as_ref() == Some(&name.normalized))
+            && self
+                .login
+                .as_ref()
+                .is_none_or(|login| record.login.as_ref() == Some(login))
+            && self.action.is_none_or(|action| record.action == action)
+            && self.outcome.is_none_or(|outcome| record.outcome == outcome)
+            && self.admin.is_none_or(|admin| record.admin == admin)
+            && self.since.is_none_or(|since| record.at >= since)
+            && self.until.is_none_or(|until| record.at < until)
+    }
+}
+
+/// Up to `limit` records matching `filter`, oldest first, read from `store` a page of
+/// [`EXPORT_PAGE`] at a time so that an export never holds the whole log.
+pub fn export<S: Store + Send + Sync + 'static>(
+    store: S,
+    filter: AuditFilter,
+    limit: Option<usize>,
+) -> impl Stream<Item = Result<Vec<AuditRecord>, HttpError>> + Send {
+    // (`at`, ID) of the last record exported; records from its second can sort after it
+    let start = (
+        store,
+        filter,
+        limit.unwrap_or(usize::MAX),
+        None::<(u64, String)>,
+    );
+    futures_util::stream::try_unfold(start, |(store, filter, remaining, last)| async move {
+        if remaining == 0 {
+            return Ok(None);
+        }
+        let since = last.as_ref().map(|(at, _)| *at).or(filter.since);
+        let page_size = remaining.min(EXPORT_PAGE);
+        let page = store
+            .list_audit(since, AuditOrder::OldestFirst, page_size, |record| {
+                filter.matches(record) && last.as_ref().is_none_or(|(_, id)| &record.id > id)
+            })
+            .await?;
+        let Some(newest) = page.last() else {
+            return Ok(None);
+        };
+        let last = Some((newest.at, newest.id.clone()));
+        // a short page is the end of the log
+        let remaining = match page.len() < page_size {
+            true => 0,
+            false => remaining - page.len(),
+        };
+        Ok(Some((page, (store, filter, remaining, last))))
+    })
+}
+
+/// One JSON object per line, as served by the export endpoint.
+pub fn to_jsonl(records: &[AuditRecord]) -> String {
+    let mut jsonl = String::new();
+    for record in records {
+        jsonl.push_str(&serde_json::to_string(record).expect("records serialize"));
+        jsonl.push('\n');
+    }
+    jsonl
+}
@@ -1,201 +1,1310 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, Instant},
+};
 
+use arc_swap::ArcSwap;
 use axum::http::StatusCode;
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
+use tracing::{debug, warn};
+use zeroize::Zeroizing;
 
+use super::{
+    Mutation, Permissions, Principal, Provider, Scope, TokenHash, granted, rules::AuthRules,
+};
 use crate::{
-    HttpError, ResponseValidatable, ToHttpError, ToHttpErrorOption,
+    HttpError, ResponseValidatable, ToHttpError,
     api_schema::{CrateName, RegistryUser},
     axum_aux::RawAuthorization,
 };
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(
+    Serialize, Deserialize, JsonSchema, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord,
+)]
+pub enum RepoPermissionLevel {
+    #[serde(rename = "read")]
+    Read,
+    #[serde(rename = "triage")]
+    Triage,
+    #[serde(rename = "write")]
+    Write,
+    #[serde(rename = "maintain")]
+    Maintain,
+    #[serde(rename = "admin")]
+    Admin,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
 pub enum Rule {
     #[serde(rename = "is")]
     Is { user: String },
+    /// Active members of `org`. Invitees who have not accepted yet and outside
+    /// collaborators on the org's repositories only count when opted in. Checking outside
+    /// collaborators needs a token that can list them, i.e. an org owner's.
     #[serde(rename = "in_orgs")]
-    InOrgs { org: String },
+    InOrgs {
+        org: String,
+        #[serde(default)]
+        include_pending: bool,
+        #[serde(default)]
+        include_outside_collaborators: bool,
+    },
+    #[serde(rename = "in_team")]
+    InTeam { org: String, team: String },
+    /// `repo` is `owner/name`. Satisfied when the user has at least `level` on it.
+    #[serde(rename = "repo_permission")]
+    RepoPermission {
+        repo: String,
+        level: RepoPermissionLevel,
+    },
+    /// A service account, see [`super::service`]. GitHub knows nothing about these, so no
+    /// other rule but `anyone` matches them.
+    #[serde(rename = "service")]
+    Service { name: String },
+    #[serde(rename = "anyone")]
+    Anyone,
+    #[serde(rename = "any_of")]
+    AnyOf(Vec<Rule>),
+    #[serde(rename = "all_of")]
+    AllOf(Vec<Rule>),
+    // a newtype variant would need a nested YAML tag (`!not !is ...`) which serde_yaml rejects
+    #[serde(rename = "not")]
+    Not { rule: Box<Rule> },
+}
+
+/// How a rule and each sub-rule evaluated. Operands of `any_of` and `all_of` after the one
+/// that settled it are not evaluated and not listed.
+#[derive(Serialize, Clone, Debug)]
+pub struct RuleTrace {
+    /// The rule in short, like `in_team(acme/core)`.
+    pub rule: String,
+    pub holds: bool,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub children: Vec<RuleTrace>,
+}
+
+impl RuleTrace {
+    /// One line per rule, children indented under their parent.
+    pub fn lines(&self) -> Vec<String> {
+        let mut lines = vec![format!(
+            "{} {}",
+            if self.holds { "holds" } else { "fails" },
+            self.rule
+        )];
+        for child in &self.children {
+            lines.extend(child.lines().into_iter().map(|line| format!("  {line}")));
+        }
+        lines
+    }
+}
+
+/// Failure to get an answer out of GitHub. Denials are `Ok(false)`, not errors.
+#[derive(Debug, Clone, thiserror::Error)]
+pub enum GitHubError {
+    /// Network failure, 5xx or rate limiting. Worth retrying, and never a reason to deny.
+    #[error("github api is unavailable: {message}")]
+    Unavailable {
+        message: String,
+        retry_after: Option<u64>,
+    },
+    /// GitHub answered but refused the request, e.g. because the token is invalid.
+    #[error("github api rejected the request: {0}")]
+    Rejected(String),
+    /// GitHub answered with a body that does not parse. Not an outage, so the last known
+    /// decision is not served instead.
+    #[error("github api sent an unexpected response: {0}")]
+    Malformed(String),
+    /// A login has to be checked but there is no service token to do it with. Asking without
+    /// one would get anonymous rate limits and 401s, which look like an outage.
+    #[error("no github service token is configured to check logins with")]
+    NoServiceToken,
+}
+
+impl From<reqwest::Error> for GitHubError {
+    fn from(e: reqwest::Error) -> Self {
+        if e.is_decode() {
+            return Self::Malformed(e.to_string());
+        }
+        Self::Unavailable {
+            message: e.to_string(),
+            retry_after: None,
+        }
+    }
+}
+
+impl From<GitHubError> for HttpError {
+    fn from(e: GitHubError) -> Self {
+        match e {
+            GitHubError::Unavailable { retry_after, .. } => HttpError {
+                error_type: StatusCode::SERVICE_UNAVAILABLE,
+                message: "github is unavailable, retry later".to_string(),
+                verbose_message: e.to_string(),
+                contexts: Default::default(),
+                retry_after: Some(retry_after.unwrap_or(DEFAULT_RETRY_AFTER)),
+            },
+            GitHubError::Rejected(_) => HttpError {
+                error_type: StatusCode::FORBIDDEN,
+                message: "forbidden".to_string(),
+                verbose_message: e.to_string(),
+                contexts: Default::default(),
+                retry_after: None,
+            },
+            GitHubError::Malformed(_) => HttpError {
+                error_type: StatusCode::BAD_GATEWAY,
+                message: "github sent an unexpected response".to_string(),
+                verbose_message: e.to_string(),
+                contexts: Default::default(),
+                retry_after: None,
+            },
+            GitHubError::NoServiceToken => HttpError {
+                error_type: StatusCode::INTERNAL_SERVER_ERROR,
+                message: e.to_string(),
+                verbose_message: e.to_string(),
+                contexts: Default::default(),
+                retry_after: None,
+            },
+        }
+    }
+}
+
+const DEFAULT_RETRY_AFTER: u64 = 30;
+
+pub const DEFAULT_API_URL: &str = "https://api.github.com";
+pub const DEFAULT_USER_AGENT: &str = "prates-io";
+
+/// Where and how to reach the GitHub REST API. Point `base_url` at
+/// `https://<host>/api/v3` for GitHub Enterprise Server.
+///
+/// Clones share one connection pool.
+#[derive(Clone, Debug)]
+pub struct GitHubApi {
+    client: reqwest::Client,
+    base_url: String,
+    user_agent: String,
+}
+
+impl Default for GitHubApi {
+    fn default() -> Self {
+        Self::new(DEFAULT_API_URL)
+    }
+}
+
+impl GitHubApi {
+    pub fn new(base_url: impl Into<String>) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            base_url: base_url.into().trim_end_matches('/').to_string(),
+            user_agent: DEFAULT_USER_AGENT.to_string(),
+        }
+    }
+
+    pub fn with_user_agent(self, user_agent: impl Into<String>) -> Self {
+        Self {
+            user_agent: user_agent.into(),
+            ..self
+        }
+    }
+
+    /// Trust the certificates in `pem` in addition to the public roots, for GHES
+    /// instances behind an internal CA.
+    pub fn with_ca_bundle(self, pem: &[u8]) -> Result<Self, reqwest::Error> {
+        let client = reqwest::Certificate::from_pem_bundle(pem)?
+            .into_iter()
+            .fold(reqwest::Client::builder(), |builder, cert| {
+                builder.add_root_certificate(cert)
+            })
+            .build()?;
+        Ok(Self { client, ..self })
+    }
+
+    fn get(&self, token: &str, path: &str) -> reqwest::RequestBuilder {
+        self.client
+            .get(format!("{}{path}", self.base_url))
+            .bearer_auth(token)
+            .header("user-agent", &self.user_agent)
+            .header("x-github-api-version", "2022-11-28")
+            .header("accept", "application/vnd.github+json")
+    }
 }
 
 mod permission_test {
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    use reqwest::StatusCode;
     use serde::Deserialize;
 
-    use crate::ResponseValidatable;
+    use super::{GitHubApi, GitHubError, RepoPermissionLevel};
+
+    fn header_u64(response: &reqwest::Response, name: &str) -> Option<u64> {
+        response.headers().get(name)?.to_str().ok()?.parse().ok()
+    }
+
+    /// Like [`crate::ResponseValidatable::validate`], but tells outages and rate limits
+    /// apart from refusals.
+    async fn validate(response: reqwest::Response) -> Result<reqwest::Response, GitHubError> {
+        let status = response.status();
+        if status.is_success() {
+            return Ok(response);
+        }
+        let rate_limited = status == StatusCode::TOO_MANY_REQUESTS
+            || (status == StatusCode::FORBIDDEN
+                && header_u64(&response, "x-ratelimit-remaining") == Some(0));
+        let retry_after = header_u64(&response, "retry-after").or_else(|| {
+            let reset = header_u64(&response, "x-ratelimit-reset")?;
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).ok()?.as_secs();
+            Some(reset.saturating_sub(now))
+        });
+        let message = format!("{status}: {}", response.text().await.unwrap_or_default());
+        if rate_limited || status.is_server_error() {
+            Err(GitHubError::Unavailable {
+                message,
+                retry_after,
+            })
+        } else {
+            Err(GitHubError::Rejected(message))
+        }
+    }
 
     #[derive(Deserialize)]
     struct GhUserResponse {
         login: String,
     }
-    async fn get_user(token: &str) -> anyhow::Result<String> {
-        let user = reqwest::Client::new()
-            .get("https://api.github.com/user")
-            .bearer_auth(token)
-            .header("user-agent", "prates-io")
-            .header("x-github-api-version", "2022-11-28")
-            .header("accept", "application/vnd.github+json")
+    pub async fn get_user(api: &GitHubApi, token: &str) -> Result<String, GitHubError> {
+        let user = api.get(token, "/user").send().await?;
+        let user = validate(user).await?.json::<GhUserResponse>().await?;
+        Ok(user.login)
+    }
+
+    #[derive(Deserialize)]
+    struct GhMembershipResponse {
+        state: String,
+    }
+
+    /// `active` or `pending`, or `None` when `login` is not a member of `org`.
+    pub async fn org_membership(
+        api: &GitHubApi,
+        token: &str,
+        login: &str,
+        org: &str,
+    ) -> Result<Option<String>, GitHubError> {
+        let response = api
+            .get(token, &format!("/orgs/{org}/memberships/{login}"))
             .send()
+            .await?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        let membership = validate(response)
             .await?
-            .validate()
-            .await?
-            .json::<GhUserResponse>()
+            .json::<GhMembershipResponse>()
             .await?;
-        Ok(user.login)
+        Ok(Some(membership.state))
     }
 
-    pub async fn in_orgs(token: &str, org: &str) -> anyhow::Result<bool> {
-        let members = reqwest::Client::new()
-            .get(format!("https://api.github.com/orgs/{org}/members"))
-            .bearer_auth(token)
-            .header("user-agent", "prates-io")
-            .header("x-github-api-version", "2022-11-28")
-            .header("accept", "application/vnd.github+json")
+    pub async fn is_outside_collaborator(
+        api: &GitHubApi,
+        token: &str,
+        login: &str,
+        org: &str,
+    ) -> Result<bool, GitHubError> {
+        const PER_PAGE: usize = 100;
+        for page in 1.. {
+            let response = api
+                .get(
+                    token,
+                    &format!("/orgs/{org}/outside_collaborators?per_page={PER_PAGE}&page={page}"),
+                )
+                .send()
+                .await?;
+            let collaborators = validate(response)
+                .await?
+                .json::<Vec<GhUserResponse>>()
+                .await?;
+            if collaborators.iter().any(|user| user.login == login) {
+                return Ok(true);
+            }
+            if collaborators.len() < PER_PAGE {
+                break;
+            }
+        }
+        Ok(false)
+    }
+
+    pub async fn in_team(
+        api: &GitHubApi,
+        token: &str,
+        login: &str,
+        org: &str,
+        team: &str,
+    ) -> Result<bool, GitHubError> {
+        let response = api
+            .get(
+                token,
+                &format!("/orgs/{org}/teams/{team}/memberships/{login}"),
+            )
             .send()
+            .await?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(false);
+        }
+        let membership = validate(response)
             .await?
-            .validate()
-            .await?
-            .json::<Vec<GhUserResponse>>()
+            .json::<GhMembershipResponse>()
             .await?;
-        let me = get_user(token).await?;
-        Ok(members.iter().any(|member| member.login == me))
+        Ok(membership.state == "active")
     }
 
-    pub async fn is(token: &str, user: &str) -> anyhow::Result<bool> {
-        let me = get_user(token).await?;
-        Ok(me == user)
+    #[derive(Deserialize)]
+    struct GhCollaboratorPermissionResponse {
+        permission: String,
+        role_name: String,
+    }
+
+    fn parse_level(level: &str) -> Option<RepoPermissionLevel> {
+        match level {
+            "admin" => Some(RepoPermissionLevel::Admin),
+            "maintain" => Some(RepoPermissionLevel::Maintain),
+            "write" | "push" => Some(RepoPermissionLevel::Write),
+            "triage" => Some(RepoPermissionLevel::Triage),
+            "read" | "pull" => Some(RepoPermissionLevel::Read),
+            _ => None,
+        }
+    }
+
+    pub async fn repo_permission(
+        api: &GitHubApi,
+        token: &str,
+        login: &str,
+        repo: &str,
+        level: RepoPermissionLevel,
+    ) -> Result<bool, GitHubError> {
+        // GitHub answers 404 both for non-collaborators and for private repositories
+        // the token cannot see.
+        let response = api
+            .get(
+                token,
+                &format!("/repos/{repo}/collaborators/{login}/permission"),
+            )
+            .send()
+            .await?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(false);
+        }
+        let permission = validate(response)
+            .await?
+            .json::<GhCollaboratorPermissionResponse>()
+            .await?;
+        // custom repository roles show up in `role_name` only; `permission` holds the
+        // base role they inherit from.
+        let granted =
+            parse_level(&permission.role_name).or_else(|| parse_level(&permission.permission));
+        Ok(granted.is_some_and(|granted| granted >= level))
     }
 }
 
 impl Rule {
-    async fn test(&self, token: &str) -> anyhow::Result<bool> {
+    /// Collect problems serde cannot catch, such as combinators without operands.
+    pub(super) fn lint(&self, path: &str, errors: &mut Vec<String>) {
         match self {
-            Self::InOrgs { org } => permission_test::in_orgs(token, org).await,
-            Self::Is { user } => permission_test::is(token, user).await,
+            Self::Is { user } if user.is_empty() => {
+                errors.push(format!("{path}: `is` needs a non-empty user"))
+            }
+            Self::Service { name } if name.is_empty() => {
+                errors.push(format!("{path}: `service` needs a non-empty name"))
+            }
+            Self::InOrgs { org, .. } if org.is_empty() => {
+                errors.push(format!("{path}: `in_orgs` needs a non-empty org"))
+            }
+            Self::InTeam { org, team } if org.is_empty() || team.is_empty() => {
+                errors.push(format!("{path}: `in_team` needs a non-empty org and team"))
+            }
+            Self::RepoPermission { repo, .. }
+                if !repo
+                    .split_once('/')
+                    .is_some_and(|(owner, name)| !owner.is_empty() && !name.is_empty()) =>
+            {
+                errors.push(format!(
+                    "{path}: `repo_permission` repo must be `owner/name`, got `{repo}`"
+                ))
+            }
+            Self::AnyOf(rules) if rules.is_empty() => {
+                errors.push(format!("{path}: empty `any_of` never matches"))
+            }
+            Self::AllOf(rules) if rules.is_empty() => {
+                errors.push(format!("{path}: empty `all_of` always matches"))
+            }
+            Self::AnyOf(rules) | Self::AllOf(rules) => {
+                for (i, rule) in rules.iter().enumerate() {
+                    rule.lint(&format!("{path}[{i}]"), errors);
+                }
+            }
+            Self::Not { rule } => rule.lint(&format!("{path}.rule"), errors),
+            _ => (),
+        }
+    }
+
+    /// Whether the rule holds without knowing who is asking. `not` never does, so that an
+    /// anonymous caller cannot pass as "not one of the banned users".
+    pub fn allows_anonymous(&self) -> bool {
+        match self {
+            Self::Anyone => true,
+            Self::AnyOf(rules) => rules.iter().any(Rule::allows_anonymous),
+            Self::AllOf(rules) => rules.iter().all(Rule::allows_anonymous),
+            _ => false,
+        }
+    }
+
+    fn summary(&self) -> String {
+        match self {
+            Self::Is { user } => format!("is({user})"),
+            Self::Service { name } => format!("service({name})"),
+            Self::InOrgs { org, .. } => format!("in_orgs({org})"),
+            Self::InTeam { org, team } => format!("in_team({org}/{team})"),
+            Self::RepoPermission { repo, level } => {
+                format!(
+                    "repo_permission({repo}, {})",
+                    format!("{level:?}").to_lowercase()
+                )
+            }
+            Self::Anyone => "anyone".to_string(),
+            Self::AnyOf(_) => "any_of".to_string(),
+            Self::AllOf(_) => "all_of".to_string(),
+            Self::Not { .. } => "not".to_string(),
+        }
+    }
+
+    /// Like [`Rule::test_as`], keeping how each sub-rule evaluated.
+    pub async fn explain_as(
+        &self,
+        api: &GitHubApi,
+        token: &str,
+        login: &str,
+    ) -> Result<RuleTrace, GitHubError> {
+        let mut children = Vec::new();
+        let holds = match self {
+            Self::AnyOf(rules) | Self::AllOf(rules) => {
+                // any_of settles on the first operand that holds, all_of on the first that fails
+                let settles_on = matches!(self, Self::AnyOf(_));
+                let mut holds = !settles_on;
+                for rule in rules {
+                    let trace = Box::pin(rule.explain_as(api, token, login)).await?;
+                    let settled = trace.holds == settles_on;
+                    children.push(trace);
+                    if settled {
+                        holds = settles_on;
+                        break;
+                    }
+                }
+                holds
+            }
+            Self::Not { rule } => {
+                let trace = Box::pin(rule.explain_as(api, token, login)).await?;
+                let holds = !trace.holds;
+                children.push(trace);
+                holds
+            }
+            _ => self.test_as(api, token, login).await?,
+        };
+        Ok(RuleTrace {
+            rule: self.summary(),
+            holds,
+            children,
+        })
+    }
+
+    /// Like [`Rule::explain_as`] for the service account `account`, without asking GitHub.
+    pub fn explain_as_service(&self, account: &str) -> RuleTrace {
+        let mut children = Vec::new();
+        let holds = match self {
+            Self::Service { name } => name == account,
+            Self::Anyone => true,
+            Self::AnyOf(rules) | Self::AllOf(rules) => {
+                let settles_on = matches!(self, Self::AnyOf(_));
+                let mut holds = !settles_on;
+                for rule in rules {
+                    let trace = rule.explain_as_service(account);
+                    let settled = trace.holds == settles_on;
+                    children.push(trace);
+                    if settled {
+                        holds = settles_on;
+                        break;
+                    }
+                }
+                holds
+            }
+            Self::Not { rule } => {
+                let trace = rule.explain_as_service(account);
+                let holds = !trace.holds;
+                children.push(trace);
+                holds
+            }
+            Self::Is { .. }
+            | Self::InOrgs { .. }
+            | Self::InTeam { .. }
+            | Self::RepoPermission { .. } => false,
+        };
+        RuleTrace {
+            rule: self.summary(),
+            holds,
+            children,
+        }
+    }
+
+    /// Evaluate the rule for `login`. `token` is only used to query GitHub, so it does not
+    /// have to belong to `login`.
+    pub async fn test_as(
+        &self,
+        api: &GitHubApi,
+        token: &str,
+        login: &str,
+    ) -> Result<bool, GitHubError> {
+        match self {
+            Self::InOrgs {
+                org,
+                include_pending,
+                include_outside_collaborators,
+            } => {
+                let state = permission_test::org_membership(api, token, login, org).await?;
+                if state.as_deref() == Some("active")
+                    || (*include_pending && state.as_deref() == Some("pending"))
+                {
+                    return Ok(true);
+                }
+                if *include_outside_collaborators && state.is_none() {
+                    return permission_test::is_outside_collaborator(api, token, login, org).await;
+                }
+                Ok(false)
+            }
+            Self::Is { user } => Ok(login == user),
+            Self::Service { .. } => Ok(false),
+            Self::InTeam { org, team } => {
+                permission_test::in_team(api, token, login, org, team).await
+            }
+            Self::RepoPermission { repo, level } => {
+                permission_test::repo_permission(api, token, login, repo, *level).await
+            }
+            Self::Anyone => Ok(true),
+            Self::AnyOf(rules) => {
+                for rule in rules {
+                    if Box::pin(rule.test_as(api, token, login)).await? {
+                        return Ok(true);
+                    }
+                }
+                Ok(false)
+            }
+            Self::AllOf(rules) => {
+                for rule in rules {
+                    if !Box::pin(rule.test_as(api, token, login)).await? {
+                        return Ok(false);
+                    }
+                }
+                Ok(true)
+            }
+            Self::Not { rule } => Ok(!Box::pin(rule.test_as(api, token, login)).await?),
         }
     }
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
-pub struct CrateRule {
-    pub write: Rule,
-    pub read: Rule,
+/// Who is asking, as far as the rules are concerned.
+#[derive(Clone, Copy)]
+enum Caller<'a> {
+    /// A GitHub token presented by the caller.
+    Token(&'a str),
+    /// A login vouched for by some other means, checked with the service token.
+    Login(&'a str),
+    /// A service account, which only `service` rules name.
+    Service(&'a str),
 }
 
-pub type AuthRules = HashMap<String, CrateRule>;
+/// [`Caller`] as cached, without the token itself.
+#[derive(Hash, PartialEq, Eq, Clone)]
+enum Subject {
+    Token(TokenHash),
+    Login(String),
+    Service(String),
+}
+
+impl Caller<'_> {
+    fn key(self, name: &CrateName) -> CacheKey {
+        CacheKey {
+            crate_name: name.normalized.clone(),
+            subject: match self {
+                Self::Token(token) => Subject::Token(TokenHash::of(token)),
+                Self::Login(login) => Subject::Login(login.to_string()),
+                Self::Service(account) => Subject::Service(account.to_string()),
+            },
+        }
+    }
+}
 
 #[derive(Hash, PartialEq, Eq, Clone)]
 struct CacheKey {
     crate_name: String,
-    token: String,
+    subject: Subject,
+}
+
+#[derive(Serialize, Deserialize, Hash, PartialEq, Eq, Clone, Copy, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Access {
+    Read,
+    Download,
+    // `write` from before there were separate rules for each write
+    #[serde(alias = "write")]
+    Publish,
+    Yank,
+    Owners,
+}
+
+impl Access {
+    pub const ALL: [Access; 5] = [
+        Self::Read,
+        Self::Download,
+        Self::Publish,
+        Self::Yank,
+        Self::Owners,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Read => "read",
+            Self::Download => "download",
+            Self::Publish => "publish",
+            Self::Yank => "yank",
+            Self::Owners => "owners",
+        }
+    }
+}
+
+impl From<Scope> for Access {
+    fn from(scope: Scope) -> Self {
+        match scope {
+            Scope::PublishNew | Scope::PublishUpdate => Self::Publish,
+            Scope::Yank => Self::Yank,
+            Scope::ChangeOwners => Self::Owners,
+        }
+    }
+}
+
+/// Outcome of a rule evaluation. Only a rule that holds in [`Rule::explain_as`] becomes
+/// [`Decision::Allow`]; everything else that is not an outage is a denial.
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+enum Decision {
+    Allow,
+    Deny,
+}
+
+const LOGIN_TTL: u64 = 5 * 60;
+
+/// How long and how many rule decisions are reused before GitHub is asked again.
+#[derive(Deserialize, Clone, Copy, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthCacheConfig {
+    /// Seconds an allowing decision is reused.
+    pub positive_ttl: u64,
+    /// Seconds a denial is reused. Kept short so that newly granted access shows up soon.
+    pub negative_ttl: u64,
+    /// Decisions kept at most, across all users and crates. Also bounds the login cache.
+    pub capacity: u64,
+}
+
+impl Default for AuthCacheConfig {
+    fn default() -> Self {
+        Self {
+            positive_ttl: 60,
+            negative_ttl: 10,
+            capacity: 10_000,
+        }
+    }
+}
+
+struct DecisionExpiry {
+    positive: Duration,
+    negative: Duration,
+}
+
+impl moka::Expiry<(Access, CacheKey), Evaluation> for DecisionExpiry {
+    fn expire_after_create(
+        &self,
+        _key: &(Access, CacheKey),
+        evaluation: &Evaluation,
+        _created_at: Instant,
+    ) -> Option<Duration> {
+        Some(match evaluation.decision {
+            Decision::Allow => self.positive,
+            Decision::Deny => self.negative,
+        })
+    }
+}
+
+#[derive(Default)]
+struct Counters {
+    hits: AtomicU64,
+    misses: AtomicU64,
+    stale: AtomicU64,
+}
+
+/// Decision cache metrics since start, or since the caches were last rebuilt.
+#[derive(Serialize, Clone, Debug)]
+pub struct CacheStats {
+    /// Approximate, as moka counts lazily.
+    pub entries: u64,
+    pub capacity: u64,
+    pub hits: u64,
+    pub misses: u64,
+    /// Decisions served from the outage cache.
+    pub stale: u64,
+    pub logins: u64,
+}
+
+/// A decision along with how it was reached.
+#[derive(Clone, Debug)]
+struct Evaluation {
+    decision: Decision,
+    /// [`GitHubAuth::generation`] of the rules evaluated; other generations are discarded.
+    generation: u64,
+    /// Whom the rules were evaluated for, if known, so that their decisions can be flushed.
+    login: Option<String>,
+    /// Where the matching rule is in the rules file; `None` when no rule covers the crate.
+    matched: Option<String>,
+    /// `None` when no rule covers the crate.
+    trace: Option<Arc<RuleTrace>>,
+}
+
+/// Where a decision came from.
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Source {
+    /// Evaluated for this request.
+    Fresh,
+    /// Reused from an earlier request within the cache TTL.
+    Cached,
+    /// Last known decision, served because GitHub is unavailable.
+    Stale,
+}
+
+/// Why a login can or cannot read or write a crate, for admins and `check-config`.
+#[derive(Serialize, Clone, Debug)]
+pub struct Explanation {
+    pub allowed: bool,
+    pub matched: Option<String>,
+    pub source: Source,
+    pub trace: Option<RuleTrace>,
 }
 
 #[derive(Clone)]
 pub struct GitHubAuth {
-    auth_rules: AuthRules,
-    read_cache: Arc<moka::future::Cache<CacheKey, bool>>,
-    write_cache: Arc<moka::future::Cache<CacheKey, bool>>,
+    api: GitHubApi,
+    auth_rules: Arc<ArcSwap<AuthRules>>,
+    // bumped after each reload, so evaluations of older rules can be told apart
+    generation: Arc<AtomicU64>,
+    // used to query GitHub about logins that did not come with a GitHub token
+    service_token: Option<Zeroizing<String>>,
+    positive_ttl: Duration,
+    negative_ttl: Duration,
+    capacity: u64,
+    stale_if_error: Option<Duration>,
+    // token -> login, shared by every crate the token asks about
+    logins: Arc<moka::future::Cache<TokenHash, String>>,
+    decisions: Arc<moka::future::Cache<(Access, CacheKey), Evaluation>>,
+    // last decision actually obtained from GitHub, served while GitHub is down
+    last_known: Option<Arc<moka::future::Cache<(Access, CacheKey), Evaluation>>>,
+    stats: Arc<Counters>,
+    // every crate is readable by everyone, with or without a token
+    public_read: bool,
+    // say in denials why, without naming rules
+    debug_reasons: bool,
 }
 
 impl GitHubAuth {
     pub fn new_from_config(auth_rules: AuthRules) -> Self {
         Self {
-            auth_rules,
-            read_cache: Arc::new(moka::future::Cache::new(1024)),
-            write_cache: Arc::new(moka::future::Cache::new(1024)),
+            api: GitHubApi::default(),
+            auth_rules: Arc::new(ArcSwap::from_pointee(auth_rules)),
+            generation: Default::default(),
+            service_token: None,
+            positive_ttl: Duration::ZERO,
+            negative_ttl: Duration::ZERO,
+            capacity: 0,
+            stale_if_error: None,
+            // replaced by with_new_caches
+            logins: Arc::new(moka::future::Cache::new(0)),
+            decisions: Arc::new(moka::future::Cache::new(0)),
+            last_known: None,
+            stats: Default::default(),
+            public_read: false,
+            debug_reasons: false,
         }
+        .with_cache_config(AuthCacheConfig::default())
     }
 
-    async fn test_read(&self, key: &CacheKey) -> Result<bool, HttpError> {
-        let rule = self
-            .auth_rules
-            .get(&key.crate_name)
-            .http_error_with(StatusCode::FORBIDDEN, || "forbidden")?;
-        Ok(rule.read.test(&key.token).await.is_ok())
+    /// Caches as configured, empty. Clones made before keep the old ones.
+    fn with_new_caches(self) -> Self {
+        let capacity = self.capacity;
+        Self {
+            logins: Arc::new(
+                moka::future::Cache::builder()
+                    .max_capacity(capacity)
+                    .time_to_live(Duration::from_secs(LOGIN_TTL))
+                    .build(),
+            ),
+            decisions: Arc::new(
+                moka::future::Cache::builder()
+                    .max_capacity(capacity)
+                    .expire_after(DecisionExpiry {
+                        positive: self.positive_ttl,
+                        negative: self.negative_ttl,
+                    })
+                    .support_invalidation_closures()
+                    .build(),
+            ),
+            last_known: self.stale_if_error.map(|max_age| {
+                Arc::new(
+                    moka::future::Cache::builder()
+                        .max_capacity(capacity)
+                        .time_to_live(max_age)
+                        .support_invalidation_closures()
+                        .build(),
+                )
+            }),
+            stats: Default::default(),
+            ..self
+        }
     }
 
-    async fn test_write(&self, key: &CacheKey) -> Result<bool, HttpError> {
-        let rule = self
-            .auth_rules
-            .get(&key.crate_name)
-            .http_error_with(StatusCode::FORBIDDEN, || "forbidden")?;
-        Ok(rule.write.test(&key.token).await.is_ok())
+    pub fn with_api(self, api: GitHubApi) -> Self {
+        Self { api, ..self }
     }
-}
 
-#[derive(Deserialize)]
-struct GhUserResponse {
-    id: u32,
-    login: String,
-    name: String,
-}
+    /// Token used to evaluate rules for callers authenticated by something other than a
+    /// GitHub token, e.g. registry-issued tokens. It needs to see the org and team
+    /// memberships the rules refer to.
+    pub fn with_service_token(self, service_token: impl Into<String>) -> Self {
+        Self {
+            service_token: Some(Zeroizing::new(service_token.into())),
+            ..self
+        }
+    }
 
-impl super::Auth for GitHubAuth {
-    async fn readable(&self, token: &RawAuthorization, name: &CrateName) -> Result<(), HttpError> {
-        let key = CacheKey {
-            crate_name: name.normalized.clone(),
-            token: token.value().to_string(),
+    pub fn with_cache_config(self, cache_config: AuthCacheConfig) -> Self {
+        Self {
+            positive_ttl: Duration::from_secs(cache_config.positive_ttl),
+            negative_ttl: Duration::from_secs(cache_config.negative_ttl),
+            capacity: cache_config.capacity,
+            ..self
+        }
+        .with_new_caches()
+    }
+
+    /// How long any decision, allowing or denying, is reused before GitHub is asked again.
+    pub fn with_cache_ttl(self, cache_ttl: Duration) -> Self {
+        Self {
+            positive_ttl: cache_ttl,
+            negative_ttl: cache_ttl,
+            ..self
+        }
+        .with_new_caches()
+    }
+
+    /// Keep every decision for `max_age` and answer with it when GitHub is unavailable
+    /// instead of failing with 503.
+    pub fn with_stale_if_error(self, max_age: Duration) -> Self {
+        Self {
+            stale_if_error: Some(max_age),
+            ..self
+        }
+        .with_new_caches()
+    }
+
+    /// Let anyone read every crate, regardless of the read rules. Writes are unaffected.
+    pub fn with_public_read(self, public_read: bool) -> Self {
+        Self {
+            public_read,
+            ..self
+        }
+    }
+
+    /// Add a short reason to the contexts of denials, such as whether a rule covers the crate
+    /// at all and whether the decision was cached. The rules themselves are not revealed.
+    pub fn with_debug_reasons(self, debug_reasons: bool) -> Self {
+        Self {
+            debug_reasons,
+            ..self
+        }
+    }
+
+    /// Swap in a new rule set. Cached decisions were made against the old rules, so they are
+    /// dropped as well, along with evaluations of the old rules still in flight.
+    pub fn reload(&self, auth_rules: AuthRules) {
+        self.auth_rules.store(Arc::new(auth_rules));
+        // after the store: whoever sees the new generation also sees the new rules
+        self.generation.fetch_add(1, Ordering::SeqCst);
+        self.decisions.invalidate_all();
+        if let Some(last_known) = &self.last_known {
+            last_known.invalidate_all();
+        }
+    }
+
+    /// Drop cached decisions about `login`, about `crate_name`, about `login` and
+    /// `crate_name` together when both are given, or, with neither, all of them, including
+    /// the ones kept for outages.
+    pub fn flush(&self, login: Option<&str>, crate_name: Option<&CrateName>) {
+        if login.is_none() && crate_name.is_none() {
+            self.decisions.invalidate_all();
+            if let Some(last_known) = &self.last_known {
+                last_known.invalidate_all();
+            }
+            return;
+        }
+        let login = login.map(ToString::to_string);
+        let crate_name = crate_name.map(|name| name.normalized.clone());
+        let matches = move |(_, key): &(Access, CacheKey), evaluation: &Evaluation| {
+            (login.is_none() || evaluation.login == login)
+                && (crate_name.is_none() || crate_name.as_ref() == Some(&key.crate_name))
         };
-        let result = if let Some(result) = self.read_cache.get(&key).await {
-            result
-        } else {
-            let result = self.test_read(&key).await.unwrap_or(false);
-            self.read_cache.insert(key.clone(), result).await;
-            let read_cache = self.read_cache.clone();
-            tokio::spawn(async move {
-                tokio::time::sleep(std::time::Duration::from_secs(60)).await;
-                read_cache.invalidate(&key).await
+        for cache in std::iter::once(&self.decisions).chain(&self.last_known) {
+            cache
+                .invalidate_entries_if(matches.clone())
+                .expect("caches are built with invalidation closures");
+        }
+    }
+
+    pub fn cache_stats(&self) -> CacheStats {
+        CacheStats {
+            entries: self.decisions.entry_count(),
+            capacity: self.capacity,
+            hits: self.stats.hits.load(Ordering::Relaxed),
+            misses: self.stats.misses.load(Ordering::Relaxed),
+            stale: self.stats.stale.load(Ordering::Relaxed),
+            logins: self.logins.entry_count(),
+        }
+    }
+
+    /// Rules currently in effect.
+    pub fn rules(&self) -> Arc<AuthRules> {
+        self.auth_rules.load_full()
+    }
+
+    /// Whether cargo has to send a token to fetch the index and download crates, i.e.
+    /// `auth-required` in `config.json`. Cargo decides this for the whole registry, so a
+    /// single crate that anonymous callers cannot read keeps it on, public crates included.
+    pub fn reads_need_auth(&self) -> bool {
+        !self.public_read && !self.auth_rules.load().anyone_reads_everything()
+    }
+
+    /// GitHub login owning `token`. Looked up once per token and kept for five minutes;
+    /// failed lookups are not remembered.
+    pub async fn login(&self, token: &str) -> Result<String, GitHubError> {
+        self.logins
+            .try_get_with(
+                TokenHash::of(token),
+                permission_test::get_user(&self.api, token),
+            )
+            .await
+            .map_err(|e| (*e).clone())
+    }
+
+    async fn test(
+        &self,
+        access: Access,
+        name: &CrateName,
+        caller: Caller<'_>,
+    ) -> Result<Evaluation, GitHubError> {
+        let generation = self.generation.load(Ordering::SeqCst);
+        let auth_rules = self.auth_rules.load();
+        let found = auth_rules
+            .lookup(&name.normalized)
+            .and_then(|(matched, rule)| rule.get(access).map(|(_, rule)| (matched, rule)));
+        let Some((matched, rule)) = found else {
+            return Ok(Evaluation {
+                decision: Decision::Deny,
+                generation,
+                login: match caller {
+                    Caller::Token(token) => self.login(token).await.ok(),
+                    Caller::Login(login) => Some(login.to_string()),
+                    Caller::Service(_) => None,
+                },
+                matched: None,
+                trace: None,
             });
-            result
         };
-        if result {
-            Ok(())
-        } else {
-            Err(HttpError {
-                error_type: StatusCode::FORBIDDEN,
-                message: "forbidden".to_string(),
-                verbose_message: "forbidden".to_string(),
-                contexts: Default::default(),
-            })
-        }
+        let mut evaluated_for = None;
+        let result = match caller {
+            Caller::Token(token) => match self.login(token).await {
+                Ok(login) => {
+                    let result = rule.explain_as(&self.api, token, &login).await;
+                    evaluated_for = Some(login);
+                    result
+                }
+                Err(e) => Err(e),
+            },
+            Caller::Login(login) => match &self.service_token {
+                Some(token) => {
+                    evaluated_for = Some(login.to_string());
+                    rule.explain_as(&self.api, token, login).await
+                }
+                None => Err(GitHubError::NoServiceToken),
+            },
+            Caller::Service(account) => Ok(rule.explain_as_service(account)),
+        };
+        let trace = match result {
+            Ok(trace) => trace,
+            Err(GitHubError::Rejected(message)) => {
+                debug!(message, "github_rejected");
+                RuleTrace {
+                    rule: format!("rejected by github: {message}"),
+                    holds: false,
+                    children: Vec::new(),
+                }
+            }
+            Err(
+                e @ (GitHubError::Unavailable { .. }
+                | GitHubError::Malformed(_)
+                | GitHubError::NoServiceToken),
+            ) => {
+                return Err(e);
+            }
+        };
+        Ok(Evaluation {
+            decision: if trace.holds {
+                Decision::Allow
+            } else {
+                Decision::Deny
+            },
+            generation,
+            login: evaluated_for,
+            matched: Some(matched),
+            trace: Some(Arc::new(trace)),
+        })
     }
-    async fn writable(&self, token: &RawAuthorization, name: &CrateName) -> Result<(), HttpError> {
-        let key = CacheKey {
-            crate_name: name.normalized.clone(),
-            token: token.value().to_string(),
+
+    async fn evaluate(
+        &self,
+        access: Access,
+        name: &CrateName,
+        caller: Caller<'_>,
+    ) -> Result<(Evaluation, Source), HttpError> {
+        let key = (access, caller.key(name));
+        let current = |evaluation: &Evaluation| {
+            evaluation.generation == self.generation.load(Ordering::SeqCst)
         };
-        let result = if let Some(result) = self.write_cache.get(&key).await {
-            result
-        } else {
-            let result = self.test_write(&key).await.unwrap_or(false);
-            self.write_cache.insert(key.clone(), result).await;
-            let write_cache = self.write_cache.clone();
-            tokio::spawn(async move {
-                tokio::time::sleep(std::time::Duration::from_secs(60)).await;
-                write_cache.invalidate(&key).await
-            });
-            result
+        if let Some(evaluation) = self.decisions.get(&key).await
+            && current(&evaluation)
+        {
+            self.stats.hits.fetch_add(1, Ordering::Relaxed);
+            return Ok((evaluation, Source::Cached));
+        }
+        self.stats.misses.fetch_add(1, Ordering::Relaxed);
+        let evaluation = match self.test(access, name, caller).await {
+            Ok(evaluation) => evaluation,
+            // not cached, so the next request asks GitHub again
+            Err(e) => {
+                let stale = match (&self.last_known, &e) {
+                    (Some(last_known), GitHubError::Unavailable { .. }) => {
+                        last_known.get(&key).await.filter(current)
+                    }
+                    _ => None,
+                };
+                let Some(stale) = stale else {
+                    warn!(e = e.to_string(), "github_unavailable");
+                    return Err(e.into());
+                };
+                warn!(e = e.to_string(), "github_unavailable_serving_stale");
+                self.stats.stale.fetch_add(1, Ordering::Relaxed);
+                return Ok((stale, Source::Stale));
+            }
         };
-        if result {
-            Ok(())
-        } else {
-            Err(HttpError {
-                error_type: StatusCode::FORBIDDEN,
-                message: "forbidden".to_string(),
-                verbose_message: "forbidden".to_string(),
+        // rules reloaded meanwhile; answer this request but do not keep the decision
+        if !current(&evaluation) {
+            return Ok((evaluation, Source::Fresh));
+        }
+        if let Some(last_known) = &self.last_known {
+            last_known.insert(key.clone(), evaluation.clone()).await;
+        }
+        self.decisions.insert(key, evaluation.clone()).await;
+        Ok((evaluation, Source::Fresh))
+    }
+
+    async fn decide(
+        &self,
+        access: Access,
+        name: &CrateName,
+        caller: Caller<'_>,
+    ) -> Result<(), HttpError> {
+        let (evaluation, source) = self.evaluate(access, name, caller).await?;
+        if evaluation.decision == Decision::Allow {
+            return Ok(());
+        }
+        let mut e = forbidden();
+        if self.debug_reasons {
+            let access = access.as_str();
+            let reason = match evaluation.matched {
+                Some(_) => format!("the {access} rule for this crate does not allow you"),
+                None => "no rule covers this crate".to_string(),
+            };
+            e.contexts.push(match source {
+                Source::Fresh => reason,
+                Source::Cached => format!("{reason} (cached decision)"),
+                Source::Stale => format!("{reason} (last known decision, github is unavailable)"),
+            });
+        }
+        Err(e)
+    }
+
+    /// Why `login` can or cannot `access` `name`, evaluated like a request authenticated by
+    /// something other than a GitHub token.
+    pub async fn explain(
+        &self,
+        login: &str,
+        name: &CrateName,
+        access: Access,
+    ) -> Result<Explanation, HttpError> {
+        let (evaluation, source) = self.evaluate(access, name, Caller::Login(login)).await?;
+        Ok(Explanation {
+            allowed: evaluation.decision == Decision::Allow,
+            matched: evaluation.matched,
+            source,
+            trace: evaluation.trace.map(|trace| (*trace).clone()),
+        })
+    }
+
+    /// Anonymous reads and downloads are allowed where their rule holds for anyone at all.
+    fn anonymously(&self, access: Access, name: &CrateName) -> Result<(), HttpError> {
+        if self.public_read {
+            return Ok(());
+        }
+        let auth_rules = self.auth_rules.load();
+        let rule = auth_rules
+            .get(&name.normalized)
+            .and_then(|rule| rule.get(access));
+        match rule {
+            Some((_, rule)) if rule.allows_anonymous() => Ok(()),
+            _ => Err(HttpError {
+                error_type: StatusCode::UNAUTHORIZED,
+                message: "authentication required".to_string(),
+                verbose_message: format!("{} is not publicly readable", name.original),
                 contexts: Default::default(),
-            })
+                retry_after: None,
+            }),
         }
     }
-    async fn as_registry_user(
+
+    /// Like [`super::Auth::readable`] for a login instead of a token.
+    pub async fn readable_by(&self, login: &str, name: &CrateName) -> Result<(), HttpError> {
+        if self.public_read {
+            return Ok(());
+        }
+        self.decide(Access::Read, name, Caller::Login(login)).await
+    }
+
+    /// Like [`super::Auth::downloadable`] for a login instead of a token.
+    pub async fn downloadable_by(&self, login: &str, name: &CrateName) -> Result<(), HttpError> {
+        if self.public_read {
+            return Ok(());
+        }
+        self.decide(Access::Download, name, Caller::Login(login))
+            .await
+    }
+
+    /// Like [`super::Auth::permissions`] for a login instead of a token.
+    pub async fn permissions_of(
         &self,
-        token: &RawAuthorization,
+        login: &str,
+        name: &CrateName,
+    ) -> Result<Permissions, HttpError> {
+        self.permissions_for(name, Caller::Login(login)).await
+    }
+
+    async fn permissions_for(
+        &self,
+        name: &CrateName,
+        caller: Caller<'_>,
+    ) -> Result<Permissions, HttpError> {
+        let mut write = Vec::new();
+        for scope in Scope::ALL {
+            if granted(self.decide(scope.into(), name, caller).await)? {
+                write.push(scope);
+            }
+        }
+        Ok(Permissions {
+            read: self.public_read || granted(self.decide(Access::Read, name, caller).await)?,
+            download: self.public_read
+                || granted(self.decide(Access::Download, name, caller).await)?,
+            write,
+        })
+    }
+
+    /// Like [`super::Auth::writable`] for a login instead of a token.
+    pub async fn writable_by(
+        &self,
+        login: &str,
+        name: &CrateName,
+        scope: Scope,
+    ) -> Result<(), HttpError> {
+        self.decide(scope.into(), name, Caller::Login(login)).await
+    }
+
+    /// Like [`GitHubAuth::readable_by`] for a service account, which is no GitHub login.
+    pub async fn readable_by_service(
+        &self,
+        account: &str,
+        name: &CrateName,
+    ) -> Result<(), HttpError> {
+        if self.public_read {
+            return Ok(());
+        }
+        self.decide(Access::Read, name, Caller::Service(account))
+            .await
+    }
+
+    /// Like [`GitHubAuth::downloadable_by`] for a service account.
+    pub async fn downloadable_by_service(
+        &self,
+        account: &str,
+        name: &CrateName,
+    ) -> Result<(), HttpError> {
+        if self.public_read {
+            return Ok(());
+        }
+        self.decide(Access::Download, name, Caller::Service(account))
+            .await
+    }
+
+    /// Like [`GitHubAuth::writable_by`] for a service account.
+    pub async fn writable_by_service(
+        &self,
+        account: &str,
+        name: &CrateName,
+        scope: Scope,
+    ) -> Result<(), HttpError> {
+        self.decide(scope.into(), name, Caller::Service(account))
+            .await
+    }
+
+    /// Like [`GitHubAuth::permissions_of`] for a service account.
+    pub async fn permissions_of_service(
+        &self,
+        account: &str,
+        name: &CrateName,
+    ) -> Result<Permissions, HttpError> {
+        self.permissions_for(name, Caller::Service(account)).await
+    }
+
+    /// Look up `user` with the caller's token, or the service token when `token` is `None`.
+    pub async fn registry_user(
+        &self,
+        token: Option<&str>,
         user: &str,
     ) -> Result<RegistryUser, HttpError> {
-        let user = reqwest::Client::new()
-            .get(format!("https://api.github.com/users/{user}"))
-            .bearer_auth(token.value())
-            .header("user-agent", "prates-io")
-            .header("x-github-api-version", "2022-11-28")
-            .header("accept", "application/vnd.github+json")
+        let token = token
+            .or(self.service_token.as_deref().map(String::as_str))
+            .ok_or(GitHubError::NoServiceToken)?;
+        let user = self
+            .api
+            .get(token, &format!("/users/{user}"))
             .send()
             .await
             .http_error(StatusCode::FORBIDDEN)?
@@ -211,3 +1320,110 @@ impl super::Auth for GitHubAuth {
         })
     }
 }
+
+fn forbidden() -> HttpError {
+    HttpError {
+        error_type: StatusCode::FORBIDDEN,
+        message: "forbidden".to_string(),
+        verbose_message: "forbidden".to_string(),
+        contexts: Default::default(),
+        retry_after: None,
+    }
+}
+
+#[derive(Deserialize)]
+struct GhUserResponse {
+    id: u32,
+    login: String,
+    name: String,
+}
+
+impl GitHubAuth {
+    async fn principal(&self, token: &RawAuthorization) -> Result<Principal, HttpError> {
+        let login = self.login(token.value()).await?;
+        Ok(Principal::new(Provider::GitHub, login))
+    }
+}
+
+impl super::Auth for GitHubAuth {
+    async fn authenticate(&self, token: Option<&RawAuthorization>) -> Result<Principal, HttpError> {
+        match token {
+            Some(token) => self.principal(token).await,
+            None => Ok(Principal::anonymous()),
+        }
+    }
+
+    async fn readable(
+        &self,
+        token: Option<&RawAuthorization>,
+        name: &CrateName,
+    ) -> Result<Principal, HttpError> {
+        if self.public_read {
+            return Ok(Principal::anonymous());
+        }
+        let Some(token) = token else {
+            self.anonymously(Access::Read, name)?;
+            return Ok(Principal::anonymous());
+        };
+        self.decide(Access::Read, name, Caller::Token(token.value()))
+            .await?;
+        self.principal(token).await
+    }
+    async fn downloadable(
+        &self,
+        token: Option<&RawAuthorization>,
+        name: &CrateName,
+    ) -> Result<Principal, HttpError> {
+        if self.public_read {
+            return Ok(Principal::anonymous());
+        }
+        let Some(token) = token else {
+            self.anonymously(Access::Download, name)?;
+            return Ok(Principal::anonymous());
+        };
+        self.decide(Access::Download, name, Caller::Token(token.value()))
+            .await?;
+        self.principal(token).await
+    }
+    async fn writable(
+        &self,
+        token: &RawAuthorization,
+        name: &CrateName,
+        mutation: &Mutation<'_>,
+    ) -> Result<Principal, HttpError> {
+        self.decide(mutation.scope().into(), name, Caller::Token(token.value()))
+            .await?;
+        self.principal(token).await
+    }
+    async fn permissions(
+        &self,
+        token: Option<&RawAuthorization>,
+        name: &CrateName,
+    ) -> Result<Permissions, HttpError> {
+        let Some(token) = token else {
+            return Ok(Permissions {
+                read: self.anonymously(Access::Read, name).is_ok(),
+                download: self.anonymously(Access::Download, name).is_ok(),
+                write: Vec::new(),
+            });
+        };
+        self.permissions_for(name, Caller::Token(token.value()))
+            .await
+    }
+    async fn as_registry_user(
+        &self,
+        token: Option<&RawAuthorization>,
+        user: &str,
+    ) -> Result<RegistryUser, HttpError> {
+        self.registry_user(token.map(RawAuthorization::value), user)
+            .await
+    }
+}
+
+/// GitHub has issued tokens in several formats over the years, so every token no other
+/// provider claims is taken to be one. Put it last in a chain.
+impl super::Authenticator for GitHubAuth {
+    fn accepts(&self, _token: &str) -> bool {
+        true
+    }
+}
@@ -1,4 +1,15 @@
+use std::{
+    sync::LazyLock,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use axum::http::StatusCode;
+use digest::{Digest, Mac};
 use futures_util::Future;
+use hmac::Hmac;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use subtle::ConstantTimeEq;
 
 use crate::{
     HttpError,
@@ -7,21 +18,402 @@ use crate::{
 };
 
 pub mod github;
+pub mod oidc;
+pub mod paseto;
+pub mod rules;
+pub mod service;
+pub mod token;
+
+/// What a write is about to do, named after the crates.io token scopes.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Scope {
+    #[serde(rename = "publish-new")]
+    PublishNew,
+    #[serde(rename = "publish-update")]
+    PublishUpdate,
+    #[serde(rename = "yank")]
+    Yank,
+    #[serde(rename = "change-owners")]
+    ChangeOwners,
+}
+
+impl Scope {
+    pub const ALL: [Scope; 4] = [
+        Self::PublishNew,
+        Self::PublishUpdate,
+        Self::Yank,
+        Self::ChangeOwners,
+    ];
+}
+
+/// A write as cargo describes it, down to what is being published.
+#[derive(Clone, Copy, Debug)]
+pub enum Mutation<'a> {
+    Publish {
+        vers: &'a semver::Version,
+        cksum: &'a str,
+        /// The crate has no versions yet.
+        new: bool,
+    },
+    Yank {
+        vers: &'a semver::Version,
+    },
+    Unyank {
+        vers: &'a semver::Version,
+    },
+    Owners,
+}
+
+impl Mutation<'_> {
+    pub fn scope(&self) -> Scope {
+        match self {
+            Self::Publish { new: true, .. } => Scope::PublishNew,
+            Self::Publish { new: false, .. } => Scope::PublishUpdate,
+            Self::Yank { .. } | Self::Unyank { .. } => Scope::Yank,
+            Self::Owners => Scope::ChangeOwners,
+        }
+    }
+}
+
+/// Which provider vouched for a request.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Provider {
+    Anonymous,
+    #[serde(rename = "github")]
+    GitHub,
+    RegistryToken,
+    TrustedPublishing,
+    Paseto,
+    ServiceAccount,
+}
+
+impl Provider {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Anonymous => "anonymous",
+            Self::GitHub => "github",
+            Self::RegistryToken => "registry_token",
+            Self::TrustedPublishing => "trusted_publishing",
+            Self::Paseto => "paseto",
+            Self::ServiceAccount => "service_account",
+        }
+    }
+}
+
+/// Who a request acts for, as resolved by the provider that accepted its token.
+#[derive(Serialize, Clone, Debug, PartialEq, Eq)]
+pub struct Principal {
+    /// GitHub login or service account name the rules were evaluated for; the repository
+    /// for trusted publishing; `None` when anonymous.
+    pub login: Option<String>,
+    pub provider: Provider,
+    /// Registry token ID or PASETO key ID.
+    pub token_id: Option<String>,
+    /// Writes the credential is limited to on top of the rules; `None` when only the rules
+    /// apply.
+    pub scopes: Option<Vec<Scope>>,
+    /// Crate name globs the credential is limited to. Empty means any crate.
+    pub crate_scopes: Vec<String>,
+}
+
+impl Principal {
+    pub fn new(provider: Provider, login: impl Into<String>) -> Self {
+        Self {
+            login: Some(login.into()),
+            provider,
+            token_id: None,
+            scopes: None,
+            crate_scopes: Vec::new(),
+        }
+    }
+
+    pub fn anonymous() -> Self {
+        Self {
+            login: None,
+            provider: Provider::Anonymous,
+            token_id: None,
+            scopes: None,
+            crate_scopes: Vec::new(),
+        }
+    }
+
+    pub fn with_token_id(self, token_id: impl Into<String>) -> Self {
+        Self {
+            token_id: Some(token_id.into()),
+            ..self
+        }
+    }
+
+    pub fn with_scopes(self, scopes: Vec<Scope>, crate_scopes: Vec<String>) -> Self {
+        Self {
+            scopes: Some(scopes),
+            crate_scopes,
+            ..self
+        }
+    }
+
+    /// Whether this is one of `admins`. Scoped credentials, like registry tokens, never act
+    /// as admins even when their owner is one, and neither do service accounts, whose names
+    /// are not GitHub logins.
+    pub fn is_admin(&self, admins: &[String]) -> bool {
+        matches!(self.provider, Provider::GitHub | Provider::Paseto)
+            && self
+                .login
+                .as_ref()
+                .is_some_and(|login| admins.contains(login))
+    }
+}
+
+/// What a principal may do with one crate, as reported by `/api/v1/me`.
+#[derive(Serialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Permissions {
+    pub read: bool,
+    pub download: bool,
+    /// Writes allowed, by scope.
+    pub write: Vec<Scope>,
+}
 
-pub trait Auth {
+impl Permissions {
+    pub fn any(&self) -> bool {
+        self.read || self.download || !self.write.is_empty()
+    }
+}
+
+pub trait Auth: Sync {
+    /// Who `token` belongs to, without looking at any crate. `token` is `None` for anonymous
+    /// requests here and below.
+    fn authenticate(
+        &self,
+        token: Option<&RawAuthorization>,
+    ) -> impl Future<Output = Result<Principal, HttpError>> + Send;
     fn readable(
         &self,
-        token: &RawAuthorization,
+        token: Option<&RawAuthorization>,
+        name: &CrateName,
+    ) -> impl Future<Output = Result<Principal, HttpError>> + Send;
+    /// Fetching `.crate` files, which may be more restricted than reading the index.
+    fn downloadable(
+        &self,
+        token: Option<&RawAuthorization>,
         name: &CrateName,
-    ) -> impl Future<Output = Result<(), HttpError>>;
+    ) -> impl Future<Output = Result<Principal, HttpError>> + Send;
     fn writable(
         &self,
         token: &RawAuthorization,
         name: &CrateName,
-    ) -> impl Future<Output = Result<(), HttpError>>;
+        mutation: &Mutation<'_>,
+    ) -> impl Future<Output = Result<Principal, HttpError>> + Send;
+    /// Who `token` belongs to when presented for `mutation`, to record writes that failed.
+    /// Neither the rules nor whether a single-use token was used up are checked.
+    fn identify(
+        &self,
+        token: &RawAuthorization,
+        _name: &CrateName,
+        _mutation: &Mutation<'_>,
+    ) -> impl Future<Output = Result<Principal, HttpError>> + Send {
+        self.authenticate(Some(token))
+    }
+    /// What `token` may do with `name`. Unlike [`Auth::writable`] this never uses up a
+    /// single-use token.
+    fn permissions(
+        &self,
+        token: Option<&RawAuthorization>,
+        name: &CrateName,
+    ) -> impl Future<Output = Result<Permissions, HttpError>> + Send;
     fn as_registry_user(
+        &self,
+        token: Option<&RawAuthorization>,
+        user: &str,
+    ) -> impl Future<Output = Result<RegistryUser, HttpError>> + Send;
+}
+
+/// An [`Auth`] that only understands tokens of a certain format, so that several of them can
+/// be chained.
+pub trait Authenticator: Auth {
+    fn accepts(&self, token: &str) -> bool;
+
+    /// Send the tokens this does not accept, and anonymous requests, to `next`.
+    fn or<B: Auth>(self, next: B) -> AuthChain<Self, B>
+    where
+        Self: Sized,
+    {
+        AuthChain { first: self, next }
+    }
+}
+
+/// Picks `first` for the tokens it accepts and `next` for everything else, built with
+/// [`Authenticator::or`].
+#[derive(Clone)]
+pub struct AuthChain<A, B> {
+    first: A,
+    next: B,
+}
+
+impl<A: Authenticator, B> AuthChain<A, B> {
+    fn first_accepts(&self, token: Option<&RawAuthorization>) -> bool {
+        token.is_some_and(|token| self.first.accepts(token.value()))
+    }
+}
+
+impl<A: Authenticator, B: Auth> Auth for AuthChain<A, B> {
+    async fn authenticate(&self, token: Option<&RawAuthorization>) -> Result<Principal, HttpError> {
+        if self.first_accepts(token) {
+            self.first.authenticate(token).await
+        } else {
+            self.next.authenticate(token).await
+        }
+    }
+
+    async fn readable(
+        &self,
+        token: Option<&RawAuthorization>,
+        name: &CrateName,
+    ) -> Result<Principal, HttpError> {
+        if self.first_accepts(token) {
+            self.first.readable(token, name).await
+        } else {
+            self.next.readable(token, name).await
+        }
+    }
+
+    async fn downloadable(
+        &self,
+        token: Option<&RawAuthorization>,
+        name: &CrateName,
+    ) -> Result<Principal, HttpError> {
+        if self.first_accepts(token) {
+            self.first.downloadable(token, name).await
+        } else {
+            self.next.downloadable(token, name).await
+        }
+    }
+
+    async fn writable(
         &self,
         token: &RawAuthorization,
+        name: &CrateName,
+        mutation: &Mutation<'_>,
+    ) -> Result<Principal, HttpError> {
+        if self.first_accepts(Some(token)) {
+            self.first.writable(token, name, mutation).await
+        } else {
+            self.next.writable(token, name, mutation).await
+        }
+    }
+
+    async fn identify(
+        &self,
+        token: &RawAuthorization,
+        name: &CrateName,
+        mutation: &Mutation<'_>,
+    ) -> Result<Principal, HttpError> {
+        if self.first_accepts(Some(token)) {
+            self.first.identify(token, name, mutation).await
+        } else {
+            self.next.identify(token, name, mutation).await
+        }
+    }
+
+    async fn permissions(
+        &self,
+        token: Option<&RawAuthorization>,
+        name: &CrateName,
+    ) -> Result<Permissions, HttpError> {
+        if self.first_accepts(token) {
+            self.first.permissions(token, name).await
+        } else {
+            self.next.permissions(token, name).await
+        }
+    }
+
+    async fn as_registry_user(
+        &self,
+        token: Option<&RawAuthorization>,
         user: &str,
-    ) -> impl Future<Output = Result<RegistryUser, HttpError>>;
+    ) -> Result<RegistryUser, HttpError> {
+        if self.first_accepts(token) {
+            self.first.as_registry_user(token, user).await
+        } else {
+            self.next.as_registry_user(token, user).await
+        }
+    }
+}
+
+impl<A: Authenticator, B: Authenticator> Authenticator for AuthChain<A, B> {
+    fn accepts(&self, token: &str) -> bool {
+        self.first.accepts(token) || self.next.accepts(token)
+    }
+}
+
+pub(crate) fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|now| now.as_secs())
+        .unwrap_or_default()
+}
+
+/// Issued tokens are only ever stored under this hash.
+fn hash(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+/// Whether `token` is the one stored as `stored`, without leaking how much of it matched.
+fn hash_matches(stored: &str, token: &str) -> bool {
+    hash(token).as_bytes().ct_eq(stored.as_bytes()).into()
+}
+
+// drawn per process, so cache keys are useless outside of it
+static CACHE_KEY: LazyLock<[u8; 32]> = LazyLock::new(rand::random);
+
+/// What in-memory caches keep in place of a token: an HMAC under a per-process key.
+#[derive(Clone, PartialEq, Eq, Hash)]
+struct TokenHash([u8; 32]);
+
+impl TokenHash {
+    fn of(token: &str) -> Self {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&*CACHE_KEY).expect("any key length");
+        mac.update(token.as_bytes());
+        Self(mac.finalize().into_bytes().into())
+    }
+}
+
+impl std::fmt::Debug for TokenHash {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("TokenHash(..)")
+    }
+}
+
+fn error(error_type: StatusCode, message: &str) -> HttpError {
+    HttpError {
+        error_type,
+        message: message.to_string(),
+        verbose_message: message.to_string(),
+        contexts: Default::default(),
+        retry_after: None,
+    }
+}
+
+/// Turn a denial into `false`, keeping outages and malformed tokens as errors.
+fn granted<T>(result: Result<T, HttpError>) -> Result<bool, HttpError> {
+    match result {
+        Ok(_) => Ok(true),
+        Err(e) if e.error_type == StatusCode::FORBIDDEN => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// For providers that never see anonymous requests in a chain.
+fn authentication_required() -> HttpError {
+    error(StatusCode::UNAUTHORIZED, "authentication required")
+}
+
+/// Random token secret, 32 alphanumeric characters.
+fn secret() -> String {
+    use rand::{Rng, distr::Alphanumeric};
+    rand::rng()
+        .sample_iter(Alphanumeric)
+        .take(32)
+        .map(char::from)
+        .collect()
 }
@@ -0,0 +1,577 @@
+use std::{
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use axum::http::StatusCode;
+use base64::{Engine, prelude::BASE64_URL_SAFE_NO_PAD};
+use jsonwebtoken::{
+    Algorithm, DecodingKey, Validation,
+    jwk::{AlgorithmParameters, EllipticCurve, Jwk, JwkSet},
+};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use super::{
+    Auth, Authenticator, Mutation, Permissions, Principal, Provider, Scope, TokenHash,
+    authentication_required, error, github::GitHubAuth, now, rules::glob_match, secret,
+};
+use crate::{
+    HttpError,
+    api_schema::{CrateName, RegistryUser},
+    axum_aux::RawAuthorization,
+};
+
+/// Publish tokens issued for OIDC tokens look like `gdo_<secret>`.
+pub const TOKEN_PREFIX: &str = "gdo_";
+
+/// Publish tokens alive at once; past this the oldest ones are dropped before they expire.
+const MAX_PUBLISH_TOKENS: u64 = 10_000;
+
+/// A fetched key set is kept at least this long, however many unknown keys tokens name.
+const MIN_JWKS_REFETCH: Duration = Duration::from_secs(60);
+
+/// An OIDC issuer whose tokens may be exchanged for publish tokens.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct OidcIssuer {
+    /// `iss` claim, e.g. `https://token.actions.githubusercontent.com`
+    pub issuer: String,
+    /// `aud` claim the CI job has to request.
+    pub audience: String,
+    /// Where to fetch the JWKS from. Defaults to the `jwks_uri` of the issuer's
+    /// `/.well-known/openid-configuration`.
+    pub jwks_url: Option<String>,
+    /// Read the JWKS from this file once at startup instead of fetching it.
+    pub jwks_file: Option<PathBuf>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct TrustedPublishingConfig {
+    #[serde(default)]
+    pub issuers: Vec<OidcIssuer>,
+    /// Lifetime of issued publish tokens in seconds.
+    #[serde(default = "default_token_ttl")]
+    pub token_ttl: u64,
+}
+
+fn default_token_ttl() -> u64 {
+    30 * 60
+}
+
+impl Default for TrustedPublishingConfig {
+    fn default() -> Self {
+        Self {
+            issuers: Vec::new(),
+            token_ttl: default_token_ttl(),
+        }
+    }
+}
+
+/// A CI workflow allowed to publish a crate, listed under the crate's `trusted_publishers`.
+/// Every field that is set has to match the OIDC token.
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct TrustedPublisher {
+    /// One of the configured issuers.
+    pub issuer: String,
+    /// `owner/repo`, compared with the `repository` claim.
+    pub repository: String,
+    /// Workflow file name like `release.yml`, compared with the file in `workflow_ref`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub workflow: Option<String>,
+    /// Glob like `refs/tags/v*`, matched against the `ref` claim.
+    #[serde(default, rename = "ref", skip_serializing_if = "Option::is_none")]
+    pub git_ref: Option<String>,
+    /// Deployment environment, compared with the `environment` claim.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub environment: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Claims {
+    iss: String,
+    repository: Option<String>,
+    workflow_ref: Option<String>,
+    #[serde(rename = "ref")]
+    git_ref: Option<String>,
+    environment: Option<String>,
+}
+
+impl Claims {
+    /// `release.yml` out of `owner/repo/.github/workflows/release.yml@refs/tags/v1`.
+    fn workflow_file(&self) -> Option<&str> {
+        let path = self.workflow_ref.as_deref()?.split('@').next()?;
+        path.rsplit('/').next()
+    }
+}
+
+impl TrustedPublisher {
+    fn matches(&self, claims: &Claims) -> bool {
+        self.issuer == claims.iss
+            && claims
+                .repository
+                .as_deref()
+                .is_some_and(|repository| repository.eq_ignore_ascii_case(&self.repository))
+            && self
+                .workflow
+                .as_deref()
+                .is_none_or(|workflow| claims.workflow_file() == Some(workflow))
+            && self.git_ref.as_deref().is_none_or(|pattern| {
+                claims
+                    .git_ref
+                    .as_deref()
+                    .is_some_and(|git_ref| glob_match(pattern, git_ref))
+            })
+            && self
+                .environment
+                .as_deref()
+                .is_none_or(|environment| claims.environment.as_deref() == Some(environment))
+    }
+
+    pub(super) fn lint(&self, path: &str, errors: &mut Vec<String>) {
+        if self.issuer.is_empty() {
+            errors.push(format!("{path}: issuer must not be empty"));
+        }
+        if self
+            .repository
+            .split('/')
+            .filter(|part| !part.is_empty())
+            .count()
+            != 2
+        {
+            errors.push(format!(
+                "{path}: repository `{}` is not of the form owner/repo",
+                self.repository
+            ));
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum JwksError {
+    #[error("cannot read {path}: {source}")]
+    Read {
+        path: String,
+        source: std::io::Error,
+    },
+    #[error("invalid JWKS in {path}: {source}")]
+    Parse {
+        path: String,
+        source: serde_json::Error,
+    },
+}
+
+enum Jwks {
+    File(Arc<JwkSet>),
+    // with when it was fetched
+    Remote(moka::future::Cache<(), (Arc<JwkSet>, Instant)>),
+}
+
+struct Issuer {
+    config: OidcIssuer,
+    jwks: Jwks,
+}
+
+#[derive(Deserialize)]
+struct Discovery {
+    jwks_uri: String,
+}
+
+impl Issuer {
+    async fn fetch(&self, client: &reqwest::Client) -> Result<JwkSet, reqwest::Error> {
+        let url = match &self.config.jwks_url {
+            Some(url) => url.clone(),
+            None => {
+                client
+                    .get(format!(
+                        "{}/.well-known/openid-configuration",
+                        self.config.issuer.trim_end_matches('/')
+                    ))
+                    .send()
+                    .await?
+                    .error_for_status()?
+                    .json::<Discovery>()
+                    .await?
+                    .jwks_uri
+            }
+        };
+        client
+            .get(url)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await
+    }
+
+    /// Key set of the issuer. A fetched set is refreshed when `stale` is set, which is done
+    /// once when a token names a key the cached set does not have, unless the set is younger
+    /// than [`MIN_JWKS_REFETCH`].
+    async fn jwks(&self, client: &reqwest::Client, stale: bool) -> Result<Arc<JwkSet>, HttpError> {
+        let cache = match &self.jwks {
+            Jwks::File(jwks) => return Ok(jwks.clone()),
+            Jwks::Remote(cache) => cache,
+        };
+        if stale
+            && let Some((_, fetched_at)) = cache.get(&()).await
+            && fetched_at.elapsed() >= MIN_JWKS_REFETCH
+        {
+            cache.invalidate(&()).await;
+        }
+        cache
+            .try_get_with((), async {
+                let jwks = self.fetch(client).await?;
+                Ok::<_, reqwest::Error>((Arc::new(jwks), Instant::now()))
+            })
+            .await
+            .map(|(jwks, _)| jwks)
+            .map_err(|e| {
+                warn!(
+                    issuer = self.config.issuer,
+                    e = e.to_string(),
+                    "jwks_fetch_failed"
+                );
+                HttpError {
+                    retry_after: Some(30),
+                    ..error(
+                        StatusCode::SERVICE_UNAVAILABLE,
+                        "cannot fetch the issuer's keys, retry later",
+                    )
+                }
+            })
+    }
+}
+
+#[derive(Clone)]
+struct PublishToken {
+    crate_name: String,
+    repository: String,
+}
+
+#[derive(Serialize)]
+pub struct IssuedToken {
+    pub token: String,
+    pub expires_at: u64,
+    /// Who the token publishes as, for the audit log.
+    #[serde(skip)]
+    pub principal: Principal,
+}
+
+/// Exchanges OIDC tokens from CI for short-lived tokens that can publish one crate, and
+/// accepts those tokens.
+///
+/// A publish token can read and publish its own crate and nothing else, so CI publishing a
+/// crate with private dependencies has to publish with `--no-verify`. Issued tokens live in
+/// memory only, so they do not survive a restart; CI asks for a new one on every run anyway.
+/// For the same reason only the instance that issued a token accepts it, so writes have to
+/// reach a single instance when several run behind a load balancer.
+#[derive(Clone)]
+pub struct TrustedPublishing {
+    issuers: Arc<Vec<Issuer>>,
+    client: reqwest::Client,
+    // rules carry the trusted publishers, and the service token looks up owners
+    github: GitHubAuth,
+    token_ttl: u64,
+    // hash of the token -> what it may publish
+    tokens: Arc<moka::future::Cache<TokenHash, PublishToken>>,
+}
+
+fn unauthorized(message: &str) -> HttpError {
+    error(StatusCode::UNAUTHORIZED, message)
+}
+
+/// `iss` of a JWT without checking anything, to pick the keys to check it with.
+fn unverified_issuer(jwt: &str) -> Option<String> {
+    #[derive(Deserialize)]
+    struct Iss {
+        iss: String,
+    }
+    let payload = jwt.split('.').nth(1)?;
+    let payload = BASE64_URL_SAFE_NO_PAD.decode(payload).ok()?;
+    serde_json::from_slice::<Iss>(&payload)
+        .ok()
+        .map(|claims| claims.iss)
+}
+
+/// Algorithms a token signed with `jwk` may use: the key's own `alg`, or else every
+/// public-key algorithm for its key type and curve.
+fn key_algorithms(jwk: &Jwk) -> Vec<Algorithm> {
+    if let Some(alg) = &jwk.common.key_algorithm {
+        return alg.to_string().parse().into_iter().collect();
+    }
+    match &jwk.algorithm {
+        AlgorithmParameters::RSA(_) => vec![
+            Algorithm::RS256,
+            Algorithm::RS384,
+            Algorithm::RS512,
+            Algorithm::PS256,
+            Algorithm::PS384,
+            Algorithm::PS512,
+        ],
+        AlgorithmParameters::EllipticCurve(params) => match params.curve {
+            EllipticCurve::P256 => vec![Algorithm::ES256],
+            EllipticCurve::P384 => vec![Algorithm::ES384],
+            _ => Vec::new(),
+        },
+        AlgorithmParameters::OctetKeyPair(params) if params.curve == EllipticCurve::Ed25519 => {
+            vec![Algorithm::EdDSA]
+        }
+        _ => Vec::new(),
+    }
+}
+
+impl TrustedPublishing {
+    pub async fn new(
+        config: &TrustedPublishingConfig,
+        github: GitHubAuth,
+    ) -> Result<Self, JwksError> {
+        let mut issuers = Vec::new();
+        for issuer in &config.issuers {
+            let jwks = match &issuer.jwks_file {
+                Some(path) => {
+                    let display = path.display().to_string();
+                    let src = tokio::fs::read(path)
+                        .await
+                        .map_err(|source| JwksError::Read {
+                            path: display.clone(),
+                            source,
+                        })?;
+                    let jwks = serde_json::from_slice(&src).map_err(|source| JwksError::Parse {
+                        path: display,
+                        source,
+                    })?;
+                    Jwks::File(Arc::new(jwks))
+                }
+                None => Jwks::Remote(
+                    moka::future::Cache::builder()
+                        .time_to_live(Duration::from_secs(60 * 60))
+                        .build(),
+                ),
+            };
+            issuers.push(Issuer {
+                config: issuer.clone(),
+                jwks,
+            });
+        }
+        Ok(Self {
+            issuers: Arc::new(issuers),
+            client: reqwest::Client::new(),
+            github,
+            token_ttl: config.token_ttl,
+            tokens: Arc::new(
+                moka::future::Cache::builder()
+                    .max_capacity(MAX_PUBLISH_TOKENS)
+                    .time_to_live(Duration::from_secs(config.token_ttl))
+                    .build(),
+            ),
+        })
+    }
+
+    async fn verify(&self, jwt: &str) -> Result<Claims, HttpError> {
+        let iss = unverified_issuer(jwt).ok_or_else(|| unauthorized("malformed OIDC token"))?;
+        let issuer = self
+            .issuers
+            .iter()
+            .find(|issuer| issuer.config.issuer == iss)
+            .ok_or_else(|| unauthorized("OIDC token is from an unknown issuer"))?;
+        let header = jsonwebtoken::decode_header(jwt)
+            .map_err(|e| unauthorized(&format!("malformed OIDC token: {e}")))?;
+        // shared-secret algorithms would let anyone holding the JWKS sign tokens
+        if matches!(
+            header.alg,
+            Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512
+        ) {
+            return Err(unauthorized("OIDC token must be signed with a public key"));
+        }
+        let find = |jwks: &JwkSet| match &header.kid {
+            Some(kid) => jwks.find(kid).cloned(),
+            None if jwks.keys.len() == 1 => jwks.keys.first().cloned(),
+            None => None,
+        };
+        let jwk = match find(&*issuer.jwks(&self.client, false).await?) {
+            Some(jwk) => Some(jwk),
+            // the issuer may have rotated its keys since they were fetched
+            None => find(&*issuer.jwks(&self.client, true).await?),
+        }
+        .ok_or_else(|| unauthorized("OIDC token is signed with an unknown key"))?;
+        // the header is the token's own say-so, the key decides what it may be
+        let algorithms = key_algorithms(&jwk);
+        if !algorithms.contains(&header.alg) {
+            return Err(unauthorized(&format!(
+                "OIDC token is signed with {:?}, which its key is not for",
+                header.alg
+            )));
+        }
+        let key = DecodingKey::from_jwk(&jwk)
+            .map_err(|e| unauthorized(&format!("unusable issuer key: {e}")))?;
+        let mut validation = Validation::new(header.alg);
+        validation.algorithms = algorithms;
+        validation.set_issuer(&[&issuer.config.issuer]);
+        validation.set_audience(&[&issuer.config.audience]);
+        jsonwebtoken::decode::<Claims>(jwt, &key, &validation)
+            .map(|data| data.claims)
+            .map_err(|e| unauthorized(&format!("invalid OIDC token: {e}")))
+    }
+
+    /// Check `jwt` against the trusted publishers of `name` and issue a publish token for it.
+    pub async fn exchange(&self, jwt: &str, name: &CrateName) -> Result<IssuedToken, HttpError> {
+        let claims = self.verify(jwt).await?;
+        let auth_rules = self.github.rules();
+        let trusted = auth_rules.get(&name.normalized).is_some_and(|rule| {
+            rule.trusted_publishers
+                .iter()
+                .any(|publisher| publisher.matches(&claims))
+        });
+        if !trusted {
+            return Err(error(
+                StatusCode::FORBIDDEN,
+                &format!(
+                    "no trusted publisher of {} matches this workflow",
+                    name.original
+                ),
+            ));
+        }
+        let token = format!("{TOKEN_PREFIX}{}", secret());
+        let repository = claims.repository.unwrap_or_default();
+        info!(
+            crate_name = name.normalized,
+            repository, "trusted_publishing_token_issued"
+        );
+        let publish_token = PublishToken {
+            crate_name: name.normalized.clone(),
+            repository,
+        };
+        self.tokens
+            .insert(TokenHash::of(&token), publish_token.clone())
+            .await;
+        Ok(IssuedToken {
+            token,
+            expires_at: now() + self.token_ttl,
+            principal: publish_token.principal(),
+        })
+    }
+
+    /// Drop a publish token before it expires, e.g. at the end of the CI job.
+    pub async fn revoke(&self, token: &RawAuthorization) -> Result<(), HttpError> {
+        self.publish_token(token).await?;
+        self.tokens.invalidate(&TokenHash::of(token.value())).await;
+        Ok(())
+    }
+
+    async fn publish_token(&self, token: &RawAuthorization) -> Result<PublishToken, HttpError> {
+        self.tokens
+            .get(&TokenHash::of(token.value()))
+            .await
+            .ok_or_else(|| unauthorized("invalid or expired publish token"))
+    }
+}
+
+impl PublishToken {
+    fn principal(self) -> Principal {
+        Principal::new(Provider::TrustedPublishing, self.repository).with_scopes(
+            vec![Scope::PublishNew, Scope::PublishUpdate],
+            vec![self.crate_name],
+        )
+    }
+}
+
+impl TrustedPublishing {
+    async fn own_crate(
+        &self,
+        token: &RawAuthorization,
+        name: &CrateName,
+    ) -> Result<PublishToken, HttpError> {
+        let publish_token = self.publish_token(token).await?;
+        if publish_token.crate_name != name.normalized {
+            return Err(error(
+                StatusCode::FORBIDDEN,
+                &format!(
+                    "this publish token is limited to {}",
+                    publish_token.crate_name
+                ),
+            ));
+        }
+        Ok(publish_token)
+    }
+}
+
+impl Auth for TrustedPublishing {
+    async fn authenticate(&self, token: Option<&RawAuthorization>) -> Result<Principal, HttpError> {
+        let token = token.ok_or_else(authentication_required)?;
+        Ok(self.publish_token(token).await?.principal())
+    }
+
+    async fn readable(
+        &self,
+        token: Option<&RawAuthorization>,
+        name: &CrateName,
+    ) -> Result<Principal, HttpError> {
+        let token = token.ok_or_else(authentication_required)?;
+        Ok(self.own_crate(token, name).await?.principal())
+    }
+
+    async fn downloadable(
+        &self,
+        token: Option<&RawAuthorization>,
+        name: &CrateName,
+    ) -> Result<Principal, HttpError> {
+        self.readable(token, name).await
+    }
+
+    async fn writable(
+        &self,
+        token: &RawAuthorization,
+        name: &CrateName,
+        mutation: &Mutation<'_>,
+    ) -> Result<Principal, HttpError> {
+        let publish_token = self.own_crate(token, name).await?;
+        if !matches!(mutation, Mutation::Publish { .. }) {
+            return Err(error(
+                StatusCode::FORBIDDEN,
+                "publish tokens can only publish",
+            ));
+        }
+        info!(
+            crate_name = publish_token.crate_name,
+            repository = publish_token.repository,
+            "trusted_publish"
+        );
+        Ok(publish_token.principal())
+    }
+
+    async fn permissions(
+        &self,
+        token: Option<&RawAuthorization>,
+        name: &CrateName,
+    ) -> Result<Permissions, HttpError> {
+        let token = token.ok_or_else(authentication_required)?;
+        if self.publish_token(token).await?.crate_name != name.normalized {
+            return Ok(Permissions::default());
+        }
+        Ok(Permissions {
+            read: true,
+            download: true,
+            write: vec![Scope::PublishNew, Scope::PublishUpdate],
+        })
+    }
+
+    async fn as_registry_user(
+        &self,
+        token: Option<&RawAuthorization>,
+        user: &str,
+    ) -> Result<RegistryUser, HttpError> {
+        let token = token.ok_or_else(authentication_required)?;
+        self.publish_token(token).await?;
+        self.github.registry_user(None, user).await
+    }
+}
+
+impl Authenticator for TrustedPublishing {
+    fn accepts(&self, token: &str) -> bool {
+        token.starts_with(TOKEN_PREFIX)
+    }
+}
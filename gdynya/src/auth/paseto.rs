@@ -0,0 +1,459 @@
+//! Asymmetric tokens as specified by cargo RFC 3231.
+//!
+//! Users register a P-384 public key (the `k3.public.` PASERK `cargo login` prints with the
+//! `cargo:paseto` credential provider), and cargo then signs a short-lived `v3.public` PASETO
+//! per request instead of sending a long-lived secret.
+//!
+//! A token signed for a write is accepted once. Which tokens were used is remembered in the
+//! memory of each instance, so with several instances behind a load balancer a captured token
+//! can be replayed against another instance within `iat_window`.
+
+use std::{sync::Arc, time::Duration};
+
+use axum::http::StatusCode;
+use base64::{Engine, prelude::BASE64_URL_SAFE_NO_PAD};
+use digest::Digest;
+use p384::ecdsa::{Signature, VerifyingKey, signature::Verifier};
+use serde::{Deserialize, Serialize};
+use sha2::Sha384;
+use time::{OffsetDateTime, format_description::well_known::Rfc3339};
+
+use super::{
+    Auth, Authenticator, Mutation, Permissions, Principal, Provider, TokenHash,
+    authentication_required, error, github::GitHubAuth, now,
+};
+use crate::{
+    HttpError,
+    api_schema::{CrateName, RegistryUser},
+    axum_aux::RawAuthorization,
+    store::Store,
+};
+
+pub const TOKEN_PREFIX: &str = "v3.public.";
+const PASERK_PUBLIC: &str = "k3.public.";
+const PASERK_PID: &str = "k3.pid.";
+const SIGNATURE_LEN: usize = 96;
+/// Used write tokens remembered at once. They cannot be evicted early without becoming
+/// replayable, so writes are turned away until some expire instead.
+const MAX_USED_TOKENS: u64 = 100_000;
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct PasetoConfig {
+    /// Index URL cargo signs tokens for, e.g. `sparse+https://crates.example.com/`.
+    pub registry_url: String,
+    /// Seconds a token's `iat` may be away from the server clock. Mutation tokens cannot be
+    /// replayed within this window either, since each is accepted only once.
+    #[serde(default = "default_iat_window")]
+    pub iat_window: u64,
+}
+
+fn default_iat_window() -> u64 {
+    5 * 60
+}
+
+/// A registered public key.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct KeyRecord {
+    /// `k3.pid.` PASERK of the key, which cargo sends as the footer's `kid`.
+    pub kid: String,
+    /// GitHub login the key acts for. The auth rules still apply to it.
+    pub owner: String,
+    pub name: String,
+    /// `k3.public.` PASERK.
+    pub public_key: String,
+    pub created_at: u64,
+}
+
+#[derive(Deserialize)]
+pub struct RegisterKeyRequest {
+    pub name: String,
+    pub public_key: String,
+}
+
+fn parse_public_key(paserk: &str) -> Option<VerifyingKey> {
+    let key = BASE64_URL_SAFE_NO_PAD
+        .decode(paserk.strip_prefix(PASERK_PUBLIC)?)
+        .ok()?;
+    // PASERK v3 keys are always point-compressed
+    if key.len() != 49 {
+        return None;
+    }
+    VerifyingKey::from_sec1_bytes(&key).ok()
+}
+
+/// `k3.pid.` PASERK identifying a `k3.public.` PASERK.
+pub fn key_id(paserk: &str) -> String {
+    let digest = Sha384::digest(format!("{PASERK_PID}{paserk}"));
+    format!(
+        "{PASERK_PID}{}",
+        BASE64_URL_SAFE_NO_PAD.encode(&digest[..33])
+    )
+}
+
+/// Pre-authentication encoding from the PASETO spec.
+fn pae(pieces: &[&[u8]]) -> Vec<u8> {
+    let le64 = |n: usize| (n as u64 & !(1 << 63)).to_le_bytes();
+    let mut out = le64(pieces.len()).to_vec();
+    for piece in pieces {
+        out.extend_from_slice(&le64(piece.len()));
+        out.extend_from_slice(piece);
+    }
+    out
+}
+
+#[derive(Deserialize)]
+struct Footer {
+    url: String,
+    kid: String,
+}
+
+#[derive(Deserialize)]
+struct Message {
+    iat: String,
+    mutation: Option<String>,
+    name: Option<String>,
+    vers: Option<String>,
+    cksum: Option<String>,
+    challenge: Option<String>,
+    v: Option<u8>,
+}
+
+fn unauthorized(message: &str) -> HttpError {
+    error(StatusCode::UNAUTHORIZED, message)
+}
+
+fn same_registry(a: &str, b: &str) -> bool {
+    let normalize = |url: &'_ str| {
+        url.trim_start_matches("sparse+")
+            .trim_end_matches('/')
+            .to_string()
+    };
+    normalize(a) == normalize(b)
+}
+
+impl Message {
+    /// The fields cargo binds to a write have to describe exactly this write, and reads must
+    /// not use a token minted for a write.
+    fn check(&self, name: Option<&CrateName>, mutation: Option<&Mutation<'_>>) -> bool {
+        let Some(mutation) = mutation else {
+            return self.mutation.is_none();
+        };
+        let (kind, vers, cksum) = match mutation {
+            Mutation::Publish { vers, cksum, .. } => ("publish", Some(*vers), Some(*cksum)),
+            Mutation::Yank { vers } => ("yank", Some(*vers), None),
+            Mutation::Unyank { vers } => ("unyank", Some(*vers), None),
+            Mutation::Owners => ("owners", None, None),
+        };
+        let same_name = match (&self.name, name) {
+            (Some(signed), Some(name)) => signed
+                .parse::<CrateName>()
+                .is_ok_and(|signed| signed.normalized == name.normalized),
+            _ => false,
+        };
+        self.mutation.as_deref() == Some(kind)
+            && same_name
+            && vers.is_none_or(|vers| self.vers.as_deref() == Some(&vers.to_string()))
+            && cksum.is_none_or(|cksum| self.cksum.as_deref() == Some(cksum))
+    }
+}
+
+/// Registered public keys.
+#[derive(Clone)]
+pub struct PublicKeys<S> {
+    store: S,
+    github: GitHubAuth,
+    // kid -> record, so that index fetches do not hit the store for every file
+    records: Arc<moka::future::Cache<String, KeyRecord>>,
+}
+
+impl<S: Store> PublicKeys<S> {
+    pub fn new(store: S, github: GitHubAuth) -> Self {
+        Self {
+            store,
+            github,
+            records: Arc::new(
+                moka::future::Cache::builder()
+                    .max_capacity(1024)
+                    .time_to_live(Duration::from_secs(30))
+                    .build(),
+            ),
+        }
+    }
+
+    async fn get(&self, kid: &str) -> Result<Option<KeyRecord>, HttpError> {
+        if let Some(record) = self.records.get(kid).await {
+            return Ok(Some(record));
+        }
+        let record = self.store.get_key(kid).await?;
+        if let Some(record) = &record {
+            self.records.insert(kid.to_string(), record.clone()).await;
+        }
+        Ok(record)
+    }
+
+    /// Register a key for the owner of a GitHub token.
+    pub async fn register(
+        &self,
+        token: &RawAuthorization,
+        req: RegisterKeyRequest,
+    ) -> Result<KeyRecord, HttpError> {
+        let owner = self.github.login(token.value()).await?;
+        if parse_public_key(&req.public_key).is_none() {
+            return Err(error(
+                StatusCode::BAD_REQUEST,
+                "public_key must be a k3.public PASERK",
+            ));
+        }
+        let kid = key_id(&req.public_key);
+        if self.store.get_key(&kid).await?.is_some() {
+            return Err(error(StatusCode::CONFLICT, "key is already registered"));
+        }
+        let record = KeyRecord {
+            kid,
+            owner,
+            name: req.name,
+            public_key: req.public_key,
+            created_at: now(),
+        };
+        self.store.put_key(&record).await?;
+        Ok(record)
+    }
+
+    pub async fn list(&self, token: &RawAuthorization) -> Result<Vec<KeyRecord>, HttpError> {
+        let owner = self.github.login(token.value()).await?;
+        Ok(self
+            .store
+            .list_keys()
+            .await?
+            .into_iter()
+            .filter(|record| record.owner == owner)
+            .collect())
+    }
+
+    pub async fn revoke(&self, token: &RawAuthorization, kid: &str) -> Result<(), HttpError> {
+        let owner = self.github.login(token.value()).await?;
+        // someone else's key is reported as missing rather than forbidden
+        match self.store.get_key(kid).await? {
+            Some(record) if record.owner == owner => {
+                self.store.delete_key(kid).await?;
+                self.records.invalidate(kid).await;
+                Ok(())
+            }
+            _ => Err(error(StatusCode::NOT_FOUND, "no such key")),
+        }
+    }
+}
+
+/// Accepts `v3.public` PASETOs signed with a registered key.
+#[derive(Clone)]
+pub struct PasetoAuth<S> {
+    keys: PublicKeys<S>,
+    // `None` turns asymmetric tokens off
+    config: Option<Arc<PasetoConfig>>,
+    // hashes of mutation tokens already accepted
+    used: Arc<moka::future::Cache<TokenHash, ()>>,
+}
+
+impl<S: Store> PasetoAuth<S> {
+    pub fn new(keys: PublicKeys<S>, config: Option<PasetoConfig>) -> Self {
+        let window = config.as_ref().map_or(0, |config| config.iat_window);
+        Self {
+            keys,
+            config: config.map(Arc::new),
+            used: Arc::new(
+                moka::future::Cache::builder()
+                    .max_capacity(MAX_USED_TOKENS)
+                    // a token is rejected by its iat once it leaves the window on either side
+                    .time_to_live(Duration::from_secs(2 * window + 1))
+                    .build(),
+            ),
+        }
+    }
+
+    /// Check signature, footer and `iat` of `token`, and that it was minted for this request.
+    async fn verify(
+        &self,
+        token: &str,
+        name: Option<&CrateName>,
+        mutation: Option<&Mutation<'_>>,
+    ) -> Result<KeyRecord, HttpError> {
+        let Some(config) = &self.config else {
+            return Err(unauthorized("asymmetric tokens are not enabled"));
+        };
+        let malformed = || unauthorized("malformed PASETO");
+        let (payload, footer) = token
+            .strip_prefix(TOKEN_PREFIX)
+            .and_then(|rest| rest.split_once('.'))
+            .ok_or_else(malformed)?;
+        let payload = BASE64_URL_SAFE_NO_PAD
+            .decode(payload)
+            .map_err(|_| malformed())?;
+        let footer = BASE64_URL_SAFE_NO_PAD
+            .decode(footer)
+            .map_err(|_| malformed())?;
+        if payload.len() <= SIGNATURE_LEN {
+            return Err(malformed());
+        }
+        let (message, signature) = payload.split_at(payload.len() - SIGNATURE_LEN);
+        let parsed_footer: Footer = serde_json::from_slice(&footer).map_err(|_| malformed())?;
+        if !same_registry(&parsed_footer.url, &config.registry_url) {
+            return Err(unauthorized("PASETO was signed for another registry"));
+        }
+        let record = self
+            .keys
+            .get(&parsed_footer.kid)
+            .await?
+            .ok_or_else(|| unauthorized("PASETO is signed with an unknown key"))?;
+        let key = parse_public_key(&record.public_key)
+            .ok_or_else(|| unauthorized("registered key is unusable"))?;
+        let signature = Signature::from_slice(signature).map_err(|_| malformed())?;
+        let compressed = key.to_encoded_point(true);
+        let signed = pae(&[
+            compressed.as_bytes(),
+            TOKEN_PREFIX.as_bytes(),
+            message,
+            &footer,
+            b"",
+        ]);
+        key.verify(&signed, &signature)
+            .map_err(|_| unauthorized("invalid PASETO signature"))?;
+
+        let message: Message = serde_json::from_slice(message).map_err(|_| malformed())?;
+        if message.v.is_some_and(|v| v != 1) {
+            return Err(unauthorized("unsupported PASETO message version"));
+        }
+        // challenges are never handed out, so none can be answered
+        if message.challenge.is_some() {
+            return Err(unauthorized("unknown challenge"));
+        }
+        let iat = OffsetDateTime::parse(&message.iat, &Rfc3339)
+            .map_err(|_| malformed())?
+            .unix_timestamp();
+        if iat.abs_diff(now() as i64) > config.iat_window {
+            return Err(unauthorized("PASETO is expired or from the future"));
+        }
+        if !message.check(name, mutation) {
+            return Err(error(
+                StatusCode::FORBIDDEN,
+                "PASETO was signed for a different operation",
+            ));
+        }
+        Ok(record)
+    }
+
+    /// Tokens for a write are good for one request only.
+    async fn use_up(&self, token: &str) -> Result<(), HttpError> {
+        if self.used.entry_count() >= MAX_USED_TOKENS {
+            return Err(HttpError {
+                error_type: StatusCode::SERVICE_UNAVAILABLE,
+                message: "too many signed writes, retry later".to_string(),
+                verbose_message: String::new(),
+                contexts: Vec::new(),
+                retry_after: Some(
+                    self.config
+                        .as_ref()
+                        .map_or(1, |config| config.iat_window.max(1)),
+                ),
+            });
+        }
+        let fresh = self
+            .used
+            .entry(TokenHash::of(token))
+            .or_insert(())
+            .await
+            .is_fresh();
+        if !fresh {
+            return Err(unauthorized("PASETO was already used"));
+        }
+        Ok(())
+    }
+}
+
+impl<S: Store + Sync> Auth for PasetoAuth<S> {
+    async fn authenticate(&self, token: Option<&RawAuthorization>) -> Result<Principal, HttpError> {
+        let token = token.ok_or_else(authentication_required)?;
+        let record = self.verify(token.value(), None, None).await?;
+        Ok(Principal::new(Provider::Paseto, record.owner).with_token_id(record.kid))
+    }
+
+    async fn readable(
+        &self,
+        token: Option<&RawAuthorization>,
+        name: &CrateName,
+    ) -> Result<Principal, HttpError> {
+        let token = token.ok_or_else(authentication_required)?;
+        let record = self.verify(token.value(), None, None).await?;
+        self.keys.github.readable_by(&record.owner, name).await?;
+        Ok(Principal::new(Provider::Paseto, record.owner).with_token_id(record.kid))
+    }
+
+    async fn downloadable(
+        &self,
+        token: Option<&RawAuthorization>,
+        name: &CrateName,
+    ) -> Result<Principal, HttpError> {
+        let token = token.ok_or_else(authentication_required)?;
+        let record = self.verify(token.value(), None, None).await?;
+        self.keys
+            .github
+            .downloadable_by(&record.owner, name)
+            .await?;
+        Ok(Principal::new(Provider::Paseto, record.owner).with_token_id(record.kid))
+    }
+
+    async fn writable(
+        &self,
+        token: &RawAuthorization,
+        name: &CrateName,
+        mutation: &Mutation<'_>,
+    ) -> Result<Principal, HttpError> {
+        let record = self
+            .verify(token.value(), Some(name), Some(mutation))
+            .await?;
+        self.use_up(token.value()).await?;
+        self.keys
+            .github
+            .writable_by(&record.owner, name, mutation.scope())
+            .await?;
+        Ok(Principal::new(Provider::Paseto, record.owner).with_token_id(record.kid))
+    }
+
+    async fn identify(
+        &self,
+        token: &RawAuthorization,
+        name: &CrateName,
+        mutation: &Mutation<'_>,
+    ) -> Result<Principal, HttpError> {
+        let record = self
+            .verify(token.value(), Some(name), Some(mutation))
+            .await?;
+        Ok(Principal::new(Provider::Paseto, record.owner).with_token_id(record.kid))
+    }
+
+    async fn permissions(
+        &self,
+        token: Option<&RawAuthorization>,
+        name: &CrateName,
+    ) -> Result<Permissions, HttpError> {
+        let token = token.ok_or_else(authentication_required)?;
+        let record = self.verify(token.value(), None, None).await?;
+        self.keys.github.permissions_of(&record.owner, name).await
+    }
+
+    async fn as_registry_user(
+        &self,
+        token: Option<&RawAuthorization>,
+        user: &str,
+    ) -> Result<RegistryUser, HttpError> {
+        let token = token.ok_or_else(authentication_required)?;
+        self.verify(token.value(), None, None).await?;
+        self.keys.github.registry_user(None, user).await
+    }
+}
+
+impl<S: Store + Sync> Authenticator for PasetoAuth<S> {
+    fn accepts(&self, token: &str) -> bool {
+        token.starts_with(TOKEN_PREFIX)
+    }
+}
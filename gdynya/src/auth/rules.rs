@@ -0,0 +1,339 @@
+use std::collections::BTreeMap;
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use super::{
+    github::{Access, Rule},
+    oidc::TrustedPublisher,
+};
+use crate::api_schema::CrateName;
+
+#[derive(Debug, thiserror::Error)]
+pub enum RulesError {
+    #[error(transparent)]
+    Parse(#[from] serde_yaml::Error),
+    #[error("{}", .0.iter().map(|problem| problem.message.as_str()).collect::<Vec<_>>().join("\n"))]
+    Invalid(Vec<RuleProblem>),
+}
+
+#[derive(Debug)]
+pub struct RuleProblem {
+    /// Keys leading to the rule with the problem as written in the file, e.g. `crates` and
+    /// `acme-*`; just the crate name in the legacy layout.
+    pub path: Vec<String>,
+    pub message: String,
+}
+
+impl RuleProblem {
+    /// 1-based line of the rule with the problem in `src`, if the file is in block style.
+    pub fn line(&self, src: &str) -> Option<usize> {
+        let mut depth = 0;
+        // indentation of the last key matched, and of the keys directly under it
+        let mut parent = None;
+        let mut child = None;
+        for (i, line) in src.lines().enumerate() {
+            let content = line.trim_start();
+            if content.is_empty() || content.starts_with('#') || content == "---" {
+                continue;
+            }
+            let indent = line.len() - content.len();
+            if parent.is_some_and(|parent| indent <= parent) {
+                return None;
+            }
+            if indent != *child.get_or_insert(indent) {
+                continue;
+            }
+            let key = content
+                .split_once(':')
+                .map(|(key, _)| key.trim().trim_matches(['"', '\'']));
+            if key == self.path.get(depth).map(String::as_str) {
+                depth += 1;
+                if depth == self.path.len() {
+                    return Some(i + 1);
+                }
+                parent = Some(indent);
+                child = None;
+            }
+        }
+        None
+    }
+}
+
+/// Who may do what with a crate. `write` stands for each of `publish`, `yank` and `owners`
+/// that is not given, and `read` for `download`; without `write`, all three must be given.
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+pub struct CrateRule {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub write: Option<Rule>,
+    pub read: Rule,
+    /// Fetching `.crate` files; the index and owners only need `read`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub download: Option<Rule>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub publish: Option<Rule>,
+    /// Yanking and unyanking.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub yank: Option<Rule>,
+    /// Adding and removing owners.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub owners: Option<Rule>,
+    /// CI workflows that may publish without a user token, see [`super::oidc`].
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub trusted_publishers: Vec<TrustedPublisher>,
+}
+
+/// Rules file layout.
+///
+/// Lookup precedence for a crate is:
+///
+/// 1. an exact entry in `crates`
+/// 2. the longest matching prefix in `namespaces`
+/// 3. the most specific glob in `crates` (the one with the most non-`*` characters,
+///    ties broken by the pattern's lexical order)
+/// 4. `default`
+///
+/// A file in this layout starts with `version: 2`. Without it, the file is read in the legacy
+/// layout, a flat map from crate name to [`CrateRule`]: `crates`, `namespaces` and `default`
+/// are crate names too, so the keys alone cannot tell the two apart.
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct AuthRules {
+    /// Always `2`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<u64>,
+    /// Keys are crate names or glob patterns such as `acme-*`.
+    #[serde(default)]
+    pub crates: BTreeMap<String, CrateRule>,
+    /// Prefix claims. A claim lets its owners create new crates under the prefix
+    /// without touching this file, and wins over any glob in `crates`. `acme` claims `acme`
+    /// and `acme-*` (or `acme_*`), but not `acmefoo`.
+    #[serde(default)]
+    pub namespaces: BTreeMap<String, CrateRule>,
+    #[serde(default)]
+    pub default: Option<CrateRule>,
+}
+
+/// `version` of a file in the sectioned layout.
+const LAYOUT_VERSION: u64 = 2;
+
+pub(crate) fn normalize(name: &str) -> String {
+    name.replace('_', "-")
+}
+
+/// Whether `name` matches `pattern`, where `*` stands for any run of characters.
+pub fn glob_match(pattern: &str, name: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let Some(first) = parts.next() else {
+        return name.is_empty();
+    };
+    let Some(mut rest) = name.strip_prefix(first) else {
+        return false;
+    };
+    let parts = parts.collect::<Vec<_>>();
+    let Some((last, middle)) = parts.split_last() else {
+        return rest.is_empty();
+    };
+    for part in middle {
+        match rest.find(part) {
+            Some(pos) => rest = &rest[pos + part.len()..],
+            None => return false,
+        }
+    }
+    rest.ends_with(last)
+}
+
+/// Whether the namespace `prefix` covers `name`: it must end where a word of the name does.
+fn claims(prefix: &str, name: &str) -> bool {
+    match name.strip_prefix(prefix) {
+        Some(rest) => rest.is_empty() || prefix.ends_with('-') || rest.starts_with('-'),
+        None => false,
+    }
+}
+
+impl CrateRule {
+    /// The rule deciding `access`, and the key it is under. `None`, meaning nobody, only
+    /// for rules built in code; files without the rule fail [`AuthRules::from_yaml`].
+    pub fn get(&self, access: Access) -> Option<(&'static str, &Rule)> {
+        let (key, rule) = match access {
+            Access::Read => return Some(("read", &self.read)),
+            Access::Download => ("download", &self.download),
+            Access::Publish => ("publish", &self.publish),
+            Access::Yank => ("yank", &self.yank),
+            Access::Owners => ("owners", &self.owners),
+        };
+        match (rule, &self.write) {
+            (Some(rule), _) => Some((key, rule)),
+            (None, _) if access == Access::Download => Some(("read", &self.read)),
+            (None, write) => write.as_ref().map(|write| ("write", write)),
+        }
+    }
+
+    fn lint(&self, path: &str, errors: &mut Vec<String>) {
+        self.read.lint(&format!("{path}.read"), errors);
+        for (key, rule) in [
+            ("write", &self.write),
+            ("download", &self.download),
+            ("publish", &self.publish),
+            ("yank", &self.yank),
+            ("owners", &self.owners),
+        ] {
+            if let Some(rule) = rule {
+                rule.lint(&format!("{path}.{key}"), errors);
+            }
+        }
+        if self.write.is_none()
+            && [&self.publish, &self.yank, &self.owners]
+                .iter()
+                .any(|rule| rule.is_none())
+        {
+            errors.push(format!(
+                "{path}: without write, publish, yank and owners must all be given"
+            ));
+        }
+        for (i, publisher) in self.trusted_publishers.iter().enumerate() {
+            publisher.lint(&format!("{path}.trusted_publishers[{i}]"), errors);
+        }
+    }
+}
+
+impl AuthRules {
+    pub fn from_yaml(src: &str) -> Result<Self, RulesError> {
+        let value: serde_yaml::Value = serde_yaml::from_str(src)?;
+        // a legacy crate called `version` has a rule there, not a number
+        let version = value.get("version").and_then(serde_yaml::Value::as_u64);
+        if let Some(version) = version
+            && version != LAYOUT_VERSION
+        {
+            return Err(RulesError::Invalid(vec![RuleProblem {
+                path: vec!["version".to_string()],
+                message: format!("version: {version} is not supported, only {LAYOUT_VERSION} is"),
+            }]));
+        }
+        let is_legacy = version.is_none();
+        let rules = if is_legacy {
+            Self {
+                crates: serde_yaml::from_str(src)?,
+                ..Default::default()
+            }
+        } else {
+            serde_yaml::from_str(src)?
+        };
+        rules.validate(is_legacy)?;
+        Ok(rules.normalized())
+    }
+
+    fn validate(&self, is_legacy: bool) -> Result<(), RulesError> {
+        let mut problems = Vec::new();
+        let mut report = |path: &[&str], errors: Vec<String>| {
+            let path = match path {
+                ["crates", key] if is_legacy => vec![key.to_string()],
+                path => path.iter().map(ToString::to_string).collect(),
+            };
+            problems.extend(errors.into_iter().map(|message| RuleProblem {
+                path: path.clone(),
+                message,
+            }))
+        };
+        for (pattern, rule) in &self.crates {
+            let mut errors = Vec::new();
+            if pattern.contains('*') {
+                if !pattern
+                    .chars()
+                    .all(|c| c.is_alphanumeric() || matches!(c, '-' | '_' | '*'))
+                {
+                    errors.push(format!("crates.{pattern}: invalid character in pattern"));
+                }
+            } else if let Err(e) = pattern.parse::<CrateName>() {
+                errors.push(format!("crates.{pattern}: {e}"));
+            }
+            rule.lint(&format!("crates.{pattern}"), &mut errors);
+            report(&["crates", pattern], errors);
+        }
+        for (prefix, rule) in &self.namespaces {
+            let mut errors = Vec::new();
+            if let Err(e) = prefix.parse::<CrateName>() {
+                errors.push(format!("namespaces.{prefix}: {e}"));
+            }
+            rule.lint(&format!("namespaces.{prefix}"), &mut errors);
+            report(&["namespaces", prefix], errors);
+        }
+        if let Some(rule) = &self.default {
+            let mut errors = Vec::new();
+            rule.lint("default", &mut errors);
+            report(&["default"], errors);
+        }
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(RulesError::Invalid(problems))
+        }
+    }
+
+    fn normalized(self) -> Self {
+        Self {
+            version: self.version,
+            crates: self
+                .crates
+                .into_iter()
+                .map(|(pattern, rule)| (normalize(&pattern), rule))
+                .collect(),
+            namespaces: self
+                .namespaces
+                .into_iter()
+                .map(|(prefix, rule)| (normalize(&prefix), rule))
+                .collect(),
+            default: self.default,
+        }
+    }
+
+    /// Whether every crate, listed or not, can be read and downloaded without a token.
+    pub fn anyone_reads_everything(&self) -> bool {
+        let anonymous = |rule: &CrateRule| {
+            [Access::Read, Access::Download].into_iter().all(|access| {
+                rule.get(access)
+                    .is_some_and(|(_, rule)| rule.allows_anonymous())
+            })
+        };
+        self.default.as_ref().is_some_and(anonymous)
+            && self
+                .crates
+                .values()
+                .chain(self.namespaces.values())
+                .all(anonymous)
+    }
+
+    /// `name` must be the normalized crate name.
+    pub fn get(&self, name: &str) -> Option<&CrateRule> {
+        self.lookup(name).map(|(_, rule)| rule)
+    }
+
+    /// Like [`AuthRules::get`], also telling where the rule is, e.g. `crates.acme-*`.
+    pub fn lookup(&self, name: &str) -> Option<(String, &CrateRule)> {
+        if let Some(rule) = self.crates.get(name) {
+            return Some((format!("crates.{name}"), rule));
+        }
+        let namespace = self
+            .namespaces
+            .iter()
+            .filter(|(prefix, _)| claims(prefix, name))
+            .max_by_key(|(prefix, _)| prefix.len());
+        if let Some((prefix, rule)) = namespace {
+            return Some((format!("namespaces.{prefix}"), rule));
+        }
+        let glob = self
+            .crates
+            .iter()
+            .filter(|(pattern, _)| pattern.contains('*') && glob_match(pattern, name))
+            // BTreeMap iterates in lexical order and max_by_key keeps the last maximum,
+            // so reverse to let the lexically first pattern win ties.
+            .rev()
+            .max_by_key(|(pattern, _)| pattern.chars().filter(|c| *c != '*').count());
+        if let Some((pattern, rule)) = glob {
+            return Some((format!("crates.{pattern}"), rule));
+        }
+        self.default
+            .as_ref()
+            .map(|rule| ("default".to_string(), rule))
+    }
+}
@@ -0,0 +1,151 @@
+//! Static service accounts for machines that have neither a GitHub account nor a CI OIDC
+//! token, read from a YAML file mapping each account name to the SHA-256 of its token:
+//!
+//! ```yaml
+//! deploy-bot: 0a1b2c...  # echo -n "$token" | sha256sum
+//! ```
+//!
+//! Tokens must start with `gds_`. The rules refer to an account with `!service {name:
+//! deploy-bot}`. Account names are not GitHub logins: `is`, org, team and repository rules
+//! never match an account, GitHub is never asked about one, and its decisions are cached
+//! apart from a GitHub user's of the same name. Service accounts are never admins.
+
+use std::{collections::BTreeMap, sync::Arc};
+
+use axum::http::StatusCode;
+
+use super::{
+    Auth, Authenticator, Mutation, Permissions, Principal, Provider, authentication_required,
+    error, github::GitHubAuth, hash,
+};
+use crate::{
+    HttpError,
+    api_schema::{CrateName, RegistryUser},
+    axum_aux::RawAuthorization,
+};
+
+pub const TOKEN_PREFIX: &str = "gds_";
+
+#[derive(Debug, thiserror::Error)]
+pub enum ServiceAccountsError {
+    #[error(transparent)]
+    Parse(#[from] serde_yaml::Error),
+    #[error("{0}: token hash must be a hex SHA-256")]
+    InvalidHash(String),
+    #[error("{0}: token hash is shared with another account")]
+    DuplicateHash(String),
+}
+
+#[derive(Clone)]
+pub struct ServiceAccounts {
+    // hash of the token -> account name
+    accounts: Arc<BTreeMap<String, String>>,
+    github: GitHubAuth,
+}
+
+impl ServiceAccounts {
+    /// No accounts at all; every service account token is rejected.
+    pub fn new(github: GitHubAuth) -> Self {
+        Self {
+            accounts: Default::default(),
+            github,
+        }
+    }
+
+    pub fn from_yaml(src: &str, github: GitHubAuth) -> Result<Self, ServiceAccountsError> {
+        let file: BTreeMap<String, String> = serde_yaml::from_str(src)?;
+        let mut accounts = BTreeMap::new();
+        for (name, token_hash) in file {
+            let token_hash = token_hash.to_ascii_lowercase();
+            if token_hash.len() != 64 || !token_hash.chars().all(|c| c.is_ascii_hexdigit()) {
+                return Err(ServiceAccountsError::InvalidHash(name));
+            }
+            if accounts.insert(token_hash, name.clone()).is_some() {
+                return Err(ServiceAccountsError::DuplicateHash(name));
+            }
+        }
+        Ok(Self {
+            accounts: Arc::new(accounts),
+            github,
+        })
+    }
+
+    fn account(&self, token: &RawAuthorization) -> Result<&str, HttpError> {
+        self.accounts
+            .get(&hash(token.value()))
+            .map(String::as_str)
+            .ok_or_else(|| error(StatusCode::UNAUTHORIZED, "unknown service account token"))
+    }
+}
+
+impl Auth for ServiceAccounts {
+    async fn authenticate(&self, token: Option<&RawAuthorization>) -> Result<Principal, HttpError> {
+        let token = token.ok_or_else(authentication_required)?;
+        Ok(Principal::new(
+            Provider::ServiceAccount,
+            self.account(token)?,
+        ))
+    }
+
+    async fn readable(
+        &self,
+        token: Option<&RawAuthorization>,
+        name: &CrateName,
+    ) -> Result<Principal, HttpError> {
+        let token = token.ok_or_else(authentication_required)?;
+        let account = self.account(token)?;
+        self.github.readable_by_service(account, name).await?;
+        Ok(Principal::new(Provider::ServiceAccount, account))
+    }
+
+    async fn downloadable(
+        &self,
+        token: Option<&RawAuthorization>,
+        name: &CrateName,
+    ) -> Result<Principal, HttpError> {
+        let token = token.ok_or_else(authentication_required)?;
+        let account = self.account(token)?;
+        self.github.downloadable_by_service(account, name).await?;
+        Ok(Principal::new(Provider::ServiceAccount, account))
+    }
+
+    async fn writable(
+        &self,
+        token: &RawAuthorization,
+        name: &CrateName,
+        mutation: &Mutation<'_>,
+    ) -> Result<Principal, HttpError> {
+        let account = self.account(token)?;
+        self.github
+            .writable_by_service(account, name, mutation.scope())
+            .await?;
+        Ok(Principal::new(Provider::ServiceAccount, account))
+    }
+
+    async fn permissions(
+        &self,
+        token: Option<&RawAuthorization>,
+        name: &CrateName,
+    ) -> Result<Permissions, HttpError> {
+        let token = token.ok_or_else(authentication_required)?;
+        self.github
+            .permissions_of_service(self.account(token)?, name)
+            .await
+    }
+
+    async fn as_registry_user(
+        &self,
+        token: Option<&RawAuthorization>,
+        user: &str,
+    ) -> Result<RegistryUser, HttpError> {
+        let token = token.ok_or_else(authentication_required)?;
+        self.account(token)?;
+        self.github.registry_user(None, user).await
+    }
+}
+
+impl Authenticator for ServiceAccounts {
+    fn accepts(&self, token: &str) -> bool {
+        token.starts_with(TOKEN_PREFIX)
+    }
+}
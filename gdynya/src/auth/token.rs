@@ -0,0 +1,330 @@
+use std::{sync::Arc, time::Duration};
+
+use axum::http::StatusCode;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+use super::{
+    Auth, Authenticator, Mutation, Permissions, Principal, Provider, Scope,
+    authentication_required, error,
+    github::GitHubAuth,
+    hash, hash_matches, now,
+    rules::{glob_match, normalize},
+    secret,
+};
+use crate::{
+    HttpError,
+    api_schema::{CrateName, RegistryUser},
+    axum_aux::RawAuthorization,
+    store::Store,
+};
+
+/// Registry tokens look like `gdy_<id>_<secret>`, which also tells them apart from GitHub
+/// tokens.
+pub const TOKEN_PREFIX: &str = "gdy_";
+
+/// A registry-issued token as stored. Only the SHA-256 of the token is kept.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct TokenRecord {
+    pub id: String,
+    /// GitHub login the token acts for. The auth rules still apply to it.
+    pub owner: String,
+    pub name: String,
+    pub hash: String,
+    pub endpoint_scopes: Vec<Scope>,
+    /// Crate name globs like `acme-*` the token may write to. Empty means any crate.
+    pub crate_scopes: Vec<String>,
+    pub created_at: u64,
+    pub expires_at: Option<u64>,
+}
+
+#[derive(Deserialize)]
+pub struct CreateTokenRequest {
+    pub name: String,
+    pub endpoint_scopes: Vec<Scope>,
+    #[serde(default)]
+    pub crate_scopes: Vec<String>,
+    pub expires_in_days: Option<u64>,
+}
+
+/// What the API shows about a token; never the token or its hash.
+#[derive(Serialize)]
+pub struct TokenView {
+    pub id: String,
+    pub name: String,
+    pub endpoint_scopes: Vec<Scope>,
+    pub crate_scopes: Vec<String>,
+    pub created_at: u64,
+    pub expires_at: Option<u64>,
+}
+
+#[derive(Serialize)]
+pub struct CreatedToken {
+    #[serde(flatten)]
+    pub view: TokenView,
+    /// Shown once, at creation.
+    pub token: String,
+}
+
+impl TokenRecord {
+    fn covers(&self, name: &CrateName) -> bool {
+        self.crate_scopes.is_empty()
+            || self
+                .crate_scopes
+                .iter()
+                .any(|pattern| glob_match(pattern, &name.normalized))
+    }
+
+    fn principal(&self) -> Principal {
+        Principal::new(Provider::RegistryToken, &self.owner)
+            .with_token_id(&self.id)
+            .with_scopes(self.endpoint_scopes.clone(), self.crate_scopes.clone())
+    }
+
+    pub fn view(&self) -> TokenView {
+        TokenView {
+            id: self.id.clone(),
+            name: self.name.clone(),
+            endpoint_scopes: self.endpoint_scopes.clone(),
+            crate_scopes: self.crate_scopes.clone(),
+            created_at: self.created_at,
+            expires_at: self.expires_at,
+        }
+    }
+}
+
+/// Accepts registry tokens, evaluating the rules for their owner with [`GitHubAuth`].
+///
+/// Records are cached in memory for 30 seconds per process. With several instances behind a
+/// load balancer, a token revoked on one keeps working on the others until their copy expires.
+#[derive(Clone)]
+pub struct RegistryTokenAuth<S> {
+    store: S,
+    github: GitHubAuth,
+    // id -> record, so that index fetches do not hit the store for every file
+    records: Arc<moka::future::Cache<String, TokenRecord>>,
+}
+
+impl<S: Store> RegistryTokenAuth<S> {
+    pub fn new(store: S, github: GitHubAuth) -> Self {
+        Self {
+            store,
+            github,
+            records: Arc::new(
+                moka::future::Cache::builder()
+                    .max_capacity(1024)
+                    .time_to_live(Duration::from_secs(30))
+                    .build(),
+            ),
+        }
+    }
+
+    pub fn github(&self) -> &GitHubAuth {
+        &self.github
+    }
+
+    async fn verify(&self, token: &str) -> Result<TokenRecord, HttpError> {
+        let invalid = || error(StatusCode::UNAUTHORIZED, "invalid registry token");
+        let (id, _) = token
+            .strip_prefix(TOKEN_PREFIX)
+            .and_then(|rest| rest.split_once('_'))
+            .ok_or_else(invalid)?;
+        let record = match self.records.get(id).await {
+            Some(record) => record,
+            None => {
+                let record = self.store.get_token(id).await?.ok_or_else(invalid)?;
+                self.records.insert(id.to_string(), record.clone()).await;
+                record
+            }
+        };
+        if !hash_matches(&record.hash, token) {
+            return Err(invalid());
+        }
+        if record
+            .expires_at
+            .is_some_and(|expires_at| expires_at <= now())
+        {
+            return Err(error(StatusCode::UNAUTHORIZED, "registry token expired"));
+        }
+        Ok(record)
+    }
+
+    /// GitHub login behind either kind of token.
+    async fn owner(&self, token: &RawAuthorization) -> Result<String, HttpError> {
+        if token.value().starts_with(TOKEN_PREFIX) {
+            Ok(self.verify(token.value()).await?.owner)
+        } else {
+            Ok(self.github.login(token.value()).await?)
+        }
+    }
+
+    /// Issue a token for the owner of a GitHub token. Registry tokens cannot issue tokens.
+    pub async fn create(
+        &self,
+        token: &RawAuthorization,
+        req: CreateTokenRequest,
+    ) -> Result<CreatedToken, HttpError> {
+        if token.value().starts_with(TOKEN_PREFIX) {
+            return Err(error(
+                StatusCode::FORBIDDEN,
+                "registry tokens cannot create registry tokens",
+            ));
+        }
+        let owner = self.github.login(token.value()).await?;
+        if req.endpoint_scopes.is_empty() {
+            return Err(error(
+                StatusCode::BAD_REQUEST,
+                "at least one endpoint scope is required",
+            ));
+        }
+        if let Some(pattern) = req.crate_scopes.iter().find(|pattern| {
+            pattern.is_empty()
+                || !pattern
+                    .chars()
+                    .all(|c| c.is_alphanumeric() || matches!(c, '-' | '_' | '*'))
+        }) {
+            return Err(error(
+                StatusCode::BAD_REQUEST,
+                &format!("invalid crate scope `{pattern}`"),
+            ));
+        }
+        let created_at = now();
+        let expires_at = req
+            .expires_in_days
+            .map(|days| {
+                days.checked_mul(24 * 60 * 60)
+                    .and_then(|secs| created_at.checked_add(secs))
+                    .ok_or_else(|| error(StatusCode::BAD_REQUEST, "expires_in_days is too large"))
+            })
+            .transpose()?;
+        let id = hex::encode(rand::rng().random::<[u8; 8]>());
+        let token = format!("{TOKEN_PREFIX}{id}_{}", secret());
+        let record = TokenRecord {
+            id,
+            owner,
+            name: req.name,
+            hash: hash(&token),
+            endpoint_scopes: req.endpoint_scopes,
+            crate_scopes: req
+                .crate_scopes
+                .iter()
+                .map(|pattern| normalize(pattern))
+                .collect(),
+            created_at,
+            expires_at,
+        };
+        self.store.put_token(&record).await?;
+        Ok(CreatedToken {
+            view: record.view(),
+            token,
+        })
+    }
+
+    pub async fn list(&self, token: &RawAuthorization) -> Result<Vec<TokenView>, HttpError> {
+        let owner = self.owner(token).await?;
+        Ok(self
+            .store
+            .list_tokens()
+            .await?
+            .into_iter()
+            .filter(|record| record.owner == owner)
+            .map(|record| record.view())
+            .collect())
+    }
+
+    pub async fn revoke(&self, token: &RawAuthorization, id: &str) -> Result<(), HttpError> {
+        let owner = self.owner(token).await?;
+        // someone else's token is reported as missing rather than forbidden
+        match self.store.get_token(id).await? {
+            Some(record) if record.owner == owner => {
+                self.store.delete_token(id).await?;
+                self.records.invalidate(id).await;
+                Ok(())
+            }
+            _ => Err(error(StatusCode::NOT_FOUND, "no such token")),
+        }
+    }
+}
+
+impl<S: Store + Sync> Auth for RegistryTokenAuth<S> {
+    async fn authenticate(&self, token: Option<&RawAuthorization>) -> Result<Principal, HttpError> {
+        let token = token.ok_or_else(authentication_required)?;
+        Ok(self.verify(token.value()).await?.principal())
+    }
+
+    async fn readable(
+        &self,
+        token: Option<&RawAuthorization>,
+        name: &CrateName,
+    ) -> Result<Principal, HttpError> {
+        let token = token.ok_or_else(authentication_required)?;
+        // crate scopes limit writes only; builds need to fetch their dependencies
+        let record = self.verify(token.value()).await?;
+        self.github.readable_by(&record.owner, name).await?;
+        Ok(record.principal())
+    }
+
+    async fn downloadable(
+        &self,
+        token: Option<&RawAuthorization>,
+        name: &CrateName,
+    ) -> Result<Principal, HttpError> {
+        let token = token.ok_or_else(authentication_required)?;
+        let record = self.verify(token.value()).await?;
+        self.github.downloadable_by(&record.owner, name).await?;
+        Ok(record.principal())
+    }
+
+    async fn writable(
+        &self,
+        token: &RawAuthorization,
+        name: &CrateName,
+        mutation: &Mutation<'_>,
+    ) -> Result<Principal, HttpError> {
+        let record = self.verify(token.value()).await?;
+        if !record.endpoint_scopes.contains(&mutation.scope()) || !record.covers(name) {
+            return Err(error(
+                StatusCode::FORBIDDEN,
+                "registry token is not scoped for this operation",
+            ));
+        }
+        self.github
+            .writable_by(&record.owner, name, mutation.scope())
+            .await?;
+        Ok(record.principal())
+    }
+
+    async fn permissions(
+        &self,
+        token: Option<&RawAuthorization>,
+        name: &CrateName,
+    ) -> Result<Permissions, HttpError> {
+        let token = token.ok_or_else(authentication_required)?;
+        let record = self.verify(token.value()).await?;
+        let mut permissions = self.github.permissions_of(&record.owner, name).await?;
+        if record.covers(name) {
+            permissions
+                .write
+                .retain(|scope| record.endpoint_scopes.contains(scope));
+        } else {
+            permissions.write.clear();
+        }
+        Ok(permissions)
+    }
+
+    async fn as_registry_user(
+        &self,
+        token: Option<&RawAuthorization>,
+        user: &str,
+    ) -> Result<RegistryUser, HttpError> {
+        let token = token.ok_or_else(authentication_required)?;
+        self.verify(token.value()).await?;
+        self.github.registry_user(None, user).await
+    }
+}
+
+impl<S: Store + Sync> Authenticator for RegistryTokenAuth<S> {
+    fn accepts(&self, token: &str) -> bool {
+        token.starts_with(TOKEN_PREFIX)
+    }
+}
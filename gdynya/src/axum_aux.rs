@@ -1,13 +1,56 @@
+use std::{
+    convert::Infallible,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+};
+
 use axum::{
-    extract::FromRequestParts,
-    http::{HeaderValue, request::Parts},
+    body::Body,
+    extract::{ConnectInfo, FromRequestParts},
+    http::{HeaderValue, StatusCode, request::Parts},
     response::{IntoResponse, Response},
 };
+use futures_util::StreamExt;
 use headers::Header;
+use ipnet::IpNet;
+use zeroize::Zeroize;
+
+use crate::{HttpError, ToHttpError};
+
+/// The largest publish request accepted, index entry and crate file together, as on crates.io.
+pub const MAX_PUBLISH_BODY: usize = 10 * 1024 * 1024;
+
+/// A request body in full, or 413 once it is longer than `limit`.
+pub async fn read_body(body: Body, limit: usize) -> Result<Vec<u8>, HttpError> {
+    let mut full = Vec::new();
+    let mut stream = body.into_data_stream();
+    while let Some(chunk) = stream.next().await {
+        let chunk = chunk.http_error(StatusCode::BAD_REQUEST)?;
+        if full.len() + chunk.len() > limit {
+            let message = format!("request body is larger than {limit} bytes");
+            return Err(HttpError {
+                error_type: StatusCode::PAYLOAD_TOO_LARGE,
+                message: message.clone(),
+                verbose_message: message,
+                contexts: Vec::new(),
+                retry_after: None,
+            });
+        }
+        full.extend_from_slice(&chunk);
+    }
+    Ok(full)
+}
 
+/// The `Authorization` header as sent, wiped from memory when dropped.
 #[derive(Clone)]
 pub struct RawAuthorization(String);
 
+impl Drop for RawAuthorization {
+    fn drop(&mut self) {
+        self.0.zeroize();
+    }
+}
+
 impl RawAuthorization {
     pub fn value(&self) -> &str {
         &self.0
@@ -199,3 +242,58 @@ impl CustomHeader for XForwardedProto {
         values.extend(std::iter::once(HeaderValue::from_str(&self.0).unwrap()))
     }
 }
+
+/// Proxies whose `X-Forwarded-For` is believed, as a request extension. None without it.
+#[derive(Clone, Default, Debug)]
+pub struct TrustedProxies(pub Arc<Vec<IpNet>>);
+
+impl TrustedProxies {
+    fn contains(&self, ip: &IpAddr) -> bool {
+        self.0.iter().any(|net| net.contains(ip))
+    }
+}
+
+/// Where a request came from: the peer address, unless the peer is a [`TrustedProxies`]
+/// entry. Then it is the right-most `X-Forwarded-For` entry that is not, as everything left of
+/// that was written by the client and can be anything.
+pub struct ClientIp(pub Option<String>);
+
+impl<S> FromRequestParts<S> for ClientIp
+where
+    S: Send + Sync,
+{
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let Some(ConnectInfo(peer)) = parts.extensions.get::<ConnectInfo<SocketAddr>>() else {
+            return Ok(Self(None));
+        };
+        let peer = peer.ip();
+        let trusted = parts
+            .extensions
+            .get::<TrustedProxies>()
+            .cloned()
+            .unwrap_or_default();
+        if !trusted.contains(&peer) {
+            return Ok(Self(Some(peer.to_string())));
+        }
+        let forwarded = parts
+            .headers
+            .get_all("X-Forwarded-For")
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(str::trim)
+            .filter(|ip| !ip.is_empty())
+            .collect::<Vec<_>>();
+        let mut client = peer.to_string();
+        for ip in forwarded.into_iter().rev() {
+            client = ip.to_string();
+            // an address that does not parse cannot be a trusted proxy either
+            if !ip.parse().is_ok_and(|ip| trusted.contains(&ip)) {
+                break;
+            }
+        }
+        Ok(Self(Some(client)))
+    }
+}
@@ -0,0 +1,136 @@
+//! Feed of publishes and yank changes, for mirrors and indexers that follow the registry.
+//!
+//! Changes are read back from the audit log, so a follower can resume from any change it
+//! has seen with `GET /api/v1/changes?since=<id>` and keep up with `GET /api/v1/events`.
+//! Both read the store every instance writes to, so either sees changes made through any
+//! instance.
+
+use std::{collections::VecDeque, time::Duration};
+
+use futures_util::Stream;
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::{
+    HttpError,
+    audit::{Action, AuditRecord, Outcome},
+    auth::now,
+    store::{AuditOrder, Store},
+};
+
+/// Changes [`follow`] reads from the store at a time, as many as a default `/changes` page.
+pub const FOLLOW_PAGE: usize = 100;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ChangeKind {
+    Publish,
+    Yank,
+    Unyank,
+    /// A version removed by an admin.
+    Delete,
+}
+
+impl ChangeKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Publish => "publish",
+            Self::Yank => "yank",
+            Self::Unyank => "unyank",
+            Self::Delete => "delete",
+        }
+    }
+
+    fn of(action: Action) -> Option<Self> {
+        match action {
+            Action::Publish => Some(Self::Publish),
+            Action::Yank => Some(Self::Yank),
+            Action::Unyank => Some(Self::Unyank),
+            Action::DeleteVersion => Some(Self::Delete),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Change {
+    /// ID of the audit record of the change; pass it as `since` to continue after it.
+    pub id: String,
+    pub kind: ChangeKind,
+    #[serde(rename = "crate")]
+    pub crate_name: String,
+    pub version: semver::Version,
+    pub at: u64,
+}
+
+impl Change {
+    /// The change a successful request made to a crate version, if any.
+    pub fn of(record: &AuditRecord) -> Option<Self> {
+        if record.outcome != Outcome::Success {
+            return None;
+        }
+        Some(Self {
+            id: record.id.clone(),
+            kind: ChangeKind::of(record.action)?,
+            crate_name: record.crate_name.clone()?,
+            version: record.version.clone()?,
+            at: record.at,
+        })
+    }
+}
+
+/// Up to `limit` changes after the one with ID `since`, or from the start, oldest first.
+///
+/// Changes from the current second are held back: another one from the same second may
+/// still be written with an ID that sorts before them, and would be skipped by a follower
+/// that already moved past it.
+pub async fn changes_after<S: Store>(
+    store: &S,
+    since: Option<&str>,
+    limit: usize,
+) -> Result<Vec<Change>, HttpError> {
+    let at = since.and_then(|id| id.split('-').next()?.parse().ok());
+    let settled = now();
+    let is_change = |record: &AuditRecord| {
+        record.at < settled
+            && since.is_none_or(|since| record.id.as_str() > since)
+            && Change::of(record).is_some()
+    };
+    Ok(store
+        .list_audit(at, AuditOrder::OldestFirst, limit, is_change)
+        .await?
+        .iter()
+        .filter_map(Change::of)
+        .collect())
+}
+
+/// Every change after `since`, or from now on without it, polling `store` every `interval`
+/// once caught up. Changes come as [`changes_after`] settles them, in the same order, so none
+/// is skipped. Failed reads are retried after `interval` rather than ending the stream.
+pub fn follow<S: Store + Send + Sync + 'static>(
+    store: S,
+    since: Option<String>,
+    interval: Duration,
+) -> impl Stream<Item = Change> + Send {
+    // IDs start with their zero-padded second, so this sorts before any from this second on
+    let cursor = since.unwrap_or_else(|| format!("{:020}", now()));
+    let start = (store, cursor, VecDeque::new());
+    futures_util::stream::unfold(start, move |(store, mut cursor, mut pending)| async move {
+        loop {
+            if let Some(change) = pending.pop_front() {
+                return Some((change, (store, cursor, pending)));
+            }
+            match changes_after(&store, Some(&cursor), FOLLOW_PAGE).await {
+                Ok(page) if !page.is_empty() => {
+                    cursor = page[page.len() - 1].id.clone();
+                    pending.extend(page);
+                }
+                Ok(_) => tokio::time::sleep(interval).await,
+                Err(e) => {
+                    warn!(e = e.to_string(), "change_feed_read_failed");
+                    tokio::time::sleep(interval).await;
+                }
+            }
+        }
+    })
+}
@@ -0,0 +1,40 @@
+use ipnet::IpNet;
+use serde::Deserialize;
+
+use crate::{
+    auth::{github::AuthCacheConfig, oidc::TrustedPublishingConfig, paseto::PasetoConfig},
+    follower::FollowerConfig,
+    maintenance::MaintenanceConfig,
+    webhook::WebhookConfig,
+};
+
+/// Server settings too structured for command line flags, read from the `--config` YAML file.
+#[derive(Deserialize, Default, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct ServerConfig {
+    /// GitHub logins allowed to use the `/api/v1/admin` endpoints.
+    #[serde(default)]
+    pub admins: Vec<String>,
+    #[serde(default)]
+    pub auth_cache: AuthCacheConfig,
+    #[serde(default)]
+    pub trusted_publishing: TrustedPublishingConfig,
+    /// Accept cargo's asymmetric tokens; off when missing.
+    pub paseto: Option<PasetoConfig>,
+    #[serde(default)]
+    pub webhooks: Vec<WebhookConfig>,
+    /// Replicate another instance instead of accepting changes; a primary when missing.
+    pub follower: Option<FollowerConfig>,
+    /// Start read-only with this message; also what `SIGUSR1` turns on.
+    pub maintenance: Option<MaintenanceConfig>,
+    /// Load balancers and proxies in front of this server, as CIDR ranges like `10.0.0.0/8`.
+    /// Only requests from these are believed about the client address in `X-Forwarded-For`.
+    #[serde(default)]
+    pub trusted_proxies: Vec<IpNet>,
+}
+
+impl ServerConfig {
+    pub fn from_yaml(src: &str) -> Result<Self, serde_yaml::Error> {
+        serde_yaml::from_str(src)
+    }
+}
@@ -3,6 +3,8 @@ use std::fmt::{Debug, Display};
 use axum::{Json, http::StatusCode, response::IntoResponse};
 use serde::{Serialize, ser::SerializeStruct};
 
+use crate::redact::redact;
+
 #[derive(Debug, Clone, thiserror::Error)]
 pub struct HttpError {
     pub error_type: StatusCode,
@@ -10,6 +12,8 @@ pub struct HttpError {
     // maybe include sensitive message
     pub verbose_message: String,
     pub contexts: Vec<String>,
+    /// Seconds the client should wait before retrying, sent as `Retry-After`.
+    pub retry_after: Option<u64>,
 }
 
 pub trait ResponseValidatable: Sized {
@@ -22,15 +26,17 @@ impl ResponseValidatable for reqwest::Response {
             Ok(self)
         } else {
             let status = self.status();
-            let message = self
+            let body = self
                 .text()
                 .await
                 .http_error(StatusCode::INTERNAL_SERVER_ERROR)?;
+            // upstream bodies can echo request details back, so they stay out of responses
             Err(HttpError {
                 error_type: status,
-                verbose_message: message.clone(),
-                message,
+                message: format!("upstream responded with {status}"),
+                verbose_message: redact(&body).into_owned(),
                 contexts: Default::default(),
+                retry_after: None,
             })
         }
     }
@@ -42,15 +48,32 @@ impl Display for HttpError {
     }
 }
 
+#[derive(Serialize)]
+struct CargoError<'a> {
+    detail: std::borrow::Cow<'a, str>,
+}
+
 impl Serialize for HttpError {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
         S: serde::Serializer,
     {
-        let mut serializer = serializer.serialize_struct("HttpError", 3)?;
+        let mut serializer = serializer.serialize_struct("HttpError", 4)?;
         serializer.serialize_field("type", &self.error_type.as_u16())?;
-        serializer.serialize_field("message", &self.message)?;
-        serializer.serialize_field("contexts", &self.contexts)?;
+        serializer.serialize_field("message", &redact(&self.message))?;
+        // what cargo shows the user
+        serializer.serialize_field(
+            "errors",
+            &[CargoError {
+                detail: redact(&self.message),
+            }],
+        )?;
+        let contexts = self
+            .contexts
+            .iter()
+            .map(|context| redact(context))
+            .collect::<Vec<_>>();
+        serializer.serialize_field("contexts", &contexts)?;
         serializer.end()
     }
 }
@@ -81,6 +104,7 @@ impl<T, E: std::error::Error> ToHttpError for Result<T, E> {
             message: e.to_string(),
             verbose_message: format!("{:?}", e),
             contexts: Default::default(),
+            retry_after: None,
         })
     }
 }
@@ -99,6 +123,7 @@ impl<T> ToHttpErrorOption for Option<T> {
                 message: message.clone(),
                 verbose_message: message,
                 contexts: Default::default(),
+                retry_after: None,
             }
         })
     }
@@ -106,6 +131,14 @@ impl<T> ToHttpErrorOption for Option<T> {
 
 impl IntoResponse for HttpError {
     fn into_response(self) -> axum::response::Response {
-        (self.error_type, Json(self)).into_response()
+        match self.retry_after {
+            Some(retry_after) => (
+                self.error_type,
+                [(axum::http::header::RETRY_AFTER, retry_after.to_string())],
+                Json(self),
+            )
+                .into_response(),
+            None => (self.error_type, Json(self)).into_response(),
+        }
     }
 }
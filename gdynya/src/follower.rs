@@ -0,0 +1,387 @@
+//! Read-only replicas in other regions.
+//!
+//! A follower polls the primary's `/api/v1/changes` feed and copies each published version,
+//! yank and deletion into its own store, so the index and downloads are served locally.
+//! Requests that would change something are passed on to the primary as they are, apart from
+//! flushing the cache and read-only mode, which only concern the instance they are sent to.
+//!
+//! Registry tokens and keys live in the primary's store and publish tokens in its memory, and
+//! none are copied. Requests made with them are passed on to the primary whatever they are
+//! for, reads included, since only the primary can tell whose they are. The instance's own
+//! cache and read-only mode need a GitHub token on a follower for the same reason.
+
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use axum::{
+    body::Body,
+    extract::{FromRequestParts, OriginalUri},
+    http::{Method, Request, StatusCode, header},
+    response::{IntoResponse, Response},
+};
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::{
+    HttpError, ToHttpError,
+    api_schema::{CrateName, GetIndexResponse},
+    auth::{now, oidc, paseto, token},
+    axum_aux::{ClientIp, MAX_PUBLISH_BODY, read_body},
+    changes::{Change, ChangeKind},
+    error::ResponseValidatable,
+    store::Store,
+};
+
+/// Changes asked for per request to the primary.
+const PAGE: usize = 1000;
+
+/// Longest a request of the follower's own to the primary may take.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Credentials only the primary can check.
+const PRIMARY_CREDENTIALS: &[&str] = &[
+    token::TOKEN_PREFIX,
+    paseto::TOKEN_PREFIX,
+    oidc::TOKEN_PREFIX,
+];
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct FollowerConfig {
+    /// Root URL of the primary, e.g. `https://crates.example.com`. The primary must list the
+    /// follower in its `trusted_proxies` to record forwarded requests as coming from the client
+    /// rather than from the follower.
+    pub primary: String,
+    /// Sent to the primary as `Authorization`; it must be able to read every crate.
+    pub token: Option<String>,
+    /// Seconds between polls of the change feed.
+    #[serde(default = "default_poll_interval")]
+    pub poll_interval: u64,
+    /// Seconds behind the primary after which the replication health check fails.
+    #[serde(default = "default_max_lag")]
+    pub max_lag: u64,
+}
+
+fn default_poll_interval() -> u64 {
+    10
+}
+
+fn default_max_lag() -> u64 {
+    300
+}
+
+/// Served by `/health/replication`.
+#[derive(Serialize, Clone, Debug, Default)]
+pub struct ReplicationStatus {
+    pub primary: String,
+    /// ID of the last change copied.
+    pub cursor: Option<String>,
+    /// When the last change copied was made on the primary.
+    pub last_change_at: Option<u64>,
+    /// When this replica last saw the end of the feed.
+    pub caught_up_at: Option<u64>,
+    /// Seconds since then; `None` until the first catch up.
+    pub lag: Option<u64>,
+    pub applied: u64,
+    /// Why the last poll failed, if it did.
+    pub error: Option<String>,
+    pub healthy: bool,
+}
+
+#[derive(Deserialize)]
+struct ChangesPage {
+    changes: Vec<Change>,
+    next: Option<String>,
+}
+
+#[derive(Clone)]
+pub struct Follower<S> {
+    store: S,
+    config: Arc<FollowerConfig>,
+    client: reqwest::Client,
+    status: Arc<Mutex<ReplicationStatus>>,
+}
+
+impl<S: Store + Clone + Send + Sync + 'static> Follower<S> {
+    pub fn new(store: S, config: FollowerConfig) -> Self {
+        let config = FollowerConfig {
+            primary: config.primary.trim_end_matches('/').to_string(),
+            ..config
+        };
+        Self {
+            store,
+            status: Arc::new(Mutex::new(ReplicationStatus {
+                primary: config.primary.clone(),
+                ..Default::default()
+            })),
+            config: Arc::new(config),
+            // forwarded responses are streamed, and `/events` never ends
+            client: reqwest::Client::builder()
+                .connect_timeout(Duration::from_secs(10))
+                .read_timeout(REQUEST_TIMEOUT)
+                .build()
+                .expect("static client settings"),
+        }
+    }
+
+    /// Poll the primary in the background for as long as the server runs.
+    pub fn spawn(&self) {
+        let follower = self.clone();
+        tokio::spawn(async move {
+            loop {
+                match follower.sync().await {
+                    Ok(0) => {}
+                    Ok(applied) => info!(applied, "replication_synced"),
+                    Err(e) => warn!(e = e.to_string(), "replication_failed"),
+                }
+                tokio::time::sleep(Duration::from_secs(follower.config.poll_interval)).await;
+            }
+        });
+    }
+
+    /// Copy every change the primary has after the cursor; the number copied.
+    pub async fn sync(&self) -> Result<usize, HttpError> {
+        let result = self.sync_pages().await;
+        let mut status = self.status.lock().unwrap();
+        match &result {
+            Ok(_) => {
+                status.caught_up_at = Some(now());
+                status.error = None;
+            }
+            Err(e) => status.error = Some(e.message.clone()),
+        }
+        result
+    }
+
+    pub fn status(&self) -> ReplicationStatus {
+        let status = self.status.lock().unwrap().clone();
+        let lag = status.caught_up_at.map(|at| now().saturating_sub(at));
+        ReplicationStatus {
+            lag,
+            healthy: lag.is_some_and(|lag| lag <= self.config.max_lag),
+            ..status
+        }
+    }
+
+    async fn sync_pages(&self) -> Result<usize, HttpError> {
+        let mut applied = 0;
+        loop {
+            let cursor = self.status.lock().unwrap().cursor.clone();
+            let mut request = self
+                .get("/api/v1/changes")
+                .query(&[("limit", PAGE.to_string())]);
+            if let Some(cursor) = &cursor {
+                request = request.query(&[("since", cursor)]);
+            }
+            let page = request
+                .send()
+                .await
+                .http_error(StatusCode::BAD_GATEWAY)?
+                .validate()
+                .await?
+                .json::<ChangesPage>()
+                .await
+                .http_error(StatusCode::BAD_GATEWAY)?;
+            for change in &page.changes {
+                self.apply(change).await?;
+                applied += 1;
+                let mut status = self.status.lock().unwrap();
+                status.cursor = Some(change.id.clone());
+                status.last_change_at = Some(change.at);
+                status.applied += 1;
+            }
+            // the primary may skip past changes this replica cannot read
+            if page.next != cursor {
+                self.status.lock().unwrap().cursor = page.next;
+            } else {
+                return Ok(applied);
+            }
+        }
+    }
+
+    async fn apply(&self, change: &Change) -> Result<(), HttpError> {
+        let name = change
+            .crate_name
+            .parse::<CrateName>()
+            .http_error(StatusCode::BAD_GATEWAY)?;
+        let vers = change.version.clone();
+        match change.kind {
+            ChangeKind::Delete => match self.store.delete_version(&name, vers).await {
+                Err(e) if e.error_type != StatusCode::NOT_FOUND => Err(e),
+                _ => Ok(()),
+            },
+            // yanks of versions published before this replica started copy the version too
+            ChangeKind::Publish | ChangeKind::Yank | ChangeKind::Unyank => {
+                // a failed read fails the sync, and the change is applied again on the next one
+                let local = match self.store.get_index(&name).await {
+                    Ok(local) => local,
+                    Err(e) if e.error_type == StatusCode::NOT_FOUND => Vec::new(),
+                    Err(e) => return Err(e),
+                };
+                if !local.iter().any(|entry| entry.vers == vers) {
+                    return self.copy(&name, &vers).await;
+                }
+                match change.kind {
+                    ChangeKind::Yank => self.store.set_yank(&name, vers, true).await,
+                    ChangeKind::Unyank => self.store.set_yank(&name, vers, false).await,
+                    _ => Ok(()),
+                }
+            }
+        }
+    }
+
+    /// Copy a version as the primary has it now, yanked or not.
+    async fn copy(&self, name: &CrateName, vers: &semver::Version) -> Result<(), HttpError> {
+        let index = self
+            .get(&format!("/{}", index_path(name)))
+            .send()
+            .await
+            .http_error(StatusCode::BAD_GATEWAY)?;
+        if index.status() == StatusCode::NOT_FOUND {
+            // deleted since; the deletion comes later in the feed
+            return Ok(());
+        }
+        let index = index
+            .validate()
+            .await?
+            .text()
+            .await
+            .http_error(StatusCode::BAD_GATEWAY)?;
+        let entry = serde_json::Deserializer::from_str(&index)
+            .into_iter::<GetIndexResponse>()
+            .collect::<Result<Vec<_>, _>>()
+            .http_error(StatusCode::BAD_GATEWAY)?
+            .into_iter()
+            .find(|entry| &entry.vers == vers);
+        let Some(entry) = entry else {
+            return Ok(());
+        };
+        let body = self
+            .get(&format!("/api/v1/crates/{}/{vers}", name.normalized))
+            .send()
+            .await
+            .http_error(StatusCode::BAD_GATEWAY)?
+            .validate()
+            .await?
+            .bytes()
+            .await
+            .http_error(StatusCode::BAD_GATEWAY)?;
+        if crate::api_schema::cksum(&body) != entry.cksum {
+            return Err(HttpError {
+                error_type: StatusCode::BAD_GATEWAY,
+                message: format!("{}/{vers} does not match its checksum", name.original),
+                verbose_message: String::new(),
+                contexts: Vec::new(),
+                retry_after: None,
+            });
+        }
+        self.store.put_replica(&entry, body.to_vec()).await
+    }
+
+    /// Pass a request on to the primary and its response back.
+    pub async fn forward(&self, request: Request<Body>) -> Response {
+        match self.try_forward(request).await {
+            Ok(response) => response,
+            Err(e) => e.into_response(),
+        }
+    }
+
+    async fn try_forward(&self, request: Request<Body>) -> Result<Response, HttpError> {
+        let (mut parts, body) = request.into_parts();
+        let Ok(ClientIp(client)) = ClientIp::from_request_parts(&mut parts, &()).await;
+        // routers nested under `/api/v1` see the path without it
+        let uri = parts
+            .extensions
+            .get::<OriginalUri>()
+            .map_or(&parts.uri, |original| &original.0);
+        let path = uri
+            .path_and_query()
+            .map(|path| path.as_str())
+            .unwrap_or("/");
+        // the primary would refuse anything longer, so there is no point holding it
+        let body = read_body(body, MAX_PUBLISH_BODY).await?;
+        let mut forwarded = self
+            .client
+            .request(
+                parts.method.clone(),
+                format!("{}{path}", self.config.primary),
+            )
+            .body(body);
+        for name in [
+            header::AUTHORIZATION,
+            header::CONTENT_TYPE,
+            header::USER_AGENT,
+        ] {
+            if let Some(value) = parts.headers.get(&name) {
+                forwarded = forwarded.header(name, value);
+            }
+        }
+        // the client as this instance resolved it; the primary believes it from trusted proxies
+        if let Some(client) = client {
+            forwarded = forwarded.header("x-forwarded-for", client);
+        }
+        let response = forwarded.send().await.http_error(StatusCode::BAD_GATEWAY)?;
+        let mut builder = Response::builder().status(response.status());
+        for name in [header::CONTENT_TYPE, header::RETRY_AFTER] {
+            if let Some(value) = response.headers().get(&name) {
+                builder = builder.header(name, value);
+            }
+        }
+        builder
+            .body(Body::from_stream(response.bytes_stream()))
+            .http_error(StatusCode::INTERNAL_SERVER_ERROR)
+    }
+
+    fn get(&self, path: &str) -> reqwest::RequestBuilder {
+        let request = self
+            .client
+            .get(format!("{}{path}", self.config.primary))
+            .timeout(REQUEST_TIMEOUT);
+        match &self.config.token {
+            Some(token) => request.header(header::AUTHORIZATION, token),
+            None => request,
+        }
+    }
+}
+
+/// Whether a follower passes a request under `/api/v1` on to the primary: anything that
+/// would change something, except the cache and read-only mode, which are this instance's own.
+pub fn is_forwarded(method: &Method, path: &str) -> bool {
+    !matches!(*method, Method::GET | Method::HEAD) && !is_instance_local(method, path)
+}
+
+/// Whether a follower passes a request on to the primary whatever it is for, because its
+/// `authorization` is one only the primary can check. `path` is the full path; `config.json`,
+/// health checks and what [`is_forwarded`] keeps are answered by the follower.
+pub fn is_checked_by_primary(method: &Method, path: &str, authorization: Option<&str>) -> bool {
+    let primary_only = authorization.is_some_and(|authorization| {
+        PRIMARY_CREDENTIALS
+            .iter()
+            .any(|prefix| authorization.trim().starts_with(prefix))
+    });
+    let local = match path.strip_prefix("/api/v1") {
+        Some(path) => is_instance_local(method, path),
+        None => path == "/config.json" || path.starts_with("/health/"),
+    };
+    primary_only && !local
+}
+
+fn is_instance_local(method: &Method, path: &str) -> bool {
+    matches!(
+        (method, path),
+        (&Method::DELETE, "/admin/cache") | (_, "/admin/maintenance")
+    )
+}
+
+/// Path of a crate's file in the sparse index, as cargo asks for it.
+pub fn index_path(name: &CrateName) -> String {
+    let name = &name.normalized;
+    match name.len() {
+        1 => format!("1/{name}"),
+        2 => format!("2/{name}"),
+        3 => format!("3/{}/{name}", &name[..1]),
+        _ => format!("{}/{}/{name}", &name[..2], &name[2..4]),
+    }
+}
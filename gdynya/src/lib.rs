@@ -1,6 +1,14 @@
+pub mod admin;
 pub mod api_schema;
+pub mod audit;
 pub mod auth;
 pub mod axum_aux;
+pub mod changes;
+pub mod config;
 pub mod error;
+pub mod follower;
+pub mod maintenance;
+pub mod redact;
 pub mod store;
+pub mod webhook;
 pub use error::{HttpError, ResponseValidatable, ToHttpError, ToHttpErrorOption};
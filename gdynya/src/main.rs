@@ -1,38 +1,80 @@
 use std::{
+    collections::BTreeMap,
     io::{self, Read},
     net::SocketAddr,
-    path::PathBuf,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
 };
 
 use axum::{
-    Json, Router, extract,
-    http::{Request, StatusCode},
+    Extension, Json, Router, extract,
+    http::{Request, StatusCode, header},
     middleware::Next,
-    response::IntoResponse,
+    response::{
+        IntoResponse,
+        sse::{self, Sse},
+    },
     routing,
 };
 use axum_extra::TypedHeader;
 use byteorder::{LE, ReadBytesExt};
-use clap::Parser;
+use clap::{Args, Parser, Subcommand};
 use futures_util::StreamExt;
 use gdynya::{
-    HttpError, ToHttpError,
+    HttpError, ToHttpError, ToHttpErrorOption,
+    admin::{BlockedName, FreezeRequest, FreezeWindow},
     api_schema::{self, CrateName, SearchCratesQuery},
-    auth::Auth,
+    audit::{self, Action, AuditFilter, AuditRecord},
+    auth::{
+        Auth, Authenticator, Mutation, Principal,
+        github::{Access, GitHubApi, GitHubAuth},
+        oidc::TrustedPublishing,
+        paseto::{PasetoAuth, PublicKeys, RegisterKeyRequest},
+        rules::{AuthRules, RulesError},
+        service::ServiceAccounts,
+        token::{CreateTokenRequest, RegistryTokenAuth},
+    },
     axum_aux::{
-        CustomTypedHeader, OptionalHeader, RawAuthorization, XForwardedHost, XForwardedProto,
+        ClientIp, CustomTypedHeader, MAX_PUBLISH_BODY, OptionalHeader, RawAuthorization,
+        TrustedProxies, XForwardedHost, XForwardedProto, read_body,
     },
-    store::Store,
+    changes::{self, Change},
+    config::ServerConfig,
+    follower::{Follower, is_checked_by_primary, is_forwarded},
+    maintenance::{Maintenance, MaintenanceConfig, is_blocked},
+    redact::{self, RedactingMakeWriter},
+    store::{AuditOrder, Store},
+    webhook::{Event, Webhooks},
 };
 use serde::Deserialize;
 use serde_json::json;
+use time::{OffsetDateTime, format_description::well_known::Rfc3339};
 use tokio::{fs, net::TcpListener};
 use tracing::{error, info};
 use tracing_subscriber::{prelude::__tracing_subscriber_SubscriberExt, util::SubscriberInitExt};
 use valuable::Valuable;
 
+/// How often `/events` looks for new changes once a subscriber has caught up.
+const EVENTS_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
 #[derive(Parser)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 struct Opts {
+    #[command(subcommand)]
+    command: Option<Command>,
+    #[command(flatten)]
+    serve: Option<ServeOpts>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Validate the rules file and server options without starting the server
+    CheckConfig(CheckConfigOpts),
+}
+
+#[derive(Args)]
+struct ServeOpts {
     #[clap(long, env)]
     addr: SocketAddr,
     #[clap(long, env)]
@@ -41,16 +83,98 @@ struct Opts {
     objstore: String,
     #[clap(long, env)]
     rules: PathBuf,
+    /// YAML file with further server settings, e.g. trusted publishing issuers
+    #[clap(long, env)]
+    config: Option<PathBuf>,
+    /// Answer with the last known decision, up to this many seconds old, while GitHub is
+    /// unavailable instead of failing with 503
+    #[clap(long, env)]
+    github_stale_if_error: Option<u64>,
+    /// GitHub token used for lookups on behalf of registry tokens and keys, which GitHub does
+    /// not know; without it, their requests fail with 500
+    #[clap(long, env, hide_env_values = true)]
+    github_service_token: Option<String>,
+    /// Let anyone read every crate without credentials; writes still need a token. Without
+    /// it, cargo is only told it can do without a token when the rules let anyone read all
+    /// crates
+    #[clap(long, env)]
+    public_read: bool,
+    /// YAML file mapping service account names to the SHA-256 of their `gds_` tokens
+    #[clap(long, env)]
+    service_accounts: Option<PathBuf>,
+    /// Say in 403 responses why access was denied, without revealing the rules
+    #[clap(long, env)]
+    auth_debug: bool,
+    #[command(flatten)]
+    github: GitHubOpts,
+}
+
+#[derive(Args)]
+struct GitHubOpts {
+    /// GitHub REST API root, e.g. https://github.example.com/api/v3 for GitHub Enterprise Server
+    #[clap(long, env, default_value = gdynya::auth::github::DEFAULT_API_URL)]
+    github_api_url: String,
+    #[clap(long, env, default_value = gdynya::auth::github::DEFAULT_USER_AGENT)]
+    github_user_agent: String,
+    /// PEM file with extra CA certificates to trust when talking to GitHub
+    #[clap(long, env)]
+    github_ca_bundle: Option<PathBuf>,
+}
+
+impl GitHubOpts {
+    async fn api(&self) -> anyhow::Result<GitHubApi> {
+        let api = GitHubApi::new(&self.github_api_url).with_user_agent(&self.github_user_agent);
+        Ok(match &self.github_ca_bundle {
+            Some(path) => api.with_ca_bundle(&fs::read(path).await?)?,
+            None => api,
+        })
+    }
+}
+
+#[derive(Args)]
+struct CheckConfigOpts {
+    #[clap(long, env)]
+    rules: Option<PathBuf>,
+    #[clap(long, env)]
+    config: Option<PathBuf>,
+    #[clap(long, env)]
+    service_accounts: Option<PathBuf>,
+    #[clap(long, env)]
+    addr: Option<String>,
+    #[clap(long, env)]
+    objstore_endpoint: Option<String>,
+    /// Print the JSON Schema of the rules file and exit
+    #[clap(long)]
+    export_schema: bool,
+    /// Evaluate the rules for this GitHub login (requires --crate-name)
+    #[clap(long, requires = "crate_name")]
+    user: Option<String>,
+    #[clap(long, requires = "user")]
+    crate_name: Option<String>,
+    /// Token used to query GitHub while evaluating --user
+    #[clap(long, env = "GITHUB_TOKEN", hide_env_values = true)]
+    github_token: Option<String>,
+    #[command(flatten)]
+    github: GitHubOpts,
 }
 
 #[derive(Clone)]
 struct State<S, A> {
     store: S,
     auth: A,
+    github: GitHubAuth,
+    admins: Arc<Vec<String>>,
+    tokens: RegistryTokenAuth<S>,
+    publishing: TrustedPublishing,
+    keys: PublicKeys<S>,
+    webhooks: Webhooks,
+    follower: Option<Follower<S>>,
+    maintenance: Maintenance,
 }
 
 // configは認証の必要なし
-async fn config(
+async fn config<S: Store, A: Auth>(
+    extract::State(state): extract::State<State<S, A>>,
     TypedHeader(host): TypedHeader<headers::Host>,
     CustomTypedHeader(OptionalHeader(x_forwarded_host)): CustomTypedHeader<
         OptionalHeader<XForwardedHost>,
@@ -66,7 +190,9 @@ async fn config(
         .map(|proto| proto.0)
         .unwrap_or_else(|| "http".to_string());
     let proto: api_schema::HttpProtocol = proto.parse().http_error(StatusCode::BAD_GATEWAY)?;
-    Ok(Json(api_schema::Config::new(proto, &host)))
+    Ok(Json(
+        api_schema::Config::new(proto, &host).with_auth_required(state.github.reads_need_auth()),
+    ))
 }
 
 #[cfg(not(unix))]
@@ -77,60 +203,56 @@ async fn wait_shutdown() {
 async fn get_index<S: Store, A: Auth>(
     state: &State<S, A>,
     name: &CrateName,
-    token: &RawAuthorization,
-) -> Result<String, HttpError> {
-    state.auth.readable(token, name).await?;
+    token: Option<&RawAuthorization>,
+) -> Result<(Extension<Principal>, String), HttpError> {
+    let principal = state.auth.readable(token, name).await?;
     let index = state.store.get_index(name).await?;
     let mut response = String::new();
     for index in index {
         response.push_str(&serde_json::to_string(&index).unwrap());
     }
-    Ok(response)
+    Ok((Extension(principal), response))
 }
 
 async fn get_index_len_1<S: Store, A: Auth>(
-    TypedHeader(token): TypedHeader<RawAuthorization>,
+    token: Option<TypedHeader<RawAuthorization>>,
     extract::State(state): extract::State<State<S, A>>,
     extract::Path(name): extract::Path<CrateName>,
 ) -> impl IntoResponse {
-    get_index(&state, &name, &token).await
+    get_index(&state, &name, token.as_deref()).await
 }
 
 async fn get_index_len_2<S: Store, A: Auth>(
-    TypedHeader(token): TypedHeader<RawAuthorization>,
+    token: Option<TypedHeader<RawAuthorization>>,
     extract::State(state): extract::State<State<S, A>>,
     extract::Path(name): extract::Path<CrateName>,
 ) -> impl IntoResponse {
-    get_index(&state, &name, &token).await
+    get_index(&state, &name, token.as_deref()).await
 }
 
 async fn get_index_len_3<S: Store, A: Auth>(
-    TypedHeader(token): TypedHeader<RawAuthorization>,
+    token: Option<TypedHeader<RawAuthorization>>,
     extract::State(state): extract::State<State<S, A>>,
     extract::Path((_, name)): extract::Path<(char, CrateName)>,
 ) -> impl IntoResponse {
-    get_index(&state, &name, &token).await
+    get_index(&state, &name, token.as_deref()).await
 }
 
 async fn get_index_len_at_least_4<S: Store, A: Auth>(
-    TypedHeader(token): TypedHeader<RawAuthorization>,
+    token: Option<TypedHeader<RawAuthorization>>,
     extract::State(state): extract::State<State<S, A>>,
     extract::Path((_, _, name)): extract::Path<(String, String, CrateName)>,
 ) -> impl IntoResponse {
-    get_index(&state, &name, &token).await
+    get_index(&state, &name, token.as_deref()).await
 }
 
 async fn publish_crate<S: Store, A: Auth>(
     TypedHeader(token): TypedHeader<RawAuthorization>,
+    ClientIp(ip): ClientIp,
     extract::State(state): extract::State<State<S, A>>,
     body: axum::body::Body,
-) -> Result<StatusCode, HttpError> {
-    let mut full = Vec::new();
-    let mut stream = body.into_data_stream();
-    while let Some(body) = stream.next().await {
-        let body = body.http_error(StatusCode::BAD_REQUEST)?;
-        full.append(&mut body.to_vec());
-    }
+) -> Result<impl IntoResponse, HttpError> {
+    let full = read_body(body, MAX_PUBLISH_BODY).await?;
     let mut full = io::Cursor::new(full);
     let index_len = full.read_u32::<LE>().http_error(StatusCode::BAD_REQUEST)?;
     let mut index = vec![0u8; index_len as usize];
@@ -144,58 +266,141 @@ async fn publish_crate<S: Store, A: Auth>(
     let index: api_schema::PostIndexRequest =
         serde_json::from_slice(&index).http_error(StatusCode::BAD_REQUEST)?;
 
-    state.auth.writable(&token, &index.name).await?;
-    state.store.put(&index, crate_archive).await?;
+    let record = AuditRecord::new(Action::Publish)
+        .from_ip(ip)
+        .with_crate(&index.name)
+        .with_version(&index.vers);
+    let cksum = api_schema::cksum(&crate_archive);
+    let new = match state.store.get_index(&index.name).await {
+        Ok(versions) => versions.is_empty(),
+        Err(e) if e.error_type == StatusCode::NOT_FOUND => true,
+        Err(e) => return Err(e),
+    };
+    let mutation = Mutation::Publish {
+        vers: &index.vers,
+        cksum: &cksum,
+        new,
+    };
+    let (principal, ()) = audited_write(&state, &token, &index.name, &mutation, record, async {
+        let principal = state.auth.writable(&token, &index.name, &mutation).await?;
+        if let Some(blocked) = state.store.get_blocked(&index.name).await? {
+            return Err(HttpError {
+                error_type: StatusCode::FORBIDDEN,
+                message: format!(
+                    "the crate name `{}` is blocked: {}",
+                    index.name.original, blocked.reason
+                ),
+                verbose_message: format!("{} is blocked", index.name.original),
+                contexts: Vec::new(),
+                retry_after: None,
+            });
+        }
+        let freezes = state.store.list_freezes().await?;
+        if let Some(freeze) = FreezeWindow::stopping(&freezes, &index.name, &principal) {
+            let until = OffsetDateTime::from_unix_timestamp(freeze.ends_at as i64)
+                .ok()
+                .and_then(|until| until.format(&Rfc3339).ok())
+                .unwrap_or_else(|| freeze.ends_at.to_string());
+            return Err(HttpError {
+                error_type: StatusCode::FORBIDDEN,
+                message: format!(
+                    "publishing `{}` is frozen until {until} by the release freeze `{}`: {}",
+                    index.name.original, freeze.id, freeze.message
+                ),
+                verbose_message: format!("{} is frozen by {}", index.name.original, freeze.id),
+                contexts: Vec::new(),
+                retry_after: None,
+            });
+        }
+        state.store.put(&index, crate_archive).await?;
+        Ok((principal, ()))
+    })
+    .await?;
 
     info!(
         name = index.name.as_value(),
         version = index.vers.to_string(),
+        login = principal.login,
+        provider = principal.provider.as_str(),
         "publish"
     );
 
-    Ok(StatusCode::OK)
+    Ok((StatusCode::OK, Extension(principal)))
 }
 
 async fn yank_crate<S: Store, A: Auth>(
     TypedHeader(token): TypedHeader<RawAuthorization>,
+    ClientIp(ip): ClientIp,
     extract::State(state): extract::State<State<S, A>>,
     extract::Path((name, ver)): extract::Path<(CrateName, semver::Version)>,
 ) -> Result<impl IntoResponse, HttpError> {
-    state.auth.writable(&token, &name).await?;
-    state.store.set_yank(&name, ver, true).await?;
-    Ok((StatusCode::OK, Json(json!({ "ok": true }))))
+    let record = AuditRecord::new(Action::Yank)
+        .from_ip(ip)
+        .with_crate(&name)
+        .with_version(&ver);
+    let mutation = Mutation::Yank { vers: &ver };
+    let (principal, ()) = audited_write(&state, &token, &name, &mutation, record, async {
+        let principal = state.auth.writable(&token, &name, &mutation).await?;
+        state.store.set_yank(&name, ver.clone(), true).await?;
+        Ok((principal, ()))
+    })
+    .await?;
+    Ok((
+        StatusCode::OK,
+        Extension(principal),
+        Json(json!({ "ok": true })),
+    ))
 }
 
 async fn unyank_crate<S: Store, A: Auth>(
     TypedHeader(token): TypedHeader<RawAuthorization>,
+    ClientIp(ip): ClientIp,
     extract::State(state): extract::State<State<S, A>>,
     extract::Path((name, ver)): extract::Path<(CrateName, semver::Version)>,
 ) -> Result<impl IntoResponse, HttpError> {
-    state.auth.writable(&token, &name).await?;
-    state.store.set_yank(&name, ver, false).await?;
-    Ok((StatusCode::OK, Json(json!({ "ok": true }))))
+    let record = AuditRecord::new(Action::Unyank)
+        .from_ip(ip)
+        .with_crate(&name)
+        .with_version(&ver);
+    let mutation = Mutation::Unyank { vers: &ver };
+    let (principal, ()) = audited_write(&state, &token, &name, &mutation, record, async {
+        let principal = state.auth.writable(&token, &name, &mutation).await?;
+        state.store.set_yank(&name, ver.clone(), false).await?;
+        Ok((principal, ()))
+    })
+    .await?;
+    Ok((
+        StatusCode::OK,
+        Extension(principal),
+        Json(json!({ "ok": true })),
+    ))
 }
 
 async fn get_owners<S: Store, A: Auth + Clone>(
-    TypedHeader(token): TypedHeader<RawAuthorization>,
+    token: Option<TypedHeader<RawAuthorization>>,
     extract::State(state): extract::State<State<S, A>>,
     extract::Path(name): extract::Path<CrateName>,
 ) -> Result<impl IntoResponse, HttpError> {
-    state.auth.readable(&token, &name).await?;
+    let token = token.map(|TypedHeader(token)| token);
+    let principal = state.auth.readable(token.as_ref(), &name).await?;
     let owners = state.store.get_owners(&name).await?;
     let owners = owners
         .into_iter()
         .map(|owner| {
             let auth = state.auth.clone();
             let token = token.clone();
-            async move { auth.as_registry_user(&token, &owner).await }
+            async move { auth.as_registry_user(token.as_ref(), &owner).await }
         })
         .collect::<Vec<_>>();
     let owners = futures_util::future::join_all(owners)
         .await
         .into_iter()
         .collect::<Result<Vec<_>, _>>()?;
-    Ok((StatusCode::OK, Json(json!({ "users": owners }))))
+    Ok((
+        StatusCode::OK,
+        Extension(principal),
+        Json(json!({ "users": owners })),
+    ))
 }
 
 #[derive(Deserialize)]
@@ -218,15 +423,26 @@ fn natural_human_names(names: &[String]) -> String {
 
 async fn add_owner<S: Store, A: Auth>(
     TypedHeader(token): TypedHeader<RawAuthorization>,
+    ClientIp(ip): ClientIp,
     extract::State(state): extract::State<State<S, A>>,
     extract::Path(name): extract::Path<CrateName>,
     Json(req): Json<AddOwnerRequest>,
 ) -> Result<impl IntoResponse, HttpError> {
     let names = natural_human_names(&req.users);
-    state.auth.writable(&token, &name).await?;
-    state.store.add_owner(&name, req.users).await?;
+    let record = AuditRecord::new(Action::AddOwners)
+        .from_ip(ip)
+        .with_crate(&name)
+        .with_detail(req.users.join(", "));
+    let mutation = Mutation::Owners;
+    let (principal, ()) = audited_write(&state, &token, &name, &mutation, record, async {
+        let principal = state.auth.writable(&token, &name, &mutation).await?;
+        state.store.add_owner(&name, req.users).await?;
+        Ok((principal, ()))
+    })
+    .await?;
     Ok((
         StatusCode::OK,
+        Extension(principal),
         Json(
             json!({"ok": true, "msg": format!("user {names} has been added to {}", name.original)}),
         ),
@@ -235,15 +451,26 @@ async fn add_owner<S: Store, A: Auth>(
 
 async fn delete_owner<S: Store, A: Auth>(
     TypedHeader(token): TypedHeader<RawAuthorization>,
+    ClientIp(ip): ClientIp,
     extract::State(state): extract::State<State<S, A>>,
     extract::Path(name): extract::Path<CrateName>,
     Json(req): Json<AddOwnerRequest>,
 ) -> Result<impl IntoResponse, HttpError> {
     let names = natural_human_names(&req.users);
-    state.auth.writable(&token, &name).await?;
-    state.store.delete_owner(&name, req.users).await?;
+    let record = AuditRecord::new(Action::RemoveOwners)
+        .from_ip(ip)
+        .with_crate(&name)
+        .with_detail(req.users.join(", "));
+    let mutation = Mutation::Owners;
+    let (principal, ()) = audited_write(&state, &token, &name, &mutation, record, async {
+        let principal = state.auth.writable(&token, &name, &mutation).await?;
+        state.store.delete_owner(&name, req.users).await?;
+        Ok((principal, ()))
+    })
+    .await?;
     Ok((
         StatusCode::OK,
+        Extension(principal),
         Json(
             json!({"ok": true, "msg": format!("user {names} has been added to {}", name.original)}),
         ),
@@ -251,16 +478,17 @@ async fn delete_owner<S: Store, A: Auth>(
 }
 
 async fn get_crate<S: Store, A: Auth>(
-    TypedHeader(token): TypedHeader<RawAuthorization>,
+    token: Option<TypedHeader<RawAuthorization>>,
     extract::State(state): extract::State<State<S, A>>,
     extract::Path((name, ver)): extract::Path<(CrateName, semver::Version)>,
-) -> impl IntoResponse {
-    state.auth.readable(&token, &name).await?;
-    state.store.get_crate(&name, ver).await
+) -> Result<impl IntoResponse, HttpError> {
+    let principal = state.auth.downloadable(token.as_deref(), &name).await?;
+    let body = state.store.get_crate(&name, ver).await?;
+    Ok((Extension(principal), body))
 }
 
 async fn search_crates<S: Store, A: Auth + Clone>(
-    TypedHeader(token): TypedHeader<RawAuthorization>,
+    token: Option<TypedHeader<RawAuthorization>>,
     extract::State(state): extract::State<State<S, A>>,
     extract::Query(query): extract::Query<SearchCratesQuery>,
 ) -> Result<impl IntoResponse, HttpError> {
@@ -275,7 +503,7 @@ async fn search_crates<S: Store, A: Auth + Clone>(
                 let Ok(name) = name else {
                     return false;
                 };
-                auth.readable(&token, &name).await.is_ok()
+                auth.readable(token.as_deref(), &name).await.is_ok()
             }
         })
         .collect::<Vec<_>>()
@@ -286,19 +514,918 @@ async fn search_crates<S: Store, A: Auth + Clone>(
     ))
 }
 
+#[derive(Deserialize)]
+struct ChangesQuery {
+    /// ID of the last change already seen.
+    since: Option<String>,
+    limit: Option<usize>,
+}
+
+async fn can_read<A: Auth>(auth: &A, token: Option<&RawAuthorization>, change: &Change) -> bool {
+    let Ok(name) = change.crate_name.parse::<CrateName>() else {
+        return false;
+    };
+    auth.readable(token, &name).await.is_ok()
+}
+
+/// Changes after `since` that the caller may read, oldest first. `next` is the `since` to
+/// ask with next time, past whatever was left out.
+async fn list_changes<S: Store, A: Auth>(
+    token: Option<TypedHeader<RawAuthorization>>,
+    extract::State(state): extract::State<State<S, A>>,
+    extract::Query(query): extract::Query<ChangesQuery>,
+) -> Result<impl IntoResponse, HttpError> {
+    let limit = query.limit.unwrap_or(100).min(1000);
+    let mut readable = BTreeMap::new();
+    let mut changes = Vec::new();
+    let mut next = query.since.clone();
+    // those the caller cannot read are skipped, so a page may come back short
+    for change in changes::changes_after(&state.store, query.since.as_deref(), limit).await? {
+        next = Some(change.id.clone());
+        let allowed = match readable.get(&change.crate_name) {
+            Some(allowed) => *allowed,
+            None => {
+                let allowed = can_read(&state.auth, token.as_deref(), &change).await;
+                readable.insert(change.crate_name.clone(), allowed);
+                allowed
+            }
+        };
+        if allowed {
+            changes.push(change);
+        }
+    }
+    Ok((
+        StatusCode::OK,
+        Json(json!({ "changes": changes, "next": next })),
+    ))
+}
+
+/// Server-sent events, one per change the caller may read, as it settles in the store, so
+/// changes made through any instance show up. Reconnecting with `Last-Event-ID` (or
+/// `?since=`) first replays what was missed, a page at a time.
+async fn events<S: Store + Clone + Send + Sync + 'static, A: Auth + Clone + Send + 'static>(
+    token: Option<TypedHeader<RawAuthorization>>,
+    headers: header::HeaderMap,
+    extract::State(state): extract::State<State<S, A>>,
+    extract::Query(query): extract::Query<ChangesQuery>,
+) -> Result<impl IntoResponse, HttpError> {
+    let since = query.since.or_else(|| {
+        headers
+            .get("last-event-id")
+            .and_then(|id| id.to_str().ok())
+            .map(ToOwned::to_owned)
+    });
+    let changes = changes::follow(state.store.clone(), since, EVENTS_POLL_INTERVAL)
+        .filter(move |change| {
+            let auth = state.auth.clone();
+            let token = token.clone();
+            let change = change.clone();
+            async move { can_read(&auth, token.as_deref(), &change).await }
+        })
+        .map(|change| {
+            sse::Event::default()
+                .id(&change.id)
+                .event(change.kind.as_str())
+                .json_data(&change)
+        });
+    Ok(Sse::new(changes).keep_alive(sse::KeepAlive::default()))
+}
+
+async fn create_token<S: Store, A: Auth>(
+    TypedHeader(token): TypedHeader<RawAuthorization>,
+    ClientIp(ip): ClientIp,
+    extract::State(state): extract::State<State<S, A>>,
+    Json(req): Json<CreateTokenRequest>,
+) -> Result<impl IntoResponse, HttpError> {
+    let record = AuditRecord::new(Action::CreateToken)
+        .from_ip(ip)
+        .with_detail(req.name.clone());
+    let (principal, created) = audited(&state, &token, record, async {
+        let principal = state.auth.authenticate(Some(&token)).await?;
+        Ok((principal, state.tokens.create(&token, req).await?))
+    })
+    .await?;
+    info!(id = created.view.id, "token_created");
+    Ok((
+        StatusCode::OK,
+        Extension(principal),
+        Json(json!({ "api_token": created })),
+    ))
+}
+
+async fn list_tokens<S: Store, A: Auth>(
+    TypedHeader(token): TypedHeader<RawAuthorization>,
+    extract::State(state): extract::State<State<S, A>>,
+) -> Result<impl IntoResponse, HttpError> {
+    let tokens = state.tokens.list(&token).await?;
+    Ok((StatusCode::OK, Json(json!({ "api_tokens": tokens }))))
+}
+
+async fn revoke_token<S: Store, A: Auth>(
+    TypedHeader(token): TypedHeader<RawAuthorization>,
+    ClientIp(ip): ClientIp,
+    extract::State(state): extract::State<State<S, A>>,
+    extract::Path(id): extract::Path<String>,
+) -> Result<impl IntoResponse, HttpError> {
+    let record = AuditRecord::new(Action::RevokeToken)
+        .from_ip(ip)
+        .with_detail(id.clone());
+    let (principal, ()) = audited(&state, &token, record, async {
+        let principal = state.auth.authenticate(Some(&token)).await?;
+        state.tokens.revoke(&token, &id).await?;
+        Ok((principal, ()))
+    })
+    .await?;
+    info!(id, "token_revoked");
+    Ok((
+        StatusCode::OK,
+        Extension(principal),
+        Json(json!({ "ok": true })),
+    ))
+}
+
+async fn register_key<S: Store, A: Auth>(
+    TypedHeader(token): TypedHeader<RawAuthorization>,
+    ClientIp(ip): ClientIp,
+    extract::State(state): extract::State<State<S, A>>,
+    Json(req): Json<RegisterKeyRequest>,
+) -> Result<impl IntoResponse, HttpError> {
+    let record = AuditRecord::new(Action::RegisterKey).from_ip(ip);
+    let (principal, key) = audited(&state, &token, record, async {
+        let principal = state.auth.authenticate(Some(&token)).await?;
+        Ok((principal, state.keys.register(&token, req).await?))
+    })
+    .await?;
+    info!(kid = key.kid, "key_registered");
+    Ok((
+        StatusCode::OK,
+        Extension(principal),
+        Json(json!({ "key": key })),
+    ))
+}
+
+async fn list_keys<S: Store, A: Auth>(
+    TypedHeader(token): TypedHeader<RawAuthorization>,
+    extract::State(state): extract::State<State<S, A>>,
+) -> Result<impl IntoResponse, HttpError> {
+    let keys = state.keys.list(&token).await?;
+    Ok((StatusCode::OK, Json(json!({ "keys": keys }))))
+}
+
+async fn revoke_key<S: Store, A: Auth>(
+    TypedHeader(token): TypedHeader<RawAuthorization>,
+    ClientIp(ip): ClientIp,
+    extract::State(state): extract::State<State<S, A>>,
+    extract::Path(kid): extract::Path<String>,
+) -> Result<impl IntoResponse, HttpError> {
+    let record = AuditRecord::new(Action::RevokeKey)
+        .from_ip(ip)
+        .with_detail(kid.clone());
+    let (principal, ()) = audited(&state, &token, record, async {
+        let principal = state.auth.authenticate(Some(&token)).await?;
+        state.keys.revoke(&token, &kid).await?;
+        Ok((principal, ()))
+    })
+    .await?;
+    info!(kid, "key_revoked");
+    Ok((
+        StatusCode::OK,
+        Extension(principal),
+        Json(json!({ "ok": true })),
+    ))
+}
+
+#[derive(Deserialize)]
+struct MeQuery {
+    /// Comma-separated crate names; the crates named in the rules when missing.
+    crates: Option<String>,
+}
+
+async fn me<S: Store, A: Auth>(
+    token: Option<TypedHeader<RawAuthorization>>,
+    extract::State(state): extract::State<State<S, A>>,
+    extract::Query(query): extract::Query<MeQuery>,
+) -> Result<impl IntoResponse, HttpError> {
+    let principal = state.auth.authenticate(token.as_deref()).await?;
+    let names = match &query.crates {
+        Some(crates) => crates
+            .split(',')
+            .filter(|name| !name.is_empty())
+            .map(str::parse)
+            .collect::<Result<Vec<CrateName>, _>>()
+            .http_error(StatusCode::BAD_REQUEST)?,
+        None => state
+            .github
+            .rules()
+            .crates
+            .keys()
+            .filter_map(|name| name.parse().ok())
+            .collect(),
+    };
+    let mut crates = BTreeMap::new();
+    for name in names {
+        let permissions = state.auth.permissions(token.as_deref(), &name).await?;
+        // crates picked from the rules are only listed to those who can use them
+        if query.crates.is_some() || permissions.any() {
+            crates.insert(name.original, permissions);
+        }
+    }
+    Ok((
+        StatusCode::OK,
+        Extension(principal.clone()),
+        Json(json!({ "principal": principal, "crates": crates })),
+    ))
+}
+
+async fn require_admin<S, A: Auth>(
+    state: &State<S, A>,
+    token: &RawAuthorization,
+) -> Result<Principal, HttpError> {
+    let principal = state.auth.authenticate(Some(token)).await?;
+    Some(principal)
+        .filter(|principal| principal.is_admin(&state.admins))
+        .http_error_with(StatusCode::FORBIDDEN, || "admin only")
+}
+
+#[derive(Deserialize)]
+struct ExplainQuery {
+    login: String,
+    #[serde(rename = "crate")]
+    crate_name: CrateName,
+    access: Access,
+}
+
+async fn explain<S: Store, A: Auth>(
+    TypedHeader(token): TypedHeader<RawAuthorization>,
+    extract::State(state): extract::State<State<S, A>>,
+    extract::Query(query): extract::Query<ExplainQuery>,
+) -> Result<impl IntoResponse, HttpError> {
+    let principal = require_admin(&state, &token).await?;
+    let explanation = state
+        .github
+        .explain(&query.login, &query.crate_name, query.access)
+        .await?;
+    Ok((StatusCode::OK, Extension(principal), Json(explanation)))
+}
+
+async fn cache_stats<S: Store, A: Auth>(
+    TypedHeader(token): TypedHeader<RawAuthorization>,
+    extract::State(state): extract::State<State<S, A>>,
+) -> Result<impl IntoResponse, HttpError> {
+    let principal = require_admin(&state, &token).await?;
+    Ok((
+        StatusCode::OK,
+        Extension(principal),
+        Json(state.github.cache_stats()),
+    ))
+}
+
+#[derive(Deserialize)]
+struct FlushQuery {
+    login: Option<String>,
+    #[serde(rename = "crate")]
+    crate_name: Option<CrateName>,
+}
+
+/// Forget cached decisions about one user, one crate or one user's access to one crate, e.g.
+/// right after a team change on GitHub.
+async fn flush_cache<S: Store, A: Auth>(
+    TypedHeader(token): TypedHeader<RawAuthorization>,
+    ClientIp(ip): ClientIp,
+    extract::State(state): extract::State<State<S, A>>,
+    extract::Query(query): extract::Query<FlushQuery>,
+) -> Result<impl IntoResponse, HttpError> {
+    let mut record = AuditRecord::new(Action::FlushAuthCache)
+        .by_admin()
+        .from_ip(ip);
+    if let Some(name) = &query.crate_name {
+        record = record.with_crate(name);
+    }
+    if let Some(login) = &query.login {
+        record = record.with_detail(format!("login {login}"));
+    }
+    let (principal, ()) = audited(&state, &token, record, async {
+        let principal = require_admin(&state, &token).await?;
+        state
+            .github
+            .flush(query.login.as_deref(), query.crate_name.as_ref());
+        Ok((principal, ()))
+    })
+    .await?;
+    Ok((
+        StatusCode::OK,
+        Extension(principal),
+        Json(json!({ "ok": true })),
+    ))
+}
+
+async fn get_maintenance<S: Store, A: Auth>(
+    TypedHeader(token): TypedHeader<RawAuthorization>,
+    extract::State(state): extract::State<State<S, A>>,
+) -> Result<impl IntoResponse, HttpError> {
+    let principal = require_admin(&state, &token).await?;
+    Ok((
+        StatusCode::OK,
+        Extension(principal),
+        Json(json!({ "maintenance": state.maintenance.current() })),
+    ))
+}
+
+/// Make the registry read-only, e.g. for a storage migration.
+async fn enable_maintenance<S: Store, A: Auth>(
+    TypedHeader(token): TypedHeader<RawAuthorization>,
+    ClientIp(ip): ClientIp,
+    extract::State(state): extract::State<State<S, A>>,
+    Json(config): Json<MaintenanceConfig>,
+) -> Result<impl IntoResponse, HttpError> {
+    let record = AuditRecord::new(Action::EnableMaintenance)
+        .by_admin()
+        .from_ip(ip)
+        .with_detail(config.message.clone());
+    let (principal, ()) = audited(&state, &token, record, async {
+        let principal = require_admin(&state, &token).await?;
+        state.maintenance.enable(config, principal.login.clone());
+        Ok((principal, ()))
+    })
+    .await?;
+    Ok((
+        StatusCode::OK,
+        Extension(principal),
+        Json(json!({ "maintenance": state.maintenance.current() })),
+    ))
+}
+
+async fn disable_maintenance<S: Store, A: Auth>(
+    TypedHeader(token): TypedHeader<RawAuthorization>,
+    ClientIp(ip): ClientIp,
+    extract::State(state): extract::State<State<S, A>>,
+) -> Result<impl IntoResponse, HttpError> {
+    let record = AuditRecord::new(Action::DisableMaintenance)
+        .by_admin()
+        .from_ip(ip);
+    let (principal, ()) = audited(&state, &token, record, async {
+        let principal = require_admin(&state, &token).await?;
+        state.maintenance.disable();
+        Ok((principal, ()))
+    })
+    .await?;
+    Ok((
+        StatusCode::OK,
+        Extension(principal),
+        Json(json!({ "ok": true })),
+    ))
+}
+
+/// Carry out a mutation and keep an audit record of it, however it ends. Failing to keep the
+/// record is logged but does not fail a mutation that has already happened.
+async fn audited<S: Store, A: Auth, T>(
+    state: &State<S, A>,
+    token: &RawAuthorization,
+    record: AuditRecord,
+    mutation: impl Future<Output = Result<(Principal, T), HttpError>>,
+) -> Result<(Principal, T), HttpError> {
+    let result = mutation.await;
+    let principal = match &result {
+        Ok((principal, _)) => principal.clone(),
+        // whoever the credential names, even if it was not allowed to do this
+        Err(_) => state
+            .auth
+            .authenticate(Some(token))
+            .await
+            .unwrap_or_else(|_| Principal::anonymous()),
+    };
+    keep_audit(state, record.by(&principal).with_result(&result)).await;
+    result
+}
+
+/// Like [`audited`] for a write to a crate, where who a token belongs to may depend on the
+/// write it was made for.
+async fn audited_write<S: Store, A: Auth, T>(
+    state: &State<S, A>,
+    token: &RawAuthorization,
+    name: &CrateName,
+    write: &Mutation<'_>,
+    record: AuditRecord,
+    mutation: impl Future<Output = Result<(Principal, T), HttpError>>,
+) -> Result<(Principal, T), HttpError> {
+    let result = mutation.await;
+    let principal = match &result {
+        Ok((principal, _)) => principal.clone(),
+        Err(_) => state
+            .auth
+            .identify(token, name, write)
+            .await
+            .unwrap_or_else(|_| Principal::anonymous()),
+    };
+    keep_audit(state, record.by(&principal).with_result(&result)).await;
+    result
+}
+
+async fn keep_audit<S: Store, A: Auth>(state: &State<S, A>, record: AuditRecord) {
+    let record = record.finished();
+    info!(
+        id = record.id,
+        login = record.login,
+        provider = record.provider.as_str(),
+        token_id = record.token_id,
+        ip = record.ip,
+        admin = record.admin,
+        action = record.action.as_str(),
+        crate_name = record.crate_name,
+        version = record.version.as_ref().map(ToString::to_string),
+        detail = record.detail,
+        outcome = ?record.outcome,
+        "audit"
+    );
+    if let Err(e) = state.store.put_audit(&record).await {
+        error!(id = record.id, e = e.to_string(), "audit_write_failed");
+    }
+    if let Some(event) = Event::of(&record) {
+        state.webhooks.emit(event);
+    }
+}
+
+/// Replace every owner, e.g. to take a crate away from someone who left.
+async fn admin_set_owners<S: Store, A: Auth>(
+    TypedHeader(token): TypedHeader<RawAuthorization>,
+    ClientIp(ip): ClientIp,
+    extract::State(state): extract::State<State<S, A>>,
+    extract::Path(name): extract::Path<CrateName>,
+    Json(req): Json<AddOwnerRequest>,
+) -> Result<impl IntoResponse, HttpError> {
+    let record = AuditRecord::new(Action::SetOwners)
+        .by_admin()
+        .from_ip(ip)
+        .with_crate(&name)
+        .with_detail(req.users.join(", "));
+    let (principal, ()) = audited(&state, &token, record, async {
+        let principal = require_admin(&state, &token).await?;
+        let removed = state
+            .store
+            .get_owners(&name)
+            .await?
+            .into_iter()
+            .filter(|owner| !req.users.contains(owner))
+            .collect::<Vec<_>>();
+        if !removed.is_empty() {
+            state.store.delete_owner(&name, removed).await?;
+        }
+        state.store.add_owner(&name, req.users.clone()).await?;
+        Ok((principal, ()))
+    })
+    .await?;
+    Ok((
+        StatusCode::OK,
+        Extension(principal),
+        Json(json!({ "ok": true })),
+    ))
+}
+
+async fn admin_set_yank<S: Store, A: Auth>(
+    state: &State<S, A>,
+    token: &RawAuthorization,
+    ip: Option<String>,
+    name: CrateName,
+    ver: semver::Version,
+    yanked: bool,
+) -> Result<impl IntoResponse + use<S, A>, HttpError> {
+    let action = if yanked { Action::Yank } else { Action::Unyank };
+    let record = AuditRecord::new(action)
+        .by_admin()
+        .from_ip(ip)
+        .with_crate(&name)
+        .with_version(&ver);
+    let (principal, ()) = audited(state, token, record, async {
+        let principal = require_admin(state, token).await?;
+        state.store.set_yank(&name, ver.clone(), yanked).await?;
+        Ok((principal, ()))
+    })
+    .await?;
+    Ok((
+        StatusCode::OK,
+        Extension(principal),
+        Json(json!({ "ok": true })),
+    ))
+}
+
+async fn admin_yank<S: Store, A: Auth>(
+    TypedHeader(token): TypedHeader<RawAuthorization>,
+    ClientIp(ip): ClientIp,
+    extract::State(state): extract::State<State<S, A>>,
+    extract::Path((name, ver)): extract::Path<(CrateName, semver::Version)>,
+) -> Result<impl IntoResponse, HttpError> {
+    admin_set_yank(&state, &token, ip, name, ver, true).await
+}
+
+async fn admin_unyank<S: Store, A: Auth>(
+    TypedHeader(token): TypedHeader<RawAuthorization>,
+    ClientIp(ip): ClientIp,
+    extract::State(state): extract::State<State<S, A>>,
+    extract::Path((name, ver)): extract::Path<(CrateName, semver::Version)>,
+) -> Result<impl IntoResponse, HttpError> {
+    admin_set_yank(&state, &token, ip, name, ver, false).await
+}
+
+/// For versions that must not be fetched at all anymore, unlike yanked ones.
+async fn admin_delete_version<S: Store, A: Auth>(
+    TypedHeader(token): TypedHeader<RawAuthorization>,
+    ClientIp(ip): ClientIp,
+    extract::State(state): extract::State<State<S, A>>,
+    extract::Path((name, ver)): extract::Path<(CrateName, semver::Version)>,
+) -> Result<impl IntoResponse, HttpError> {
+    let record = AuditRecord::new(Action::DeleteVersion)
+        .by_admin()
+        .from_ip(ip)
+        .with_crate(&name)
+        .with_version(&ver);
+    let (principal, ()) = audited(&state, &token, record, async {
+        let principal = require_admin(&state, &token).await?;
+        state.store.delete_version(&name, ver.clone()).await?;
+        Ok((principal, ()))
+    })
+    .await?;
+    Ok((
+        StatusCode::OK,
+        Extension(principal),
+        Json(json!({ "ok": true })),
+    ))
+}
+
+async fn list_blocked<S: Store, A: Auth>(
+    TypedHeader(token): TypedHeader<RawAuthorization>,
+    extract::State(state): extract::State<State<S, A>>,
+) -> Result<impl IntoResponse, HttpError> {
+    let principal = require_admin(&state, &token).await?;
+    let blocked = state.store.list_blocked().await?;
+    Ok((
+        StatusCode::OK,
+        Extension(principal),
+        Json(json!({ "blocked": blocked })),
+    ))
+}
+
+#[derive(Deserialize)]
+struct BlockRequest {
+    reason: String,
+}
+
+async fn block_name<S: Store, A: Auth>(
+    TypedHeader(token): TypedHeader<RawAuthorization>,
+    ClientIp(ip): ClientIp,
+    extract::State(state): extract::State<State<S, A>>,
+    extract::Path(name): extract::Path<CrateName>,
+    Json(req): Json<BlockRequest>,
+) -> Result<impl IntoResponse, HttpError> {
+    let record = AuditRecord::new(Action::Block)
+        .by_admin()
+        .from_ip(ip)
+        .with_crate(&name)
+        .with_detail(req.reason.clone());
+    let (principal, ()) = audited(&state, &token, record, async {
+        let principal = require_admin(&state, &token).await?;
+        let blocked = BlockedName::new(&name, req.reason, &principal);
+        state.store.put_blocked(&blocked).await?;
+        Ok((principal, ()))
+    })
+    .await?;
+    Ok((
+        StatusCode::OK,
+        Extension(principal),
+        Json(json!({ "ok": true })),
+    ))
+}
+
+async fn unblock_name<S: Store, A: Auth>(
+    TypedHeader(token): TypedHeader<RawAuthorization>,
+    ClientIp(ip): ClientIp,
+    extract::State(state): extract::State<State<S, A>>,
+    extract::Path(name): extract::Path<CrateName>,
+) -> Result<impl IntoResponse, HttpError> {
+    let record = AuditRecord::new(Action::Unblock)
+        .by_admin()
+        .from_ip(ip)
+        .with_crate(&name);
+    let (principal, ()) = audited(&state, &token, record, async {
+        let principal = require_admin(&state, &token).await?;
+        state.store.delete_blocked(&name).await?;
+        Ok((principal, ()))
+    })
+    .await?;
+    Ok((
+        StatusCode::OK,
+        Extension(principal),
+        Json(json!({ "ok": true })),
+    ))
+}
+
+async fn list_freezes<S: Store, A: Auth>(
+    TypedHeader(token): TypedHeader<RawAuthorization>,
+    extract::State(state): extract::State<State<S, A>>,
+) -> Result<impl IntoResponse, HttpError> {
+    let principal = require_admin(&state, &token).await?;
+    let freezes = state.store.list_freezes().await?;
+    Ok((
+        StatusCode::OK,
+        Extension(principal),
+        Json(json!({ "freezes": freezes })),
+    ))
+}
+
+/// Create or replace a freeze window.
+async fn put_freeze<S: Store, A: Auth>(
+    TypedHeader(token): TypedHeader<RawAuthorization>,
+    ClientIp(ip): ClientIp,
+    extract::State(state): extract::State<State<S, A>>,
+    extract::Path(id): extract::Path<String>,
+    Json(req): Json<FreezeRequest>,
+) -> Result<impl IntoResponse, HttpError> {
+    let record = AuditRecord::new(Action::Freeze)
+        .by_admin()
+        .from_ip(ip)
+        .with_detail(format!(
+            "{} on {} from {} until {}",
+            id,
+            req.crates.join(", "),
+            req.starts_at,
+            req.ends_at
+        ));
+    let (principal, freeze) = audited(&state, &token, record, async {
+        let principal = require_admin(&state, &token).await?;
+        let freeze = FreezeWindow::new(&id, req, &principal)?;
+        state.store.put_freeze(&freeze).await?;
+        Ok((principal, freeze))
+    })
+    .await?;
+    Ok((StatusCode::OK, Extension(principal), Json(freeze)))
+}
+
+async fn delete_freeze<S: Store, A: Auth>(
+    TypedHeader(token): TypedHeader<RawAuthorization>,
+    ClientIp(ip): ClientIp,
+    extract::State(state): extract::State<State<S, A>>,
+    extract::Path(id): extract::Path<String>,
+) -> Result<impl IntoResponse, HttpError> {
+    let record = AuditRecord::new(Action::Unfreeze)
+        .by_admin()
+        .from_ip(ip)
+        .with_detail(id.clone());
+    let (principal, ()) = audited(&state, &token, record, async {
+        let principal = require_admin(&state, &token).await?;
+        state.store.delete_freeze(&id).await?;
+        Ok((principal, ()))
+    })
+    .await?;
+    Ok((
+        StatusCode::OK,
+        Extension(principal),
+        Json(json!({ "ok": true })),
+    ))
+}
+
+#[derive(Deserialize)]
+struct AuditLimit {
+    limit: Option<usize>,
+}
+
+async fn find_audit<S: Store>(
+    store: &S,
+    filter: &AuditFilter,
+    order: AuditOrder,
+    limit: usize,
+) -> Result<Vec<AuditRecord>, HttpError> {
+    store
+        .list_audit(filter.since, order, limit, |record| filter.matches(record))
+        .await
+}
+
+/// Records matching the query parameters of [`AuditFilter`], newest first.
+async fn list_audit<S: Store, A: Auth>(
+    TypedHeader(token): TypedHeader<RawAuthorization>,
+    extract::State(state): extract::State<State<S, A>>,
+    extract::Query(filter): extract::Query<AuditFilter>,
+    extract::Query(AuditLimit { limit }): extract::Query<AuditLimit>,
+) -> Result<impl IntoResponse, HttpError> {
+    let principal = require_admin(&state, &token).await?;
+    let records = find_audit(
+        &state.store,
+        &filter,
+        AuditOrder::NewestFirst,
+        limit.unwrap_or(100),
+    )
+    .await?;
+    Ok((
+        StatusCode::OK,
+        Extension(principal),
+        Json(json!({ "records": records })),
+    ))
+}
+
+/// Matching records as JSON lines, oldest first, for compliance reviews. Every record unless
+/// `limit` is given; `since` and `until` bound it in time like the other filters.
+async fn export_audit<S: Store + Clone + Send + Sync + 'static, A: Auth>(
+    TypedHeader(token): TypedHeader<RawAuthorization>,
+    extract::State(state): extract::State<State<S, A>>,
+    extract::Query(filter): extract::Query<AuditFilter>,
+    extract::Query(AuditLimit { limit }): extract::Query<AuditLimit>,
+) -> Result<impl IntoResponse, HttpError> {
+    let principal = require_admin(&state, &token).await?;
+    let mut pages = Box::pin(audit::export(state.store.clone(), filter, limit));
+    // read the first page up front, so that a failing store still gets an error status
+    let first = pages.next().await.transpose()?;
+    let pages = futures_util::stream::iter(first.map(Ok))
+        .chain(pages)
+        .map(|page| match page {
+            Ok(records) => Ok(audit::to_jsonl(&records)),
+            Err(e) => {
+                error!(e = e.to_string(), "audit_export_failed");
+                Err(io::Error::other(e.to_string()))
+            }
+        });
+    Ok((
+        StatusCode::OK,
+        Extension(principal),
+        [(header::CONTENT_TYPE, "application/x-ndjson")],
+        axum::body::Body::from_stream(pages),
+    ))
+}
+
+/// Recent delivery attempts, newest first.
+async fn webhook_deliveries<S: Store, A: Auth>(
+    TypedHeader(token): TypedHeader<RawAuthorization>,
+    extract::State(state): extract::State<State<S, A>>,
+) -> Result<impl IntoResponse, HttpError> {
+    let principal = require_admin(&state, &token).await?;
+    Ok((
+        StatusCode::OK,
+        Extension(principal),
+        Json(json!({ "deliveries": state.webhooks.deliveries() })),
+    ))
+}
+
+#[derive(Deserialize)]
+struct TrustedPublishingRequest {
+    jwt: String,
+    #[serde(rename = "crate")]
+    crate_name: CrateName,
+}
+
+// the OIDC token is the credential here
+async fn exchange_oidc_token<S: Store, A: Auth>(
+    ClientIp(ip): ClientIp,
+    extract::State(state): extract::State<State<S, A>>,
+    Json(req): Json<TrustedPublishingRequest>,
+) -> Result<impl IntoResponse, HttpError> {
+    let record = AuditRecord::new(Action::ExchangeOidcToken)
+        .from_ip(ip)
+        .with_crate(&req.crate_name);
+    let result = state.publishing.exchange(&req.jwt, &req.crate_name).await;
+    let principal = match &result {
+        Ok(issued) => issued.principal.clone(),
+        Err(_) => Principal::anonymous(),
+    };
+    keep_audit(&state, record.by(&principal).with_result(&result)).await;
+    Ok((StatusCode::OK, Extension(principal), Json(result?)))
+}
+
+async fn revoke_publish_token<S: Store, A: Auth>(
+    TypedHeader(token): TypedHeader<RawAuthorization>,
+    extract::State(state): extract::State<State<S, A>>,
+) -> Result<impl IntoResponse, HttpError> {
+    state.publishing.revoke(&token).await?;
+    Ok((StatusCode::OK, Json(json!({ "ok": true }))))
+}
+
 #[cfg(unix)]
 async fn wait_shutdown() {
     use tokio::signal::unix::{SignalKind, signal};
     let mut sigint = signal(SignalKind::interrupt()).expect("sigint handler");
     let mut sigterm = signal(SignalKind::terminate()).expect("sigterm handler");
-    let mut sighup = signal(SignalKind::hangup()).expect("sighup handler");
     tokio::select! {
         Some(()) = sigint.recv() => (),
         Some(()) = sigterm.recv() => (),
-        Some(()) = sighup.recv() => (),
     }
 }
 
+async fn load_config(path: Option<&Path>) -> anyhow::Result<ServerConfig> {
+    match path {
+        Some(path) => Ok(ServerConfig::from_yaml(&fs::read_to_string(path).await?)?),
+        None => Ok(ServerConfig::default()),
+    }
+}
+
+async fn load_service_accounts(
+    path: Option<&Path>,
+    github: &GitHubAuth,
+) -> anyhow::Result<ServiceAccounts> {
+    match path {
+        Some(path) => Ok(ServiceAccounts::from_yaml(
+            &fs::read_to_string(path).await?,
+            github.clone(),
+        )?),
+        None => Ok(ServiceAccounts::new(github.clone())),
+    }
+}
+
+async fn load_rules(path: &Path) -> anyhow::Result<AuthRules> {
+    let auth_rules = fs::read_to_string(path).await?;
+    Ok(AuthRules::from_yaml(&auth_rules)?)
+}
+
+#[cfg(unix)]
+async fn reload_rules_on_sighup(path: PathBuf, auth: GitHubAuth) {
+    use tokio::signal::unix::{SignalKind, signal};
+    let mut sighup = signal(SignalKind::hangup()).expect("sighup handler");
+    while let Some(()) = sighup.recv().await {
+        match load_rules(&path).await {
+            Ok(auth_rules) => {
+                auth.reload(auth_rules);
+                info!(path = path.display().to_string(), "rules_reloaded");
+            }
+            Err(e) => {
+                error!(
+                    path = path.display().to_string(),
+                    e = e.to_string(),
+                    "rules_reload_failed"
+                );
+            }
+        }
+    }
+}
+
+/// While read-only, whatever would change a crate fails with 503; see [`is_blocked`].
+async fn reject_writes_in_maintenance<S, A>(
+    extract::State(state): extract::State<State<S, A>>,
+    req: Request<axum::body::Body>,
+    next: Next,
+) -> axum::response::Response {
+    if is_blocked(req.method(), req.uri().path())
+        && let Err(e) = state.maintenance.check()
+    {
+        return e.into_response();
+    }
+    next.run(req).await
+}
+
+#[cfg(unix)]
+async fn toggle_maintenance_on_sigusr1(maintenance: Maintenance, config: MaintenanceConfig) {
+    use tokio::signal::unix::{SignalKind, signal};
+    let mut sigusr1 = signal(SignalKind::user_defined1()).expect("sigusr1 handler");
+    while let Some(()) = sigusr1.recv().await {
+        let read_only = maintenance.toggle(config.clone());
+        info!(read_only, "maintenance_toggled");
+    }
+}
+
+/// On a follower, whatever would change something is done by the primary instead; see
+/// [`is_forwarded`].
+async fn forward_writes<S: Store + Clone + Send + Sync + 'static, A: Auth>(
+    extract::State(state): extract::State<State<S, A>>,
+    req: Request<axum::body::Body>,
+    next: Next,
+) -> axum::response::Response {
+    match &state.follower {
+        Some(follower) if is_forwarded(req.method(), req.uri().path()) => {
+            follower.forward(req).await
+        }
+        _ => next.run(req).await,
+    }
+}
+
+/// On a follower, requests made with a credential only the primary can check are answered by
+/// the primary, reads included; see [`is_checked_by_primary`].
+async fn forward_primary_credentials<S: Store + Clone + Send + Sync + 'static, A: Auth>(
+    extract::State(state): extract::State<State<S, A>>,
+    req: Request<axum::body::Body>,
+    next: Next,
+) -> axum::response::Response {
+    let authorization = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok());
+    match &state.follower {
+        Some(follower) if is_checked_by_primary(req.method(), req.uri().path(), authorization) => {
+            follower.forward(req).await
+        }
+        _ => next.run(req).await,
+    }
+}
+
+/// How far a follower is behind its primary; 503 once that is more than `max_lag`.
+async fn replication_health<S: Store + Clone + Send + Sync + 'static, A: Auth>(
+    extract::State(state): extract::State<State<S, A>>,
+) -> impl IntoResponse {
+    let Some(follower) = &state.follower else {
+        return (StatusCode::OK, Json(json!({ "follower": false })));
+    };
+    let status = follower.status();
+    let code = if status.healthy {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (code, Json(json!(status)))
+}
+
 async fn access_log_on_request(
     req: Request<axum::body::Body>,
     next: Next,
@@ -307,69 +1434,316 @@ async fn access_log_on_request(
     let path = req.uri().path().to_string();
     let query = req.uri().query().map(ToString::to_string);
     let response = next.run(req).await;
+    // handlers attach whoever the request was authenticated as
+    let principal = response.extensions().get::<Principal>();
     info!(
         method,
         path,
         query,
         status = response.status().as_u16(),
+        login = principal.and_then(|principal| principal.login.as_deref()),
+        provider = principal.map(|principal| principal.provider.as_str()),
         "access"
     );
     Ok(response)
 }
 
-async fn run(opts: Opts) -> anyhow::Result<()> {
+async fn run(opts: ServeOpts) -> anyhow::Result<()> {
     let store = gdynya::store::aws::AwsStore::new(opts.objstore, opts.objstore_endpoint).await;
-    let auth_rules = fs::read_to_string(&opts.rules).await?;
-    let auth_rules = serde_yaml::from_str(&auth_rules)?;
-    let auth = gdynya::auth::github::GitHubAuth::new_from_config(auth_rules);
+    let auth_rules = load_rules(&opts.rules).await?;
+    let server_config = load_config(opts.config.as_deref()).await?;
+    let mut github = GitHubAuth::new_from_config(auth_rules)
+        .with_api(opts.github.api().await?)
+        .with_cache_config(server_config.auth_cache);
+    if let Some(max_age) = opts.github_stale_if_error {
+        github = github.with_stale_if_error(Duration::from_secs(max_age));
+    }
+    if let Some(service_token) = opts.github_service_token {
+        github = github.with_service_token(service_token);
+    }
+    github = github
+        .with_public_read(opts.public_read)
+        .with_debug_reasons(opts.auth_debug);
+    #[cfg(unix)]
+    tokio::spawn(reload_rules_on_sighup(opts.rules.clone(), github.clone()));
+    let publishing =
+        TrustedPublishing::new(&server_config.trusted_publishing, github.clone()).await?;
+    let service_accounts = load_service_accounts(opts.service_accounts.as_deref(), &github).await?;
+    let tokens = RegistryTokenAuth::new(store.clone(), github.clone());
+    let keys = PublicKeys::new(store.clone(), github.clone());
+    // GitHub tokens come in several formats, so GitHub takes whatever the others do not
+    let auth = tokens
+        .clone()
+        .or(publishing.clone())
+        .or(PasetoAuth::new(keys.clone(), server_config.paseto))
+        .or(service_accounts)
+        .or(github.clone());
     store.health_check().await?;
     info!("store_healthcheck_passed");
-    let state = State { store, auth };
+    let follower = server_config
+        .follower
+        .map(|follower| Follower::new(store.clone(), follower));
+    let state = State {
+        store,
+        auth,
+        github,
+        admins: Arc::new(server_config.admins),
+        tokens,
+        publishing,
+        keys,
+        webhooks: Webhooks::new(server_config.webhooks),
+        follower,
+        maintenance: Maintenance::new(server_config.maintenance.clone()),
+    };
+    if state.maintenance.current().is_some() {
+        info!("read_only");
+    }
+    #[cfg(unix)]
+    tokio::spawn(toggle_maintenance_on_sigusr1(
+        state.maintenance.clone(),
+        server_config.maintenance.unwrap_or_default(),
+    ));
+    if let Some(follower) = &state.follower {
+        info!(primary = follower.status().primary, "following");
+        follower.spawn();
+    }
 
     let v1_api = Router::new()
         .route("/crates/new", routing::put(publish_crate))
-        .route("/crates/:name/:ver", routing::get(get_crate))
-        .route("/crates/:name/:ver/yank", routing::delete(yank_crate))
-        .route("/crates/:name/:ver/yank", routing::put(unyank_crate))
-        .route("/crates/:name/owners", routing::get(get_owners))
-        .route("/crates/:name/owners", routing::put(add_owner))
-        .route("/crates/:name/owners", routing::delete(delete_owner))
+        .route("/crates/{name}/{ver}", routing::get(get_crate))
+        .route("/crates/{name}/{ver}/yank", routing::delete(yank_crate))
+        .route("/crates/{name}/{ver}/yank", routing::put(unyank_crate))
+        .route("/crates/{name}/owners", routing::get(get_owners))
+        .route("/crates/{name}/owners", routing::put(add_owner))
+        .route("/crates/{name}/owners", routing::delete(delete_owner))
         .route("/crates", routing::get(search_crates))
+        .route("/me", routing::get(me))
+        .route("/changes", routing::get(list_changes))
+        .route("/events", routing::get(events))
+        .route("/admin/explain", routing::get(explain))
+        .route("/admin/cache", routing::get(cache_stats))
+        .route("/admin/cache", routing::delete(flush_cache))
+        .route(
+            "/admin/crates/{name}/owners",
+            routing::put(admin_set_owners),
+        )
+        .route(
+            "/admin/crates/{name}/{ver}",
+            routing::delete(admin_delete_version),
+        )
+        .route(
+            "/admin/crates/{name}/{ver}/yank",
+            routing::delete(admin_yank),
+        )
+        .route(
+            "/admin/crates/{name}/{ver}/yank",
+            routing::put(admin_unyank),
+        )
+        .route("/admin/blocked", routing::get(list_blocked))
+        .route("/admin/blocked/{name}", routing::put(block_name))
+        .route("/admin/blocked/{name}", routing::delete(unblock_name))
+        .route("/admin/freezes", routing::get(list_freezes))
+        .route("/admin/freezes/{id}", routing::put(put_freeze))
+        .route("/admin/freezes/{id}", routing::delete(delete_freeze))
+        .route("/admin/audit", routing::get(list_audit))
+        .route("/admin/audit/export", routing::get(export_audit))
+        .route(
+            "/admin/webhooks/deliveries",
+            routing::get(webhook_deliveries),
+        )
+        .route("/tokens", routing::put(create_token))
+        .route("/tokens", routing::get(list_tokens))
+        .route("/tokens/{id}", routing::delete(revoke_token))
+        .route("/keys", routing::put(register_key))
+        .route("/keys", routing::get(list_keys))
+        .route("/keys/{kid}", routing::delete(revoke_key))
+        .route(
+            "/trusted_publishing/tokens",
+            routing::put(exchange_oidc_token),
+        )
+        .route(
+            "/trusted_publishing/tokens",
+            routing::delete(revoke_publish_token),
+        )
+        .route("/admin/maintenance", routing::get(get_maintenance))
+        .route("/admin/maintenance", routing::put(enable_maintenance))
+        .route("/admin/maintenance", routing::delete(disable_maintenance))
+        .layer(axum::middleware::from_fn_with_state(
+            state.clone(),
+            forward_writes,
+        ))
+        .layer(axum::middleware::from_fn_with_state(
+            state.clone(),
+            reject_writes_in_maintenance,
+        ))
         .with_state(state.clone());
 
     let app = Router::new()
         .nest("/api/v1", v1_api)
         .route("/config.json", routing::get(config))
-        .route("/1/:name", routing::get(get_index_len_1))
-        .route("/2/:name", routing::get(get_index_len_2))
-        .route("/3/:prefix/:name", routing::get(get_index_len_3))
+        .route("/health/replication", routing::get(replication_health))
+        .route("/1/{name}", routing::get(get_index_len_1))
+        .route("/2/{name}", routing::get(get_index_len_2))
+        .route("/3/{prefix}/{name}", routing::get(get_index_len_3))
         .route(
-            "/:prefix1/:prefix2/:name",
+            "/{prefix1}/{prefix2}/{name}",
             routing::get(get_index_len_at_least_4),
         )
+        .layer(axum::middleware::from_fn_with_state(
+            state.clone(),
+            forward_primary_credentials,
+        ))
         .layer(axum::middleware::from_fn(access_log_on_request))
+        .layer(Extension(TrustedProxies(Arc::new(
+            server_config.trusted_proxies,
+        ))))
         .with_state(state);
 
     info!(addr = opts.addr.to_string(), "init");
 
     let stream = TcpListener::bind(opts.addr).await?;
-    axum::serve(stream, app.into_make_service())
-        .with_graceful_shutdown(wait_shutdown())
-        .await?;
+    axum::serve(
+        stream,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .with_graceful_shutdown(wait_shutdown())
+    .await?;
 
     Ok(())
 }
 
+async fn check_config(opts: CheckConfigOpts) -> i32 {
+    if opts.export_schema {
+        let schema = schemars::schema_for!(AuthRules);
+        println!("{}", serde_json::to_string_pretty(&schema).unwrap());
+        return 0;
+    }
+    let mut failed = false;
+    let mut auth_rules = None;
+    if let Some(path) = &opts.rules {
+        let path_display = path.display();
+        match fs::read_to_string(path).await {
+            Err(e) => {
+                eprintln!("{path_display}: {e}");
+                failed = true;
+            }
+            Ok(src) => match AuthRules::from_yaml(&src) {
+                Ok(rules) => {
+                    println!("{path_display}: ok");
+                    auth_rules = Some(rules);
+                }
+                Err(RulesError::Parse(e)) => {
+                    eprintln!("{path_display}: {e}");
+                    failed = true;
+                }
+                Err(RulesError::Invalid(problems)) => {
+                    for problem in problems {
+                        match problem.line(&src) {
+                            Some(line) => eprintln!("{path_display}:{line}: {}", problem.message),
+                            None => eprintln!("{path_display}: {}", problem.message),
+                        }
+                    }
+                    failed = true;
+                }
+            },
+        }
+    }
+    if let Some(path) = &opts.config {
+        match load_config(Some(path)).await {
+            Ok(_) => println!("{}: ok", path.display()),
+            Err(e) => {
+                eprintln!("{}: {e}", path.display());
+                failed = true;
+            }
+        }
+    }
+    if let Some(path) = &opts.service_accounts {
+        let github = GitHubAuth::new_from_config(AuthRules::default());
+        match load_service_accounts(Some(path), &github).await {
+            Ok(_) => println!("{}: ok", path.display()),
+            Err(e) => {
+                eprintln!("{}: {e}", path.display());
+                failed = true;
+            }
+        }
+    }
+    if let Some(Err(e)) = opts.addr.as_deref().map(str::parse::<SocketAddr>) {
+        eprintln!("addr: {e}");
+        failed = true;
+    }
+    if let Some(Err(e)) = opts.objstore_endpoint.as_deref().map(url::Url::parse) {
+        eprintln!("objstore_endpoint: {e}");
+        failed = true;
+    }
+    if let (Some(user), Some(crate_name)) = (&opts.user, &opts.crate_name) {
+        let Some(auth_rules) = &auth_rules else {
+            eprintln!("--user needs a valid --rules file");
+            return 1;
+        };
+        let name = match crate_name.parse::<CrateName>() {
+            Ok(name) => name,
+            Err(e) => {
+                eprintln!("crate_name: {e}");
+                return 1;
+            }
+        };
+        let Some((matched, rule)) = auth_rules.lookup(&name.normalized) else {
+            println!("{crate_name}: no rule matches, read and write are denied");
+            return i32::from(failed);
+        };
+        println!("{crate_name}: matched by {matched}");
+        let api = match opts.github.api().await {
+            Ok(api) => api,
+            Err(e) => {
+                eprintln!("github: {e}");
+                return 1;
+            }
+        };
+        let token = opts.github_token.as_deref().unwrap_or_default();
+        for access in Access::ALL {
+            let op = access.as_str();
+            let Some((key, rule)) = rule.get(access) else {
+                println!("{user} {op} {crate_name}: denied, no rule");
+                continue;
+            };
+            if key != op {
+                println!("{user} {op} {crate_name}: decided by the {key} rule");
+            }
+            match rule.explain_as(&api, token, user).await {
+                Ok(trace) => {
+                    let decision = if trace.holds { "allowed" } else { "denied" };
+                    println!("{user} {op} {crate_name}: {decision}");
+                    for line in trace.lines() {
+                        println!("  {line}");
+                    }
+                }
+                Err(e) => {
+                    eprintln!("{user} {op} {crate_name}: {e}");
+                    failed = true;
+                }
+            }
+        }
+    }
+    i32::from(failed)
+}
+
 #[tokio::main]
 async fn main() {
     let opts = Opts::parse();
+    if let Some(Command::CheckConfig(opts)) = opts.command {
+        std::process::exit(check_config(opts).await);
+    }
+    let Some(opts) = opts.serve else {
+        unreachable!("clap requires either a subcommand or the server options")
+    };
     tracing_subscriber::registry()
         .with(
             tracing_subscriber::EnvFilter::try_from_default_env()
                 .unwrap_or_else(|_| "gdynya=Info".into()),
         )
-        .with(tracing_subscriber::fmt::layer())
+        .with(tracing_subscriber::fmt::layer().with_writer(RedactingMakeWriter(io::stdout)))
         .init();
+    redact::install_panic_hook();
     if let Err(e) = run(opts).await {
         error!(e = e.to_string(), "error");
     }
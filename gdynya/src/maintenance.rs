@@ -0,0 +1,123 @@
+//! Read-only mode for storage migrations and the like.
+//!
+//! While it is on, requests that would change a crate are answered with 503, the message and
+//! `Retry-After`; the index and downloads keep working, and so do tokens and the admin fixes
+//! (blocked names, freezes, the cache) that might be needed in the meantime.
+
+use std::sync::Arc;
+
+use arc_swap::ArcSwapOption;
+use axum::http::{Method, StatusCode};
+use serde::{Deserialize, Serialize};
+
+use crate::{HttpError, auth::now};
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct MaintenanceConfig {
+    /// Shown by cargo to whoever tries to publish.
+    #[serde(default = "default_message")]
+    pub message: String,
+    /// Seconds, sent as `Retry-After`.
+    #[serde(default = "default_retry_after")]
+    pub retry_after: u64,
+}
+
+fn default_message() -> String {
+    "the registry is read-only for maintenance".to_string()
+}
+
+fn default_retry_after() -> u64 {
+    600
+}
+
+impl Default for MaintenanceConfig {
+    fn default() -> Self {
+        Self {
+            message: default_message(),
+            retry_after: default_retry_after(),
+        }
+    }
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct MaintenanceState {
+    #[serde(flatten)]
+    pub config: MaintenanceConfig,
+    pub since: u64,
+    /// Admin who turned it on; `None` for the config file or a signal.
+    pub by: Option<String>,
+}
+
+/// Shared switch, cheap to check on every request.
+#[derive(Clone, Default)]
+pub struct Maintenance(Arc<ArcSwapOption<MaintenanceState>>);
+
+impl Maintenance {
+    pub fn new(config: Option<MaintenanceConfig>) -> Self {
+        let maintenance = Self::default();
+        if let Some(config) = config {
+            maintenance.enable(config, None);
+        }
+        maintenance
+    }
+
+    pub fn enable(&self, config: MaintenanceConfig, by: Option<String>) {
+        self.0.store(Some(Arc::new(MaintenanceState {
+            config,
+            since: now(),
+            by,
+        })));
+    }
+
+    pub fn disable(&self) {
+        self.0.store(None);
+    }
+
+    /// Turn it on with `config` if it is off, and off otherwise; whether it is now on.
+    pub fn toggle(&self, config: MaintenanceConfig) -> bool {
+        if self.current().is_some() {
+            self.disable();
+            false
+        } else {
+            self.enable(config, None);
+            true
+        }
+    }
+
+    pub fn current(&self) -> Option<MaintenanceState> {
+        self.0.load_full().map(|state| (*state).clone())
+    }
+
+    /// 503 while read-only.
+    pub fn check(&self) -> Result<(), HttpError> {
+        let Some(state) = self.0.load_full() else {
+            return Ok(());
+        };
+        Err(HttpError {
+            error_type: StatusCode::SERVICE_UNAVAILABLE,
+            message: state.config.message.clone(),
+            verbose_message: String::new(),
+            contexts: Vec::new(),
+            retry_after: Some(state.config.retry_after),
+        })
+    }
+}
+
+/// Whether read-only mode turns away a request under `/api/v1`: publishing, yanking and
+/// unyanking, changing owners and deleting versions, admin or not.
+pub fn is_blocked(method: &Method, path: &str) -> bool {
+    if matches!(*method, Method::GET | Method::HEAD) {
+        return false;
+    }
+    let segments = path.trim_matches('/').split('/').collect::<Vec<_>>();
+    matches!(
+        segments.as_slice(),
+        ["crates", "new"]
+            | ["crates", _, _, "yank"]
+            | ["crates", _, "owners"]
+            | ["admin", "crates", _, "owners"]
+            | ["admin", "crates", _, _]
+            | ["admin", "crates", _, _, "yank"]
+    )
+}
@@ -0,0 +1,162 @@
+//! Scrubbing credentials out of text that leaves the process: log lines, error bodies and
+//! panic messages.
+
+use std::{borrow::Cow, io};
+
+use tracing_subscriber::fmt::MakeWriter;
+
+pub const REDACTED: &str = "[REDACTED]";
+
+/// Prefixes of every token format the registry issues or accepts, GitHub's included.
+const TOKEN_PREFIXES: &[&str] = &[
+    "gdy_",
+    "gdo_",
+    "gds_",
+    "v3.public.",
+    "ghp_",
+    "gho_",
+    "ghu_",
+    "ghs_",
+    "ghr_",
+    "github_pat_",
+];
+
+/// Words after which the rest of the word that follows is a credential, matched
+/// case-insensitively.
+const CREDENTIAL_LABELS: &[&str] = &["authorization:", "authorization=", "bearer "];
+
+/// Words after which a bare 40 hex digit word is a classic GitHub token rather than, say, a
+/// commit hash. Separators like `=`, `:` and quotes may come in between.
+const HEX_TOKEN_LABELS: &[&str] = &["token", "password", "secret"];
+
+/// Length of classic GitHub tokens, which have no prefix.
+const HEX_TOKEN_LEN: usize = 40;
+
+fn is_token_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.' | '=' | '+' | '/')
+}
+
+/// `text` with tokens and `Authorization` header values replaced by [`REDACTED`].
+pub fn redact(text: &str) -> Cow<'_, str> {
+    let lower = text.to_ascii_lowercase();
+    // byte ranges to blank out; the ASCII lowercase copy keeps byte offsets intact
+    let mut ranges = Vec::new();
+    for prefix in TOKEN_PREFIXES {
+        for (start, _) in text.match_indices(prefix) {
+            let preceded_by_word = text[..start]
+                .chars()
+                .next_back()
+                .is_some_and(|c| c.is_ascii_alphanumeric() || c == '_');
+            if !preceded_by_word {
+                ranges.push((start, token_end(text, start + prefix.len())));
+            }
+        }
+    }
+    for label in CREDENTIAL_LABELS {
+        for (start, _) in lower.match_indices(label) {
+            let value = start + label.len();
+            let value = value + (text[value..].len() - text[value..].trim_start().len());
+            // `Authorization: Bearer x` blanks `Bearer x`, not just `Bearer`
+            let value = if lower[value..].starts_with("bearer ") {
+                value + "bearer ".len()
+            } else {
+                value
+            };
+            ranges.push((value, token_end(text, value)));
+        }
+    }
+    ranges.extend(hex_tokens(text, &lower));
+    ranges.retain(|(start, end)| start < end);
+    if ranges.is_empty() {
+        return Cow::Borrowed(text);
+    }
+    ranges.sort();
+    let mut redacted = String::with_capacity(text.len());
+    let mut copied = 0;
+    for (start, end) in ranges {
+        if start >= copied {
+            redacted.push_str(&text[copied..start]);
+            redacted.push_str(REDACTED);
+        }
+        copied = copied.max(end);
+    }
+    redacted.push_str(&text[copied..]);
+    Cow::Owned(redacted)
+}
+
+/// Byte ranges of bare hex tokens following a [`HEX_TOKEN_LABELS`] word or standing for the
+/// user in a URL, like `https://x-access-token:<token>@github.com`.
+fn hex_tokens(text: &str, lower: &str) -> Vec<(usize, usize)> {
+    let bytes = text.as_bytes();
+    let mut ranges = Vec::new();
+    let mut start = 0;
+    while start < bytes.len() {
+        let len = bytes[start..]
+            .iter()
+            .take_while(|b| b.is_ascii_alphanumeric())
+            .count();
+        if len == 0 {
+            start += 1;
+            continue;
+        }
+        let end = start + len;
+        let word = &bytes[start..end];
+        if len == HEX_TOKEN_LEN && word.iter().all(u8::is_ascii_hexdigit) {
+            let before = lower[..start].trim_end_matches([' ', '"', '\'', ':', '=']);
+            let labelled = HEX_TOKEN_LABELS.iter().any(|label| before.ends_with(label));
+            let in_url = lower[..start].ends_with("//") && text[end..].starts_with(['@', ':']);
+            if labelled || in_url {
+                ranges.push((start, end));
+            }
+        }
+        start = end;
+    }
+    ranges
+}
+
+fn token_end(text: &str, from: usize) -> usize {
+    text[from..]
+        .char_indices()
+        .find(|(_, c)| !is_token_char(*c))
+        .map_or(text.len(), |(i, _)| from + i)
+}
+
+/// Wraps a tracing writer so that every formatted event goes through [`redact`].
+pub struct RedactingMakeWriter<M>(pub M);
+
+impl<'a, M: MakeWriter<'a>> MakeWriter<'a> for RedactingMakeWriter<M> {
+    type Writer = RedactingWriter<M::Writer>;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        RedactingWriter(self.0.make_writer())
+    }
+}
+
+pub struct RedactingWriter<W>(W);
+
+impl<W: io::Write> io::Write for RedactingWriter<W> {
+    // the fmt layer writes each event in one call, so tokens are never split across calls
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let text = String::from_utf8_lossy(buf);
+        self.0.write_all(redact(&text).as_bytes())?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+/// Print panics like the default hook does, with the message redacted.
+pub fn install_panic_hook() {
+    std::panic::set_hook(Box::new(|info| {
+        let message = info
+            .payload()
+            .downcast_ref::<&str>()
+            .map(|message| message.to_string())
+            .or_else(|| info.payload().downcast_ref::<String>().cloned())
+            .unwrap_or_default();
+        let location = info.location().map(ToString::to_string).unwrap_or_default();
+        eprintln!("panicked at {location}:\n{}", redact(&message));
+    }));
+}
@@ -1,18 +1,26 @@
 use aws_config::{BehaviorVersion, SdkConfig};
 use aws_sdk_s3::primitives::ByteStream;
 use axum::http::StatusCode;
-use futures_util::future::join_all;
+use futures_util::{StreamExt, TryStreamExt, future::join_all, stream};
 use nom::AsBytes;
+use serde::{Serialize, de::DeserializeOwned};
 use tracing::{debug, info};
 use valuable::Valuable;
 
 use crate::{
     HttpError, ToHttpError, ToHttpErrorOption,
+    admin::{BlockedName, FreezeWindow},
     api_schema::{
         CrateName, GetIndexResponse, PostIndexRequest, QueriedPackage, SearchCratesQuery,
     },
+    audit::AuditRecord,
+    auth::{paseto::KeyRecord, token::TokenRecord},
+    store::AuditOrder,
 };
 
+/// Objects fetched at once when reading many, e.g. the audit log.
+const CONCURRENT_GETS: usize = 16;
+
 #[derive(Clone)]
 pub struct AwsStore {
     s3: aws_sdk_s3::Client,
@@ -42,47 +50,144 @@ impl AwsStore {
     }
 
     async fn list_s3_keys(&self, prefix: &str) -> Result<Vec<String>, HttpError> {
+        self.list_s3_keys_after(prefix, None).await
+    }
+
+    /// Keys under `prefix` that sort after `start_after`.
+    async fn list_s3_keys_after(
+        &self,
+        prefix: &str,
+        start_after: Option<String>,
+    ) -> Result<Vec<String>, HttpError> {
+        let (mut keys, mut continuation_token) =
+            self.list_s3_page(prefix, start_after, None).await?;
+        while let Some(token) = continuation_token {
+            let (mut page, next) = self.list_s3_page(prefix, None, Some(token)).await?;
+            keys.append(&mut page);
+            continuation_token = next;
+        }
+        Ok(keys)
+    }
+
+    /// One page of keys under `prefix`, and the token for the next page if there is one.
+    async fn list_s3_page(
+        &self,
+        prefix: &str,
+        start_after: Option<String>,
+        continuation_token: Option<String>,
+    ) -> Result<(Vec<String>, Option<String>), HttpError> {
         let response = self
             .s3
             .list_objects_v2()
-            .prefix(prefix)
             .bucket(&self.s3_bucket)
+            .prefix(prefix)
+            .set_start_after(start_after)
+            .set_continuation_token(continuation_token)
             .send()
             .await
             .http_error(StatusCode::INTERNAL_SERVER_ERROR)?;
-        let mut keys = response
+        let keys = response
             .contents
             .http_error_with(StatusCode::NOT_FOUND, || "no index")?
             .into_iter()
             .map(|obj| obj.key)
             .map(|key| key.http_error_with(StatusCode::INTERNAL_SERVER_ERROR, || "no key found"))
             .collect::<Result<Vec<_>, _>>()?;
-        let mut continuation_token = response.next_continuation_token.clone();
-        while let Some(token) = continuation_token.clone() {
-            let response = self
-                .s3
-                .list_objects_v2()
-                .bucket(&self.s3_bucket)
-                .continuation_token(token)
-                .prefix(prefix)
-                //.delimiter("/")
-                .send()
-                .await
-                .http_error(StatusCode::INTERNAL_SERVER_ERROR)?;
-            continuation_token = response.next_continuation_token.clone();
-            keys.append(
-                &mut response
-                    .contents
-                    .http_error_with(StatusCode::NOT_FOUND, || "no index")?
-                    .into_iter()
-                    .map(|obj| obj.key)
-                    .map(|key| {
-                        key.http_error_with(StatusCode::INTERNAL_SERVER_ERROR, || "no key found")
-                    })
-                    .collect::<Result<_, _>>()?,
-            );
+        Ok((keys, response.next_continuation_token))
+    }
+
+    /// Objects at `keys` in that order, a few at a time; objects deleted meanwhile are skipped.
+    fn get_json_in_order<'a, T: DeserializeOwned + 'a>(
+        &'a self,
+        keys: impl IntoIterator<Item = String> + 'a,
+    ) -> impl futures_util::Stream<Item = Result<T, HttpError>> + 'a {
+        stream::iter(keys)
+            .map(move |key| async move { self.get_json(&key).await })
+            .buffered(CONCURRENT_GETS)
+            .try_filter_map(|object| async move { Ok(object) })
+    }
+
+    /// Push the objects at `keys` that `matches` holds for onto `found` until it has `limit`.
+    async fn collect_audit(
+        &self,
+        keys: impl IntoIterator<Item = String>,
+        found: &mut Vec<AuditRecord>,
+        limit: usize,
+        matches: &(impl Fn(&AuditRecord) -> bool + Sync),
+    ) -> Result<(), HttpError> {
+        let mut records = std::pin::pin!(self.get_json_in_order(keys));
+        while found.len() < limit
+            && let Some(record) = records.try_next().await?
+        {
+            if matches(&record) {
+                found.push(record);
+            }
         }
-        Ok(keys)
+        Ok(())
+    }
+
+    async fn put_json(&self, key: &str, value: &impl Serialize) -> Result<(), HttpError> {
+        self.s3
+            .put_object()
+            .bucket(&self.s3_bucket)
+            .key(key)
+            .content_type("application/json")
+            .body(serde_json::to_vec(value).unwrap().into())
+            .send()
+            .await
+            .http_error(StatusCode::INTERNAL_SERVER_ERROR)?;
+        Ok(())
+    }
+
+    async fn get_json<T: DeserializeOwned>(&self, key: &str) -> Result<Option<T>, HttpError> {
+        let response = match self
+            .s3
+            .get_object()
+            .bucket(&self.s3_bucket)
+            .key(key)
+            .send()
+            .await
+        {
+            Ok(response) => response,
+            Err(e) if e.as_service_error().is_some_and(|e| e.is_no_such_key()) => return Ok(None),
+            Err(e) => return Err(e).http_error(StatusCode::INTERNAL_SERVER_ERROR),
+        };
+        let body = response
+            .body
+            .collect()
+            .await
+            .http_error(StatusCode::INTERNAL_SERVER_ERROR)?
+            .into_bytes();
+        serde_json::from_slice(body.as_bytes()).http_error(StatusCode::INTERNAL_SERVER_ERROR)
+    }
+
+    /// Every JSON object under `prefix`; objects deleted while listing are skipped.
+    async fn list_json<T: DeserializeOwned>(&self, prefix: &str) -> Result<Vec<T>, HttpError> {
+        self.list_json_after(prefix, None).await
+    }
+
+    async fn list_json_after<T: DeserializeOwned>(
+        &self,
+        prefix: &str,
+        start_after: Option<String>,
+    ) -> Result<Vec<T>, HttpError> {
+        let keys = match self.list_s3_keys_after(prefix, start_after).await {
+            Ok(keys) => keys,
+            Err(e) if e.error_type == StatusCode::NOT_FOUND => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+        self.get_json_in_order(keys).try_collect().await
+    }
+
+    async fn delete_object(&self, key: &str) -> Result<(), HttpError> {
+        self.s3
+            .delete_object()
+            .bucket(&self.s3_bucket)
+            .key(key)
+            .send()
+            .await
+            .http_error(StatusCode::INTERNAL_SERVER_ERROR)?;
+        Ok(())
     }
 
     async fn check_object_existance(&self, key: &str) -> bool {
@@ -170,6 +275,27 @@ impl super::Store for AwsStore {
         Ok(())
     }
 
+    async fn delete_version(
+        &self,
+        name: &CrateName,
+        version: semver::Version,
+    ) -> Result<(), HttpError> {
+        let key = format!("index/{}/{version}", name.normalized);
+        if !self.check_object_existance(&key).await {
+            return Err(HttpError {
+                error_type: StatusCode::NOT_FOUND,
+                message: "no such version".to_string(),
+                verbose_message: format!("{}/{version} does not exist", name.original),
+                contexts: Vec::new(),
+                retry_after: None,
+            });
+        }
+        // the index entry goes first so that no version is listed without its archive
+        self.delete_object(&key).await?;
+        self.delete_object(&format!("crate/{}/{version}", name.normalized))
+            .await
+    }
+
     async fn get_crate(
         &self,
         name: &CrateName,
@@ -197,9 +323,10 @@ impl super::Store for AwsStore {
         {
             return Err(HttpError {
                 error_type: StatusCode::BAD_REQUEST,
-                message: format!("already exists"),
+                message: "already exists".to_string(),
                 verbose_message: format!("{}/{} already exists", index.name.original, index.vers),
                 contexts: Vec::new(),
+                retry_after: None,
             });
         }
         let index = GetIndexResponse::new(index, &body);
@@ -209,6 +336,13 @@ impl super::Store for AwsStore {
         Ok(())
     }
 
+    async fn put_replica(&self, index: &GetIndexResponse, body: Vec<u8>) -> Result<(), HttpError> {
+        // the archive goes first so that the index never names a missing one
+        self.put_crate_archive(&index.name, &index.vers, body)
+            .await?;
+        self.put_index_entry(index).await
+    }
+
     async fn get_index(&self, name: &CrateName) -> Result<Vec<GetIndexResponse>, HttpError> {
         let indices = self
             .list_s3_keys(&format!("index/{}/", name.normalized))
@@ -289,6 +423,116 @@ impl super::Store for AwsStore {
             message: "search is unsupported".into(),
             verbose_message: "search is unsupported".into(),
             contexts: Default::default(),
+            retry_after: None,
         })
     }
+
+    async fn put_token(&self, token: &TokenRecord) -> Result<(), HttpError> {
+        self.put_json(&format!("token/{}", token.id), token).await
+    }
+
+    async fn get_token(&self, id: &str) -> Result<Option<TokenRecord>, HttpError> {
+        self.get_json(&format!("token/{id}")).await
+    }
+
+    async fn list_tokens(&self) -> Result<Vec<TokenRecord>, HttpError> {
+        self.list_json("token/").await
+    }
+
+    async fn delete_token(&self, id: &str) -> Result<(), HttpError> {
+        self.delete_object(&format!("token/{id}")).await
+    }
+
+    async fn put_key(&self, key: &KeyRecord) -> Result<(), HttpError> {
+        self.put_json(&format!("key/{}", key.kid), key).await
+    }
+
+    async fn get_key(&self, kid: &str) -> Result<Option<KeyRecord>, HttpError> {
+        self.get_json(&format!("key/{kid}")).await
+    }
+
+    async fn list_keys(&self) -> Result<Vec<KeyRecord>, HttpError> {
+        self.list_json("key/").await
+    }
+
+    async fn delete_key(&self, kid: &str) -> Result<(), HttpError> {
+        self.delete_object(&format!("key/{kid}")).await
+    }
+
+    async fn put_blocked(&self, blocked: &BlockedName) -> Result<(), HttpError> {
+        self.put_json(&format!("blocked/{}", blocked.name), blocked)
+            .await
+    }
+
+    async fn get_blocked(&self, name: &CrateName) -> Result<Option<BlockedName>, HttpError> {
+        self.get_json(&format!("blocked/{}", name.normalized)).await
+    }
+
+    async fn list_blocked(&self) -> Result<Vec<BlockedName>, HttpError> {
+        self.list_json("blocked/").await
+    }
+
+    async fn delete_blocked(&self, name: &CrateName) -> Result<(), HttpError> {
+        self.delete_object(&format!("blocked/{}", name.normalized))
+            .await
+    }
+
+    async fn put_freeze(&self, freeze: &FreezeWindow) -> Result<(), HttpError> {
+        self.put_json(&format!("freeze/{}", freeze.id), freeze)
+            .await
+    }
+
+    async fn list_freezes(&self) -> Result<Vec<FreezeWindow>, HttpError> {
+        self.list_json("freeze/").await
+    }
+
+    async fn delete_freeze(&self, id: &str) -> Result<(), HttpError> {
+        self.delete_object(&format!("freeze/{id}")).await
+    }
+
+    async fn put_audit(&self, record: &AuditRecord) -> Result<(), HttpError> {
+        self.put_json(&format!("audit/{}", record.id), record).await
+    }
+
+    async fn list_audit(
+        &self,
+        since: Option<u64>,
+        order: AuditOrder,
+        limit: usize,
+        matches: impl Fn(&AuditRecord) -> bool + Send + Sync,
+    ) -> Result<Vec<AuditRecord>, HttpError> {
+        // S3 lists keys in lexical order, which is the order of the IDs
+        let start_after = since.map(|since| format!("audit/{since:020}"));
+        let mut found = Vec::new();
+        match order {
+            AuditOrder::OldestFirst => {
+                // a page at a time, so that the listing stops with the last record needed
+                let mut page = self.list_s3_page("audit/", start_after, None).await;
+                loop {
+                    let (keys, next) = match page {
+                        Ok(page) => page,
+                        Err(e) if e.error_type == StatusCode::NOT_FOUND => break,
+                        Err(e) => return Err(e),
+                    };
+                    self.collect_audit(keys, &mut found, limit, &matches)
+                        .await?;
+                    let Some(next) = next.filter(|_| found.len() < limit) else {
+                        break;
+                    };
+                    page = self.list_s3_page("audit/", None, Some(next)).await;
+                }
+            }
+            // S3 cannot list backwards, but only the keys are read before the newest records
+            AuditOrder::NewestFirst => {
+                let keys = match self.list_s3_keys_after("audit/", start_after).await {
+                    Ok(keys) => keys,
+                    Err(e) if e.error_type == StatusCode::NOT_FOUND => Vec::new(),
+                    Err(e) => return Err(e),
+                };
+                self.collect_audit(keys.into_iter().rev(), &mut found, limit, &matches)
+                    .await?;
+            }
+        }
+        Ok(found)
+    }
 }
@@ -2,13 +2,23 @@ use futures_util::Future;
 
 use crate::{
     HttpError,
+    admin::{BlockedName, FreezeWindow},
     api_schema::{
         CrateName, GetIndexResponse, PostIndexRequest, QueriedPackage, SearchCratesQuery,
     },
+    audit::AuditRecord,
+    auth::{paseto::KeyRecord, token::TokenRecord},
 };
 
 pub mod aws;
 
+/// In which order [`Store::list_audit`] goes through the records.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AuditOrder {
+    OldestFirst,
+    NewestFirst,
+}
+
 pub trait Store {
     fn health_check(&self) -> impl Future<Output = Result<(), HttpError>> + Send;
     fn put(
@@ -16,6 +26,12 @@ pub trait Store {
         index: &PostIndexRequest,
         body: Vec<u8>,
     ) -> impl Future<Output = Result<(), HttpError>> + Send;
+    /// Store a version exactly as another registry indexed it, e.g. on a follower.
+    fn put_replica(
+        &self,
+        index: &GetIndexResponse,
+        body: Vec<u8>,
+    ) -> impl Future<Output = Result<(), HttpError>> + Send;
     fn get_index(
         &self,
         name: &CrateName,
@@ -26,6 +42,12 @@ pub trait Store {
         version: semver::Version,
         yanked: bool,
     ) -> impl Future<Output = Result<(), HttpError>> + Send;
+    /// Remove a version from the index along with its archive.
+    fn delete_version(
+        &self,
+        name: &CrateName,
+        version: semver::Version,
+    ) -> impl Future<Output = Result<(), HttpError>> + Send;
     fn get_crate(
         &self,
         name: &CrateName,
@@ -49,4 +71,49 @@ pub trait Store {
         &self,
         query: &SearchCratesQuery,
     ) -> impl Future<Output = Result<(Vec<QueriedPackage>, usize), HttpError>> + Send;
+    fn put_token(&self, token: &TokenRecord) -> impl Future<Output = Result<(), HttpError>> + Send;
+    fn get_token(
+        &self,
+        id: &str,
+    ) -> impl Future<Output = Result<Option<TokenRecord>, HttpError>> + Send;
+    fn list_tokens(&self) -> impl Future<Output = Result<Vec<TokenRecord>, HttpError>> + Send;
+    fn delete_token(&self, id: &str) -> impl Future<Output = Result<(), HttpError>> + Send;
+    fn put_key(&self, key: &KeyRecord) -> impl Future<Output = Result<(), HttpError>> + Send;
+    fn get_key(
+        &self,
+        kid: &str,
+    ) -> impl Future<Output = Result<Option<KeyRecord>, HttpError>> + Send;
+    fn list_keys(&self) -> impl Future<Output = Result<Vec<KeyRecord>, HttpError>> + Send;
+    fn delete_key(&self, kid: &str) -> impl Future<Output = Result<(), HttpError>> + Send;
+    fn put_blocked(
+        &self,
+        blocked: &BlockedName,
+    ) -> impl Future<Output = Result<(), HttpError>> + Send;
+    fn get_blocked(
+        &self,
+        name: &CrateName,
+    ) -> impl Future<Output = Result<Option<BlockedName>, HttpError>> + Send;
+    fn list_blocked(&self) -> impl Future<Output = Result<Vec<BlockedName>, HttpError>> + Send;
+    fn delete_blocked(
+        &self,
+        name: &CrateName,
+    ) -> impl Future<Output = Result<(), HttpError>> + Send;
+    fn put_freeze(
+        &self,
+        freeze: &FreezeWindow,
+    ) -> impl Future<Output = Result<(), HttpError>> + Send;
+    fn list_freezes(&self) -> impl Future<Output = Result<Vec<FreezeWindow>, HttpError>> + Send;
+    fn delete_freeze(&self, id: &str) -> impl Future<Output = Result<(), HttpError>> + Send;
+    /// Records are only ever added, never changed.
+    fn put_audit(&self, record: &AuditRecord)
+    -> impl Future<Output = Result<(), HttpError>> + Send;
+    /// The first `limit` records in `order` that were written at or after `since` and that
+    /// `matches` holds for. Records past the last one returned are not read.
+    fn list_audit(
+        &self,
+        since: Option<u64>,
+        order: AuditOrder,
+        limit: usize,
+        matches: impl Fn(&AuditRecord) -> bool + Send + Sync,
+    ) -> impl Future<Output = Result<Vec<AuditRecord>, HttpError>> + Send;
 }
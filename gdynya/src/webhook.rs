@@ -0,0 +1,259 @@
+//! Outgoing webhooks for publishes, yanks and owner changes.
+//!
+//! Each delivery is a JSON [`Event`] POSTed to the hook's URL with
+//! `X-Gdynya-Signature: sha256=<hex HMAC-SHA256 of the body under the hook's secret>`.
+//! Anything but a 2xx response is retried with exponential backoff, and every attempt is
+//! kept in a bounded in-memory delivery log.
+
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use digest::Mac;
+use hmac::Hmac;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use tracing::{info, warn};
+
+use crate::{
+    audit::{Action, AuditRecord, Outcome},
+    auth::{
+        now,
+        rules::{glob_match, normalize},
+    },
+};
+
+pub const SIGNATURE_HEADER: &str = "X-Gdynya-Signature";
+pub const EVENT_HEADER: &str = "X-Gdynya-Event";
+pub const DELIVERY_HEADER: &str = "X-Gdynya-Delivery";
+
+/// Attempts kept in the delivery log.
+const LOG_CAPACITY: usize = 1000;
+/// Longest wait between two attempts.
+const MAX_BACKOFF: Duration = Duration::from_secs(10 * 60);
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum EventKind {
+    Publish,
+    Yank,
+    Unyank,
+    /// Owners added, removed or replaced.
+    Owners,
+}
+
+impl EventKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Publish => "publish",
+            Self::Yank => "yank",
+            Self::Unyank => "unyank",
+            Self::Owners => "owners",
+        }
+    }
+
+    fn of(action: Action) -> Option<Self> {
+        match action {
+            Action::Publish => Some(Self::Publish),
+            Action::Yank => Some(Self::Yank),
+            Action::Unyank => Some(Self::Unyank),
+            Action::AddOwners | Action::RemoveOwners | Action::SetOwners => Some(Self::Owners),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct WebhookConfig {
+    pub url: String,
+    /// Key of the HMAC in the signature header.
+    pub secret: String,
+    /// Crate names or globs such as `acme-*`; every crate when empty.
+    #[serde(default)]
+    pub crates: Vec<String>,
+    /// Every event when empty.
+    #[serde(default)]
+    pub events: Vec<EventKind>,
+    #[serde(default = "default_max_attempts")]
+    pub max_attempts: u32,
+}
+
+fn default_max_attempts() -> u32 {
+    6
+}
+
+impl WebhookConfig {
+    fn wants(&self, event: &Event) -> bool {
+        (self.events.is_empty() || self.events.contains(&event.event))
+            && (self.crates.is_empty()
+                || self
+                    .crates
+                    .iter()
+                    .any(|pattern| glob_match(&normalize(pattern), &event.crate_name)))
+    }
+}
+
+/// Payload of a delivery.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Event {
+    /// ID of the audit record of the change.
+    pub id: String,
+    pub event: EventKind,
+    pub action: Action,
+    #[serde(rename = "crate")]
+    pub crate_name: String,
+    pub version: Option<semver::Version>,
+    /// Owners added, removed or set, as given in the request.
+    pub detail: Option<String>,
+    pub login: Option<String>,
+    pub admin: bool,
+    pub at: u64,
+}
+
+impl Event {
+    /// The event a successful change announces, if any.
+    pub fn of(record: &AuditRecord) -> Option<Self> {
+        if record.outcome != Outcome::Success {
+            return None;
+        }
+        Some(Self {
+            id: record.id.clone(),
+            event: EventKind::of(record.action)?,
+            action: record.action,
+            crate_name: record.crate_name.clone()?,
+            version: record.version.clone(),
+            detail: record.detail.clone(),
+            login: record.login.clone(),
+            admin: record.admin,
+            at: record.at,
+        })
+    }
+}
+
+/// One attempt to deliver one event to one hook.
+#[derive(Serialize, Clone, Debug)]
+pub struct Delivery {
+    pub event_id: String,
+    pub event: EventKind,
+    pub url: String,
+    /// 1 for the first attempt.
+    pub attempt: u32,
+    pub at: u64,
+    pub status: Option<u16>,
+    pub error: Option<String>,
+    pub delivered: bool,
+}
+
+/// `sha256=` and the hex HMAC-SHA256 of `body`, as sent in [`SIGNATURE_HEADER`].
+pub fn signature(secret: &str, body: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("any key length");
+    mac.update(body);
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+#[derive(Clone)]
+pub struct Webhooks {
+    hooks: Arc<Vec<WebhookConfig>>,
+    client: reqwest::Client,
+    backoff: Duration,
+    log: Arc<Mutex<VecDeque<Delivery>>>,
+}
+
+impl Webhooks {
+    pub fn new(hooks: Vec<WebhookConfig>) -> Self {
+        Self {
+            hooks: Arc::new(hooks),
+            client: reqwest::Client::builder()
+                .timeout(Duration::from_secs(10))
+                .build()
+                .expect("static client settings"),
+            backoff: Duration::from_secs(1),
+            log: Default::default(),
+        }
+    }
+
+    /// Wait before the first retry; doubled for each one after.
+    pub fn with_backoff(self, backoff: Duration) -> Self {
+        Self { backoff, ..self }
+    }
+
+    /// Send `event` to every hook that wants it, in the background.
+    pub fn emit(&self, event: Event) {
+        for hook in self.hooks.iter().filter(|hook| hook.wants(&event)) {
+            let webhooks = self.clone();
+            let hook = hook.clone();
+            let event = event.clone();
+            tokio::spawn(async move { webhooks.deliver(&hook, &event).await });
+        }
+    }
+
+    /// Most recent attempts first.
+    pub fn deliveries(&self) -> Vec<Delivery> {
+        self.log.lock().unwrap().iter().rev().cloned().collect()
+    }
+
+    async fn deliver(&self, hook: &WebhookConfig, event: &Event) {
+        let body = serde_json::to_vec(event).expect("events serialize");
+        let signature = signature(&hook.secret, &body);
+        let mut backoff = self.backoff;
+        for attempt in 1..=hook.max_attempts.max(1) {
+            let response = self
+                .client
+                .post(&hook.url)
+                .header(reqwest::header::CONTENT_TYPE, "application/json")
+                .header(SIGNATURE_HEADER, &signature)
+                .header(EVENT_HEADER, event.event.as_str())
+                .header(DELIVERY_HEADER, &event.id)
+                .body(body.clone())
+                .send()
+                .await;
+            let (status, error) = match response {
+                Ok(response) if response.status().is_success() => {
+                    (Some(response.status().as_u16()), None)
+                }
+                Ok(response) => (
+                    Some(response.status().as_u16()),
+                    Some(format!("responded with {}", response.status())),
+                ),
+                Err(e) => (None, Some(e.to_string())),
+            };
+            let delivered = error.is_none();
+            self.record(Delivery {
+                event_id: event.id.clone(),
+                event: event.event,
+                url: hook.url.clone(),
+                attempt,
+                at: now(),
+                status,
+                error: error.clone(),
+                delivered,
+            });
+            if delivered {
+                info!(url = hook.url, id = event.id, attempt, "webhook_delivered");
+                return;
+            }
+            warn!(
+                url = hook.url,
+                id = event.id,
+                attempt,
+                error,
+                "webhook_failed"
+            );
+            if attempt < hook.max_attempts {
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(MAX_BACKOFF);
+            }
+        }
+    }
+
+    fn record(&self, delivery: Delivery) {
+        let mut log = self.log.lock().unwrap();
+        if log.len() == LOG_CAPACITY {
+            log.pop_front();
+        }
+        log.push_back(delivery);
+    }
+}
@@ -0,0 +1,151 @@
+//! Audit records: outcomes, filtering and export.
+
+mod common;
+
+use axum::http::StatusCode;
+use common::{MemoryStore, name};
+use futures_util::StreamExt;
+use gdynya::{
+    HttpError,
+    audit::{self, Action, AuditFilter, AuditRecord, EXPORT_PAGE, Outcome, to_jsonl},
+    auth::{Principal, Provider},
+    store::Store,
+};
+
+fn denied() -> Result<(), HttpError> {
+    Err(HttpError {
+        error_type: StatusCode::FORBIDDEN,
+        message: "token gdy_0011_secret is not scoped for this".to_string(),
+        verbose_message: String::new(),
+        contexts: Vec::new(),
+        retry_after: None,
+    })
+}
+
+#[test]
+fn records_say_who_did_what_and_how_it_ended() {
+    let alice = Principal::new(Provider::RegistryToken, "alice").with_token_id("0011");
+    let vers = semver::Version::new(1, 2, 3);
+    let record = AuditRecord::new(Action::Yank)
+        .from_ip(Some("192.0.2.1".to_string()))
+        .with_crate(&name("foo_bar"))
+        .with_version(&vers)
+        .by(&alice)
+        .with_result(&denied());
+    assert_eq!(record.login.as_deref(), Some("alice"));
+    assert_eq!(record.token_id.as_deref(), Some("0011"));
+    assert_eq!(record.crate_name.as_deref(), Some("foo-bar"));
+    assert_eq!(record.outcome, Outcome::Denied);
+    assert_eq!(
+        record.error.as_deref(),
+        Some("token [REDACTED] is not scoped for this")
+    );
+    let ok = record.clone().with_result(&Ok(()));
+    assert_eq!((ok.outcome, ok.error), (Outcome::Success, None));
+
+    // one line per record, and the lines read back
+    let jsonl = to_jsonl(&[record.clone(), record.clone()]);
+    let lines = jsonl.lines().collect::<Vec<_>>();
+    assert_eq!(lines.len(), 2);
+    assert_eq!(
+        serde_json::from_str::<AuditRecord>(lines[0]).unwrap(),
+        record
+    );
+}
+
+#[test]
+fn filters_combine() {
+    let alice = Principal::new(Provider::GitHub, "alice");
+    let bob = Principal::new(Provider::GitHub, "bob");
+    let records = [
+        AuditRecord::new(Action::Publish)
+            .with_crate(&name("foo"))
+            .by(&alice),
+        AuditRecord::new(Action::Yank)
+            .with_crate(&name("foo"))
+            .by(&bob)
+            .with_result(&denied()),
+        AuditRecord::new(Action::Block)
+            .by_admin()
+            .with_crate(&name("bar"))
+            .by(&alice),
+    ];
+    let count = |filter: AuditFilter| records.iter().filter(|r| filter.matches(r)).count();
+    assert_eq!(count(AuditFilter::default()), 3);
+    assert_eq!(
+        count(AuditFilter {
+            crate_name: Some(name("foo")),
+            ..Default::default()
+        }),
+        2
+    );
+    assert_eq!(
+        count(AuditFilter {
+            login: Some("alice".to_string()),
+            admin: Some(false),
+            ..Default::default()
+        }),
+        1
+    );
+    assert_eq!(
+        count(AuditFilter {
+            outcome: Some(Outcome::Denied),
+            action: Some(Action::Yank),
+            ..Default::default()
+        }),
+        1
+    );
+    let at = records[0].at;
+    assert_eq!(
+        count(AuditFilter {
+            until: Some(at),
+            ..Default::default()
+        }),
+        0
+    );
+}
+
+#[tokio::test]
+async fn exports_read_the_log_a_page_at_a_time() {
+    let store = MemoryStore::default();
+    // all from the same second, so pages have to resume by ID
+    let mut written = Vec::new();
+    for i in 0..EXPORT_PAGE * 2 + 10 {
+        let action = if i % 2 == 0 {
+            Action::Publish
+        } else {
+            Action::Yank
+        };
+        let record = AuditRecord::new(action).finished();
+        store.put_audit(&record).await.unwrap();
+        written.push(record);
+    }
+    written.sort_by(|a, b| a.id.cmp(&b.id));
+    let export = |filter, limit| {
+        audit::export(store.clone(), filter, limit)
+            .map(Result::unwrap)
+            .collect::<Vec<_>>()
+    };
+
+    let pages = export(AuditFilter::default(), None).await;
+    assert_eq!(
+        pages.iter().map(Vec::len).collect::<Vec<_>>(),
+        [EXPORT_PAGE, EXPORT_PAGE, 10]
+    );
+    assert_eq!(pages.concat(), written);
+
+    let pages = export(AuditFilter::default(), Some(EXPORT_PAGE + 1)).await;
+    assert_eq!(pages.concat(), written[..EXPORT_PAGE + 1]);
+
+    let yanks = AuditFilter {
+        action: Some(Action::Yank),
+        ..Default::default()
+    };
+    let pages = export(yanks.clone(), None).await;
+    let expected = written
+        .iter()
+        .filter(|record| yanks.matches(record))
+        .cloned()
+        .collect::<Vec<_>>();
+    assert_eq!(pages.concat(), expected);
+}
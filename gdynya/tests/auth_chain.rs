@@ -0,0 +1,238 @@
+//! Providers chained by token format, each resolving who the request acts for.
+
+mod common;
+
+use axum::{Json, Router, http::StatusCode, routing};
+use common::{MemoryStore, name, token};
+use digest::Digest;
+use gdynya::{
+    auth::{
+        Auth, Authenticator, Mutation, Permissions, Principal, Provider, Scope,
+        github::{GitHubApi, GitHubAuth},
+        rules::AuthRules,
+        service::ServiceAccounts,
+        token::{CreateTokenRequest, RegistryTokenAuth, TokenRecord},
+    },
+    store::Store,
+};
+use serde_json::json;
+use sha2::Sha256;
+use tokio::net::TcpListener;
+
+const RULES: &str = r#"
+version: 2
+crates:
+  public:
+    read: !anyone
+    write: !is {user: alice}
+  deployed:
+    read: !service {name: deploy-bot}
+    write: !service {name: deploy-bot}
+  impersonated:
+    read: !is {user: deploy-bot}
+    write: !is {user: deploy-bot}
+"#;
+
+const REGISTRY_TOKEN: &str = "gdy_0011223344556677_secret";
+const SERVICE_TOKEN: &str = "gds_deploy";
+
+fn sha256(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+async fn chain() -> impl Auth {
+    let github = GitHubAuth::new_from_config(AuthRules::from_yaml(RULES).unwrap())
+        .with_service_token("service-token");
+    let store = MemoryStore::default();
+    store
+        .put_token(&TokenRecord {
+            id: "0011223344556677".to_string(),
+            owner: "alice".to_string(),
+            name: "ci".to_string(),
+            hash: sha256(REGISTRY_TOKEN),
+            endpoint_scopes: vec![Scope::PublishUpdate],
+            crate_scopes: Vec::new(),
+            created_at: 0,
+            expires_at: None,
+        })
+        .await
+        .unwrap();
+    let accounts = ServiceAccounts::from_yaml(
+        &format!("deploy-bot: {}", sha256(SERVICE_TOKEN)),
+        github.clone(),
+    )
+    .unwrap();
+    RegistryTokenAuth::new(store, github.clone())
+        .or(accounts)
+        .or(github)
+}
+
+#[tokio::test]
+async fn tokens_are_routed_by_format() {
+    let auth = chain().await;
+    let vers = semver::Version::new(1, 0, 0);
+    let publish = Mutation::Publish {
+        vers: &vers,
+        cksum: "00",
+        new: false,
+    };
+
+    let principal = auth
+        .writable(&token(REGISTRY_TOKEN), &name("public"), &publish)
+        .await
+        .unwrap();
+    assert_eq!(principal.provider, Provider::RegistryToken);
+    assert_eq!(principal.login.as_deref(), Some("alice"));
+
+    let principal = auth
+        .writable(&token(SERVICE_TOKEN), &name("deployed"), &publish)
+        .await
+        .unwrap();
+    assert_eq!(
+        principal,
+        Principal::new(Provider::ServiceAccount, "deploy-bot")
+    );
+    let result = auth
+        .writable(&token(SERVICE_TOKEN), &name("public"), &publish)
+        .await;
+    assert_eq!(result.unwrap_err().error_type, StatusCode::FORBIDDEN);
+
+    let principal = auth.readable(None, &name("public")).await.unwrap();
+    assert_eq!(principal, Principal::anonymous());
+}
+
+#[tokio::test]
+async fn principal_and_permissions_are_introspectable() {
+    let auth = chain().await;
+    let principal = auth
+        .authenticate(Some(&token(REGISTRY_TOKEN)))
+        .await
+        .unwrap();
+    assert_eq!(principal.token_id.as_deref(), Some("0011223344556677"));
+    assert_eq!(principal.scopes, Some(vec![Scope::PublishUpdate]));
+
+    // the rules allow every write, the token only updates
+    let permissions = auth
+        .permissions(Some(&token(REGISTRY_TOKEN)), &name("public"))
+        .await
+        .unwrap();
+    assert_eq!(
+        permissions,
+        Permissions {
+            read: true,
+            download: true,
+            write: vec![Scope::PublishUpdate],
+        }
+    );
+    let permissions = auth
+        .permissions(Some(&token(SERVICE_TOKEN)), &name("public"))
+        .await
+        .unwrap();
+    assert_eq!(
+        permissions,
+        Permissions {
+            read: true,
+            download: true,
+            write: Vec::new(),
+        }
+    );
+    let permissions = auth.permissions(None, &name("deployed")).await.unwrap();
+    assert!(!permissions.any());
+}
+
+#[tokio::test]
+async fn claimed_tokens_do_not_fall_through() {
+    let auth = chain().await;
+    // GitHub would be asked about these if the chain moved on
+    for unknown in ["gds_unknown", "gdy_0011223344556677_wrong"] {
+        let result = auth.readable(Some(&token(unknown)), &name("public")).await;
+        assert_eq!(result.unwrap_err().error_type, StatusCode::UNAUTHORIZED);
+    }
+}
+
+#[tokio::test]
+async fn service_accounts_are_not_github_logins() {
+    let auth = chain().await;
+    // nothing asks GitHub whether `deploy-bot` is a user, it would be refused if it did
+    let result = auth
+        .readable(Some(&token(SERVICE_TOKEN)), &name("impersonated"))
+        .await;
+    assert_eq!(result.unwrap_err().error_type, StatusCode::FORBIDDEN);
+    let admins = ["deploy-bot".to_string()];
+    assert!(!Principal::new(Provider::ServiceAccount, "deploy-bot").is_admin(&admins));
+    assert!(Principal::new(Provider::GitHub, "deploy-bot").is_admin(&admins));
+
+    // and a GitHub user of the same name is not the service account
+    let github = GitHubAuth::new_from_config(AuthRules::from_yaml(RULES).unwrap())
+        .with_service_token("service-token");
+    let store = MemoryStore::default();
+    store
+        .put_token(&TokenRecord {
+            id: "8899aabbccddeeff".to_string(),
+            owner: "deploy-bot".to_string(),
+            name: "ci".to_string(),
+            hash: sha256("gdy_8899aabbccddeeff_secret"),
+            endpoint_scopes: vec![Scope::PublishUpdate],
+            crate_scopes: Vec::new(),
+            created_at: 0,
+            expires_at: None,
+        })
+        .await
+        .unwrap();
+    let tokens = RegistryTokenAuth::new(store, github);
+    let result = tokens
+        .readable(
+            Some(&token("gdy_8899aabbccddeeff_secret")),
+            &name("deployed"),
+        )
+        .await;
+    assert_eq!(result.unwrap_err().error_type, StatusCode::FORBIDDEN);
+}
+
+#[test]
+fn service_account_hashes_are_validated() {
+    let github = GitHubAuth::new_from_config(AuthRules::default());
+    assert!(ServiceAccounts::from_yaml("deploy-bot: not-a-hash", github.clone()).is_err());
+    let hash = sha256(SERVICE_TOKEN);
+    let duplicate = format!("a: {hash}\nb: {hash}");
+    assert!(ServiceAccounts::from_yaml(&duplicate, github).is_err());
+}
+
+#[tokio::test]
+async fn token_lifetimes_that_overflow_are_rejected() {
+    // GitHub only needs to say whose token it is
+    let app = Router::new().route(
+        "/user",
+        routing::get(|| async { Json(json!({ "login": "alice" })) }),
+    );
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+    let github = GitHubAuth::new_from_config(AuthRules::from_yaml(RULES).unwrap())
+        .with_api(GitHubApi::new(format!("http://{addr}")));
+    let tokens = RegistryTokenAuth::new(MemoryStore::default(), github);
+    let request = |expires_in_days| CreateTokenRequest {
+        name: "ci".to_string(),
+        endpoint_scopes: vec![Scope::PublishUpdate],
+        crate_scopes: Vec::new(),
+        expires_in_days,
+    };
+
+    let created = tokens
+        .create(&token("alice-token"), request(Some(30)))
+        .await
+        .unwrap();
+    assert_eq!(
+        created.view.expires_at,
+        Some(created.view.created_at + 30 * 24 * 60 * 60)
+    );
+    for days in [u64::MAX / (24 * 60 * 60) + 1, u64::MAX / (24 * 60 * 60)] {
+        let Err(e) = tokens
+            .create(&token("alice-token"), request(Some(days)))
+            .await
+        else {
+            panic!("{days} days overflow");
+        };
+        assert_eq!(e.error_type, StatusCode::BAD_REQUEST);
+    }
+}
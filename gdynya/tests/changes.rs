@@ -0,0 +1,150 @@
+//! The change feed read back from the audit log.
+
+mod common;
+
+use std::time::Duration;
+
+use axum::http::StatusCode;
+use common::{MemoryStore, name};
+use futures_util::StreamExt;
+use gdynya::{
+    HttpError,
+    audit::{Action, AuditRecord},
+    changes::{Change, ChangeKind, FOLLOW_PAGE, changes_after, follow},
+    store::Store,
+};
+
+fn now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+fn at(record: AuditRecord, at: u64, suffix: &str) -> AuditRecord {
+    AuditRecord {
+        id: format!("{at:020}-{suffix}"),
+        at,
+        ..record
+    }
+}
+
+#[tokio::test]
+async fn follows_version_changes_from_a_cursor() {
+    let store = MemoryStore::default();
+    let vers = semver::Version::new(1, 0, 0);
+    let failed: Result<(), HttpError> = Err(HttpError {
+        error_type: StatusCode::CONFLICT,
+        message: "already published".to_string(),
+        verbose_message: String::new(),
+        contexts: Vec::new(),
+        retry_after: None,
+    });
+    let then = now() - 100;
+    let records = [
+        at(
+            AuditRecord::new(Action::Publish)
+                .with_crate(&name("foo"))
+                .with_version(&vers),
+            then,
+            "00000001",
+        ),
+        // not about a version
+        at(
+            AuditRecord::new(Action::AddOwners).with_crate(&name("foo")),
+            then,
+            "00000002",
+        ),
+        at(
+            AuditRecord::new(Action::Publish)
+                .with_crate(&name("foo"))
+                .with_version(&vers)
+                .with_result(&failed),
+            then + 1,
+            "00000001",
+        ),
+        at(
+            AuditRecord::new(Action::Yank)
+                .with_crate(&name("foo"))
+                .with_version(&vers),
+            then + 1,
+            "00000002",
+        ),
+        at(
+            AuditRecord::new(Action::DeleteVersion)
+                .by_admin()
+                .with_crate(&name("foo"))
+                .with_version(&vers),
+            then + 2,
+            "00000001",
+        ),
+        // too recent to be settled
+        at(
+            AuditRecord::new(Action::Unyank)
+                .with_crate(&name("foo"))
+                .with_version(&vers),
+            now() + 10,
+            "00000001",
+        ),
+    ];
+    for record in &records {
+        store.put_audit(record).await.unwrap();
+    }
+
+    let kinds = |changes: &[Change]| changes.iter().map(|c| c.kind).collect::<Vec<_>>();
+    let all = changes_after(&store, None, 100).await.unwrap();
+    assert_eq!(
+        kinds(&all),
+        [ChangeKind::Publish, ChangeKind::Yank, ChangeKind::Delete]
+    );
+    assert_eq!(all[0].crate_name, "foo");
+    assert_eq!(all[0].version, vers);
+
+    let rest = changes_after(&store, Some(&all[0].id), 100).await.unwrap();
+    assert_eq!(kinds(&rest), [ChangeKind::Yank, ChangeKind::Delete]);
+    // records that are not changes do not count against the limit
+    let first = changes_after(&store, None, 1).await.unwrap();
+    assert_eq!(kinds(&first), [ChangeKind::Publish]);
+    let next = changes_after(&store, Some(&first[0].id), 1).await.unwrap();
+    assert_eq!(kinds(&next), [ChangeKind::Yank]);
+    assert!(
+        changes_after(&store, Some(&all[2].id), 100)
+            .await
+            .unwrap()
+            .is_empty()
+    );
+}
+
+#[tokio::test]
+async fn following_replays_in_pages_then_picks_up_new_changes() {
+    let store = MemoryStore::default();
+    let vers = semver::Version::new(1, 0, 0);
+    let publish = || {
+        AuditRecord::new(Action::Publish)
+            .with_crate(&name("foo"))
+            .with_version(&vers)
+    };
+    let then = now() - 100;
+    let mut written = Vec::new();
+    for i in 0..FOLLOW_PAGE * 2 + 5 {
+        let record = at(publish(), then, &format!("{i:08}"));
+        store.put_audit(&record).await.unwrap();
+        written.push(record.id);
+    }
+    let interval = Duration::from_millis(50);
+    let timeout = Duration::from_secs(5);
+
+    let replayed = follow(store.clone(), Some(format!("{then:020}")), interval)
+        .take(written.len())
+        .map(|change| change.id)
+        .collect::<Vec<_>>();
+    let replayed = tokio::time::timeout(timeout, replayed).await.unwrap();
+    assert_eq!(replayed, written);
+
+    // without a cursor, only what comes from now on
+    let mut live = Box::pin(follow(store.clone(), None, interval));
+    let record = publish().finished();
+    store.put_audit(&record).await.unwrap();
+    let change = tokio::time::timeout(timeout, live.next()).await.unwrap();
+    assert_eq!(change.unwrap().id, record.id);
+}
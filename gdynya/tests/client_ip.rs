@@ -0,0 +1,88 @@
+//! Which address requests are recorded as coming from.
+
+use std::{net::SocketAddr, sync::Arc};
+
+use axum::{
+    extract::{ConnectInfo, FromRequestParts},
+    http::Request,
+};
+use gdynya::axum_aux::{ClientIp, TrustedProxies};
+
+async fn client_ip(peer: &str, forwarded_for: &[&str]) -> Option<String> {
+    let mut request = Request::builder();
+    for value in forwarded_for {
+        request = request.header("X-Forwarded-For", *value);
+    }
+    let (mut parts, ()) = request.body(()).unwrap().into_parts();
+    parts.extensions.insert(ConnectInfo(
+        format!("{peer}:443").parse::<SocketAddr>().unwrap(),
+    ));
+    parts.extensions.insert(TrustedProxies(Arc::new(vec![
+        "10.0.0.0/8".parse().unwrap(),
+        "192.0.2.7/32".parse().unwrap(),
+    ])));
+    let ClientIp(ip) = ClientIp::from_request_parts(&mut parts, &()).await.unwrap();
+    ip
+}
+
+#[tokio::test]
+async fn only_trusted_proxies_are_believed() {
+    // a client talking to us directly cannot claim to be someone else
+    assert_eq!(
+        client_ip("203.0.113.5", &["198.51.100.1"]).await.as_deref(),
+        Some("203.0.113.5")
+    );
+    assert_eq!(
+        client_ip("10.1.2.3", &[]).await.as_deref(),
+        Some("10.1.2.3")
+    );
+    assert_eq!(
+        client_ip("10.1.2.3", &["203.0.113.5"]).await.as_deref(),
+        Some("203.0.113.5")
+    );
+}
+
+#[tokio::test]
+async fn the_right_most_untrusted_entry_is_the_client() {
+    // the client made up the first entry, our proxies appended the rest
+    assert_eq!(
+        client_ip("10.1.2.3", &["198.51.100.1, 203.0.113.5, 192.0.2.7"])
+            .await
+            .as_deref(),
+        Some("203.0.113.5")
+    );
+    // spread over several headers
+    assert_eq!(
+        client_ip("10.1.2.3", &["198.51.100.1", "203.0.113.5", "10.9.9.9"])
+            .await
+            .as_deref(),
+        Some("203.0.113.5")
+    );
+    // nothing but proxies
+    assert_eq!(
+        client_ip("10.1.2.3", &["10.0.0.1, 192.0.2.7"])
+            .await
+            .as_deref(),
+        Some("10.0.0.1")
+    );
+    assert_eq!(
+        client_ip("10.1.2.3", &["unknown, 10.0.0.1"])
+            .await
+            .as_deref(),
+        Some("unknown")
+    );
+}
+
+#[tokio::test]
+async fn nothing_is_trusted_by_default() {
+    let (mut parts, ()) = Request::builder()
+        .header("X-Forwarded-For", "198.51.100.1")
+        .body(())
+        .unwrap()
+        .into_parts();
+    parts
+        .extensions
+        .insert(ConnectInfo("10.1.2.3:443".parse::<SocketAddr>().unwrap()));
+    let ClientIp(ip) = ClientIp::from_request_parts(&mut parts, &()).await.unwrap();
+    assert_eq!(ip.as_deref(), Some("10.1.2.3"));
+}
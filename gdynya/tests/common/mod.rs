@@ -0,0 +1,281 @@
+//! In-memory `Store` shared by the integration tests.
+
+#![allow(dead_code)]
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    sync::{Arc, Mutex},
+};
+
+use axum::http::{HeaderValue, StatusCode};
+use gdynya::{
+    HttpError,
+    admin::{BlockedName, FreezeWindow},
+    api_schema::{
+        CrateName, GetIndexResponse, PostIndexRequest, QueriedPackage, SearchCratesQuery,
+    },
+    audit::AuditRecord,
+    auth::{paseto::KeyRecord, token::TokenRecord},
+    axum_aux::RawAuthorization,
+    store::{AuditOrder, Store},
+};
+use headers::Header;
+
+#[derive(Default)]
+struct Data {
+    index: BTreeMap<(String, semver::Version), GetIndexResponse>,
+    crates: BTreeMap<(String, semver::Version), Vec<u8>>,
+    owners: BTreeMap<String, BTreeSet<String>>,
+    tokens: BTreeMap<String, TokenRecord>,
+    keys: BTreeMap<String, KeyRecord>,
+    blocked: BTreeMap<String, BlockedName>,
+    freezes: BTreeMap<String, FreezeWindow>,
+    audit: Vec<AuditRecord>,
+}
+
+#[derive(Clone, Default)]
+pub struct MemoryStore(Arc<Mutex<Data>>);
+
+fn not_found(message: &str) -> HttpError {
+    HttpError {
+        error_type: StatusCode::NOT_FOUND,
+        message: message.to_string(),
+        verbose_message: message.to_string(),
+        contexts: Vec::new(),
+        retry_after: None,
+    }
+}
+
+impl Store for MemoryStore {
+    async fn health_check(&self) -> Result<(), HttpError> {
+        Ok(())
+    }
+
+    async fn put(&self, index: &PostIndexRequest, body: Vec<u8>) -> Result<(), HttpError> {
+        let mut data = self.0.lock().unwrap();
+        let key = (index.name.normalized.clone(), index.vers.clone());
+        if data.index.contains_key(&key) {
+            return Err(HttpError {
+                error_type: StatusCode::BAD_REQUEST,
+                ..not_found("already exists")
+            });
+        }
+        data.index
+            .insert(key.clone(), GetIndexResponse::new(index, &body));
+        data.crates.insert(key, body);
+        Ok(())
+    }
+
+    async fn put_replica(&self, index: &GetIndexResponse, body: Vec<u8>) -> Result<(), HttpError> {
+        let mut data = self.0.lock().unwrap();
+        let key = (index.name.normalized.clone(), index.vers.clone());
+        data.index.insert(key.clone(), index.clone());
+        data.crates.insert(key, body);
+        Ok(())
+    }
+
+    async fn get_index(&self, name: &CrateName) -> Result<Vec<GetIndexResponse>, HttpError> {
+        let data = self.0.lock().unwrap();
+        let index = data
+            .index
+            .iter()
+            .filter(|((crate_name, _), _)| *crate_name == name.normalized)
+            .map(|(_, index)| index.clone())
+            .collect::<Vec<_>>();
+        if index.is_empty() {
+            return Err(not_found("no index"));
+        }
+        Ok(index)
+    }
+
+    async fn set_yank(
+        &self,
+        name: &CrateName,
+        version: semver::Version,
+        yanked: bool,
+    ) -> Result<(), HttpError> {
+        let mut data = self.0.lock().unwrap();
+        let index = data
+            .index
+            .get_mut(&(name.normalized.clone(), version))
+            .ok_or_else(|| not_found("no such version"))?;
+        index.yanked = yanked;
+        Ok(())
+    }
+
+    async fn delete_version(
+        &self,
+        name: &CrateName,
+        version: semver::Version,
+    ) -> Result<(), HttpError> {
+        let mut data = self.0.lock().unwrap();
+        let key = (name.normalized.clone(), version);
+        data.index
+            .remove(&key)
+            .ok_or_else(|| not_found("no such version"))?;
+        data.crates.remove(&key);
+        Ok(())
+    }
+
+    async fn get_crate(
+        &self,
+        name: &CrateName,
+        version: semver::Version,
+    ) -> Result<Vec<u8>, HttpError> {
+        let data = self.0.lock().unwrap();
+        data.crates
+            .get(&(name.normalized.clone(), version))
+            .cloned()
+            .ok_or_else(|| not_found("no such version"))
+    }
+
+    async fn get_owners(&self, name: &CrateName) -> Result<Vec<String>, HttpError> {
+        let data = self.0.lock().unwrap();
+        Ok(data
+            .owners
+            .get(&name.normalized)
+            .map(|owners| owners.iter().cloned().collect())
+            .unwrap_or_default())
+    }
+
+    async fn add_owner(&self, name: &CrateName, owner: Vec<String>) -> Result<(), HttpError> {
+        let mut data = self.0.lock().unwrap();
+        data.owners
+            .entry(name.normalized.clone())
+            .or_default()
+            .extend(owner);
+        Ok(())
+    }
+
+    async fn delete_owner(&self, name: &CrateName, owner: Vec<String>) -> Result<(), HttpError> {
+        let mut data = self.0.lock().unwrap();
+        if let Some(owners) = data.owners.get_mut(&name.normalized) {
+            owners.retain(|existing| !owner.contains(existing));
+        }
+        Ok(())
+    }
+
+    async fn search(
+        &self,
+        _query: &SearchCratesQuery,
+    ) -> Result<(Vec<QueriedPackage>, usize), HttpError> {
+        Err(not_found("search is unsupported"))
+    }
+
+    async fn put_token(&self, token: &TokenRecord) -> Result<(), HttpError> {
+        let mut data = self.0.lock().unwrap();
+        data.tokens.insert(token.id.clone(), token.clone());
+        Ok(())
+    }
+
+    async fn get_token(&self, id: &str) -> Result<Option<TokenRecord>, HttpError> {
+        Ok(self.0.lock().unwrap().tokens.get(id).cloned())
+    }
+
+    async fn list_tokens(&self) -> Result<Vec<TokenRecord>, HttpError> {
+        Ok(self.0.lock().unwrap().tokens.values().cloned().collect())
+    }
+
+    async fn delete_token(&self, id: &str) -> Result<(), HttpError> {
+        self.0.lock().unwrap().tokens.remove(id);
+        Ok(())
+    }
+
+    async fn put_key(&self, key: &KeyRecord) -> Result<(), HttpError> {
+        let mut data = self.0.lock().unwrap();
+        data.keys.insert(key.kid.clone(), key.clone());
+        Ok(())
+    }
+
+    async fn get_key(&self, kid: &str) -> Result<Option<KeyRecord>, HttpError> {
+        Ok(self.0.lock().unwrap().keys.get(kid).cloned())
+    }
+
+    async fn list_keys(&self) -> Result<Vec<KeyRecord>, HttpError> {
+        Ok(self.0.lock().unwrap().keys.values().cloned().collect())
+    }
+
+    async fn delete_key(&self, kid: &str) -> Result<(), HttpError> {
+        self.0.lock().unwrap().keys.remove(kid);
+        Ok(())
+    }
+
+    async fn put_blocked(&self, blocked: &BlockedName) -> Result<(), HttpError> {
+        let mut data = self.0.lock().unwrap();
+        data.blocked.insert(blocked.name.clone(), blocked.clone());
+        Ok(())
+    }
+
+    async fn get_blocked(&self, name: &CrateName) -> Result<Option<BlockedName>, HttpError> {
+        Ok(self
+            .0
+            .lock()
+            .unwrap()
+            .blocked
+            .get(&name.normalized)
+            .cloned())
+    }
+
+    async fn list_blocked(&self) -> Result<Vec<BlockedName>, HttpError> {
+        Ok(self.0.lock().unwrap().blocked.values().cloned().collect())
+    }
+
+    async fn delete_blocked(&self, name: &CrateName) -> Result<(), HttpError> {
+        self.0.lock().unwrap().blocked.remove(&name.normalized);
+        Ok(())
+    }
+
+    async fn put_freeze(&self, freeze: &FreezeWindow) -> Result<(), HttpError> {
+        let mut data = self.0.lock().unwrap();
+        data.freezes.insert(freeze.id.clone(), freeze.clone());
+        Ok(())
+    }
+
+    async fn list_freezes(&self) -> Result<Vec<FreezeWindow>, HttpError> {
+        Ok(self.0.lock().unwrap().freezes.values().cloned().collect())
+    }
+
+    async fn delete_freeze(&self, id: &str) -> Result<(), HttpError> {
+        self.0.lock().unwrap().freezes.remove(id);
+        Ok(())
+    }
+
+    async fn put_audit(&self, record: &AuditRecord) -> Result<(), HttpError> {
+        self.0.lock().unwrap().audit.push(record.clone());
+        Ok(())
+    }
+
+    async fn list_audit(
+        &self,
+        since: Option<u64>,
+        order: AuditOrder,
+        limit: usize,
+        matches: impl Fn(&AuditRecord) -> bool + Send + Sync,
+    ) -> Result<Vec<AuditRecord>, HttpError> {
+        let data = self.0.lock().unwrap();
+        let mut records = data
+            .audit
+            .iter()
+            .filter(|record| since.is_none_or(|since| record.at >= since))
+            .cloned()
+            .collect::<Vec<_>>();
+        records.sort_by(|a, b| a.id.cmp(&b.id));
+        if order == AuditOrder::NewestFirst {
+            records.reverse();
+        }
+        Ok(records
+            .into_iter()
+            .filter(|record| matches(record))
+            .take(limit)
+            .collect())
+    }
+}
+
+pub fn token(value: &str) -> RawAuthorization {
+    let value = HeaderValue::from_str(value).unwrap();
+    RawAuthorization::decode(&mut std::iter::once(&value)).unwrap()
+}
+
+pub fn name(name: &str) -> CrateName {
+    name.parse().unwrap()
+}
@@ -0,0 +1,300 @@
+//! A follower replicating a primary served from the test process.
+
+mod common;
+
+use std::{collections::HashMap, net::SocketAddr, sync::Arc};
+
+use axum::{
+    Extension, Json, Router,
+    body::Body,
+    extract::{ConnectInfo, Query, State},
+    http::{HeaderMap, Method, Request, StatusCode},
+    routing,
+};
+use common::{MemoryStore, name};
+use gdynya::{
+    api_schema::{GetIndexResponse, cksum},
+    audit::{Action, AuditRecord},
+    axum_aux::{ClientIp, MAX_PUBLISH_BODY, TrustedProxies},
+    changes::changes_after,
+    follower::{Follower, FollowerConfig, is_checked_by_primary, is_forwarded},
+    store::Store,
+};
+use serde_json::{Value, json};
+use tokio::net::TcpListener;
+
+const TOKEN: &str = "gdy_follower";
+
+async fn changes(
+    State(primary): State<MemoryStore>,
+    Query(query): Query<HashMap<String, String>>,
+) -> Json<Value> {
+    let since = query.get("since").cloned();
+    let limit = query["limit"].parse().unwrap();
+    let changes = changes_after(&primary, since.as_deref(), limit)
+        .await
+        .unwrap();
+    let next = changes.last().map(|c| c.id.clone()).or(since);
+    Json(json!({ "changes": changes, "next": next }))
+}
+
+async fn index(State(primary): State<MemoryStore>) -> String {
+    let index = primary.get_index(&name("foo")).await.unwrap();
+    index
+        .iter()
+        .map(|entry| serde_json::to_string(entry).unwrap())
+        .collect()
+}
+
+async fn download(State(primary): State<MemoryStore>, headers: HeaderMap) -> (StatusCode, Vec<u8>) {
+    if headers.get("authorization").unwrap() != TOKEN {
+        return (StatusCode::FORBIDDEN, Vec::new());
+    }
+    let body = primary
+        .get_crate(&name("foo"), semver::Version::new(1, 0, 0))
+        .await
+        .unwrap();
+    (StatusCode::OK, body)
+}
+
+async fn spawn_primary(primary: MemoryStore) -> String {
+    let app = Router::new()
+        .route("/api/v1/changes", routing::get(changes))
+        .route("/api/v1/crates/foo/1.0.0", routing::get(download))
+        .route(
+            "/api/v1/crates/foo/1.0.0/yank",
+            routing::delete(|| async { (StatusCode::FORBIDDEN, "not an owner") }),
+        )
+        .route(
+            "/api/v1/crates/foo/1.0.0/unyank",
+            routing::put(|ClientIp(ip): ClientIp| async move { ip.unwrap_or_default() }),
+        )
+        .route("/3/f/foo", routing::get(index))
+        .with_state(primary)
+        // followers connect from here
+        .layer(Extension(trusted("127.0.0.0/8")));
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let app = app.into_make_service_with_connect_info::<SocketAddr>();
+        axum::serve(listener, app).await.unwrap()
+    });
+    format!("http://{addr}/")
+}
+
+fn trusted(net: &str) -> TrustedProxies {
+    TrustedProxies(Arc::new(vec![net.parse().unwrap()]))
+}
+
+/// Index entry of `foo` 1.0.0 with `body` as its archive.
+fn foo_entry(body: &[u8]) -> GetIndexResponse {
+    let entry = json!({
+        "name": "foo",
+        "vers": "1.0.0",
+        "deps": [],
+        "features": {},
+        "links": null,
+        "cksum": cksum(body),
+        "yanked": false,
+        "v": 2,
+        "rust_version": null,
+    });
+    serde_json::from_str(&entry.to_string()).unwrap()
+}
+
+// settled, so the feed hands it out straight away
+fn past(record: AuditRecord, suffix: &str) -> AuditRecord {
+    let at = record.at - 10;
+    AuditRecord {
+        id: format!("{at:020}-{suffix}"),
+        at,
+        ..record
+    }
+}
+
+#[tokio::test]
+async fn copies_publishes_and_yanks_and_forwards_writes() {
+    let primary = MemoryStore::default();
+    let vers = semver::Version::new(1, 0, 0);
+    let body = b"crate archive".to_vec();
+    let entry = foo_entry(&body);
+    primary.put_replica(&entry, body.clone()).await.unwrap();
+    let publish = AuditRecord::new(Action::Publish)
+        .with_crate(&name("foo"))
+        .with_version(&vers);
+    primary.put_audit(&past(publish, "00000001")).await.unwrap();
+
+    let replica = MemoryStore::default();
+    let follower = Follower::new(
+        replica.clone(),
+        FollowerConfig {
+            primary: spawn_primary(primary.clone()).await,
+            token: Some(TOKEN.to_string()),
+            poll_interval: 1,
+            max_lag: 60,
+        },
+    );
+    assert!(!follower.status().healthy);
+    assert_eq!(follower.sync().await.unwrap(), 1);
+    assert_eq!(replica.get_index(&name("foo")).await.unwrap(), [entry]);
+    assert_eq!(
+        replica.get_crate(&name("foo"), vers.clone()).await.unwrap(),
+        body
+    );
+    // caught up, nothing new
+    assert_eq!(follower.sync().await.unwrap(), 0);
+
+    primary
+        .set_yank(&name("foo"), vers.clone(), true)
+        .await
+        .unwrap();
+    let yank = AuditRecord::new(Action::Yank)
+        .with_crate(&name("foo"))
+        .with_version(&vers);
+    primary.put_audit(&past(yank, "00000002")).await.unwrap();
+    assert_eq!(follower.sync().await.unwrap(), 1);
+    assert!(replica.get_index(&name("foo")).await.unwrap()[0].yanked);
+    let status = follower.status();
+    assert!(status.healthy);
+    assert_eq!(status.applied, 2);
+    assert!(status.lag.unwrap() <= 1);
+
+    // the primary answers writes
+    let response = follower
+        .forward(
+            Request::delete("/api/v1/crates/foo/1.0.0/yank")
+                .header("authorization", "gdy_someone")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+}
+
+#[tokio::test]
+async fn refuses_to_forward_more_than_the_primary_accepts() {
+    let follower = Follower::new(
+        MemoryStore::default(),
+        FollowerConfig {
+            primary: spawn_primary(MemoryStore::default()).await,
+            token: Some(TOKEN.to_string()),
+            poll_interval: 1,
+            max_lag: 60,
+        },
+    );
+    let response = follower
+        .forward(
+            Request::put("/api/v1/crates/new")
+                .header("authorization", "gdy_someone")
+                .body(Body::from(vec![0u8; MAX_PUBLISH_BODY + 1]))
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+}
+
+#[tokio::test]
+async fn the_primary_records_the_client_not_the_follower() {
+    let follower = Follower::new(
+        MemoryStore::default(),
+        FollowerConfig {
+            primary: spawn_primary(MemoryStore::default()).await,
+            token: Some(TOKEN.to_string()),
+            poll_interval: 1,
+            max_lag: 60,
+        },
+    );
+    let recorded = async |peer: &str, proxies: Option<TrustedProxies>| {
+        let mut request = Request::put("/api/v1/crates/foo/1.0.0/unyank")
+            .header("authorization", "gdy_someone")
+            // believed only from a proxy the follower trusts
+            .header("x-forwarded-for", "198.51.100.1")
+            .extension(ConnectInfo(
+                format!("{peer}:443").parse::<SocketAddr>().unwrap(),
+            ));
+        if let Some(proxies) = proxies {
+            request = request.extension(proxies);
+        }
+        let response = follower.forward(request.body(Body::empty()).unwrap()).await;
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        String::from_utf8(body.to_vec()).unwrap()
+    };
+    assert_eq!(recorded("203.0.113.5", None).await, "203.0.113.5");
+    // behind the follower's own load balancer
+    assert_eq!(
+        recorded("10.1.2.3", Some(trusted("10.0.0.0/8"))).await,
+        "198.51.100.1"
+    );
+}
+
+#[test]
+fn forwards_what_would_change_the_registry() {
+    assert!(is_forwarded(&Method::DELETE, "/crates/foo/1.0.0/yank"));
+    assert!(is_forwarded(&Method::PUT, "/admin/blocked/foo"));
+    assert!(!is_forwarded(&Method::GET, "/crates/foo/1.0.0"));
+}
+
+#[tokio::test]
+async fn reads_with_credentials_only_the_primary_knows_go_to_the_primary() {
+    for token in [TOKEN, "gdo_publish", "v3.public.abc.def"] {
+        assert!(is_checked_by_primary(
+            &Method::GET,
+            "/api/v1/crates/foo/1.0.0/download",
+            Some(token)
+        ));
+        assert!(is_checked_by_primary(&Method::GET, "/3/f/foo", Some(token)));
+        assert!(!is_checked_by_primary(
+            &Method::GET,
+            "/config.json",
+            Some(token)
+        ));
+        assert!(!is_checked_by_primary(
+            &Method::DELETE,
+            "/api/v1/admin/cache",
+            Some(token)
+        ));
+    }
+    for token in [None, Some("gds_deploy"), Some("ghp_github")] {
+        assert!(!is_checked_by_primary(&Method::GET, "/3/f/foo", token));
+    }
+
+    let primary = MemoryStore::default();
+    let body = b"crate archive".to_vec();
+    let entry = foo_entry(&body);
+    primary.put_replica(&entry, body.clone()).await.unwrap();
+    let follower = Follower::new(
+        MemoryStore::default(),
+        FollowerConfig {
+            primary: spawn_primary(primary).await,
+            token: None,
+            poll_interval: 1,
+            max_lag: 60,
+        },
+    );
+    let response = follower
+        .forward(
+            Request::get("/api/v1/crates/foo/1.0.0")
+                .header("authorization", TOKEN)
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let forwarded = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    assert_eq!(forwarded, body);
+}
+
+#[test]
+fn flushes_its_own_cache() {
+    assert!(!is_forwarded(&Method::DELETE, "/admin/cache"));
+}
+
+#[test]
+fn goes_read_only_by_itself() {
+    assert!(!is_forwarded(&Method::PUT, "/admin/maintenance"));
+    assert!(!is_forwarded(&Method::DELETE, "/admin/maintenance"));
+}
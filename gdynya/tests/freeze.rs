@@ -0,0 +1,70 @@
+//! Release freeze windows.
+
+mod common;
+
+use axum::http::StatusCode;
+use common::name;
+use gdynya::{
+    admin::{FreezeRequest, FreezeWindow},
+    auth::{Principal, Provider},
+};
+
+fn now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+fn request(starts_at: u64, ends_at: u64) -> FreezeRequest {
+    FreezeRequest {
+        crates: vec!["acme-*".to_string(), "gdynya".to_string()],
+        starts_at,
+        ends_at,
+        exempt: vec!["release-bot".to_string()],
+        message: "Q4 release, ask #release".to_string(),
+    }
+}
+
+#[test]
+fn freezes_matching_crates_for_everyone_but_the_exempt() {
+    let admin = Principal::new(Provider::GitHub, "alice");
+    let bob = Principal::new(Provider::GitHub, "bob");
+    let bot = Principal::new(Provider::ServiceAccount, "release-bot");
+    let at = now();
+    let freeze = FreezeWindow::new("q4", request(at - 60, at + 3600), &admin).unwrap();
+    assert_eq!(freeze.created_by.as_deref(), Some("alice"));
+
+    assert!(freeze.covers(&name("acme_core"), at));
+    assert!(freeze.covers(&name("gdynya"), at));
+    assert!(!freeze.covers(&name("other"), at));
+    // the end is exclusive
+    assert!(!freeze.covers(&name("gdynya"), at + 3600));
+    assert!(!freeze.covers(&name("gdynya"), at - 61));
+
+    let later = FreezeWindow::new("q1", request(at + 3600, at + 7200), &admin).unwrap();
+    let freezes = [later, freeze];
+    let stopping = FreezeWindow::stopping(&freezes, &name("acme-core"), &bob).unwrap();
+    assert_eq!(stopping.id, "q4");
+    assert!(FreezeWindow::stopping(&freezes, &name("acme-core"), &bot).is_none());
+    assert!(FreezeWindow::stopping(&freezes, &name("other"), &bob).is_none());
+}
+
+#[test]
+fn rejects_bad_windows() {
+    let admin = Principal::new(Provider::GitHub, "alice");
+    let bad =
+        |id: &str, req: FreezeRequest| FreezeWindow::new(id, req, &admin).unwrap_err().error_type;
+    assert_eq!(bad("q4", request(100, 100)), StatusCode::BAD_REQUEST);
+    assert_eq!(bad("../q4", request(100, 200)), StatusCode::BAD_REQUEST);
+    assert_eq!(
+        bad(
+            "q4",
+            FreezeRequest {
+                crates: Vec::new(),
+                ..request(100, 200)
+            }
+        ),
+        StatusCode::BAD_REQUEST
+    );
+}
@@ -0,0 +1,778 @@
+//! `GitHubAuth` against a fake GitHub REST API served from the test process.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use axum::{
+    Json, Router,
+    extract::{Path, Query, Request, State},
+    http::{HeaderMap, HeaderValue, StatusCode},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing,
+};
+use gdynya::{
+    api_schema::{Config, CrateName, HttpProtocol},
+    auth::{
+        Auth, Mutation, Permissions, Scope,
+        github::{Access, AuthCacheConfig, GitHubApi, GitHubAuth, Source},
+        rules::AuthRules,
+    },
+    axum_aux::RawAuthorization,
+};
+use headers::Header;
+use serde::Deserialize;
+use serde_json::json;
+use tokio::net::TcpListener;
+
+const RULES: &str = r#"
+version: 2
+crates:
+  by-user:
+    read: !is { user: alice }
+    write: !is { user: alice }
+  by-org:
+    read: !in_orgs { org: acme }
+    write: !in_orgs { org: acme }
+  by-team:
+    read: !in_team { org: acme, team: platform }
+    write: !in_team { org: acme, team: platform }
+  by-repo:
+    read: !repo_permission { repo: acme/widget, level: read }
+    write: !repo_permission { repo: acme/widget, level: write }
+  public:
+    read: !anyone
+    write: !not { rule: !anyone }
+  outsiders:
+    read: !not { rule: !in_orgs { org: acme } }
+    write: !not { rule: !in_orgs { org: acme } }
+  with-pending:
+    read: !in_orgs { org: acme, include_pending: true }
+    write: !in_orgs { org: acme, include_pending: true }
+  with-collaborators:
+    read: !in_orgs { org: acme, include_outside_collaborators: true }
+    write: !in_orgs { org: acme, include_outside_collaborators: true }
+  combined:
+    read: !any_of [!is { user: alice }, !in_orgs { org: acme }]
+    write: !all_of [!in_orgs { org: acme }, !not { rule: !is { user: carol } }]
+  split:
+    read: !in_orgs { org: acme }
+    download: !is { user: alice }
+    write: !is { user: alice }
+    yank: !in_orgs { org: acme }
+    owners: !is { user: carol }
+"#;
+
+#[derive(Default)]
+struct FakeGitHub {
+    // token -> login
+    tokens: HashMap<String, String>,
+    // (org, login) -> membership state
+    org_memberships: HashMap<(String, String), String>,
+    outside_collaborators: HashMap<String, Vec<String>>,
+    // (org, team, login) -> membership state
+    team_memberships: HashMap<(String, String, String), String>,
+    // (owner/repo, login) -> (permission, role_name)
+    collaborators: HashMap<(String, String), (String, String)>,
+    outage: bool,
+    // answer 200 with a body that is not JSON
+    malformed: bool,
+    // added to every response
+    delay: Duration,
+    requests: usize,
+    user_requests: usize,
+}
+
+type Shared = Arc<Mutex<FakeGitHub>>;
+
+fn fixture() -> FakeGitHub {
+    let mut fake = FakeGitHub::default();
+    for login in ["alice", "bob", "carol", "dave", "erin", "frank", "gina"] {
+        fake.tokens
+            .insert(format!("{login}-token"), login.to_string());
+    }
+    for (login, state) in [
+        ("alice", "active"),
+        ("carol", "active"),
+        ("frank", "pending"),
+    ] {
+        fake.org_memberships
+            .insert(("acme".to_string(), login.to_string()), state.to_string());
+    }
+    // more than one page, with the one we look for on the last
+    let mut collaborators = (0..150)
+        .map(|i| format!("contractor{i}"))
+        .collect::<Vec<_>>();
+    collaborators.push("gina".to_string());
+    fake.outside_collaborators
+        .insert("acme".to_string(), collaborators);
+    for (login, state) in [("alice", "active"), ("carol", "pending")] {
+        fake.team_memberships.insert(
+            (
+                "acme".to_string(),
+                "platform".to_string(),
+                login.to_string(),
+            ),
+            state.to_string(),
+        );
+    }
+    for (login, permission, role_name) in [
+        ("alice", "admin", "maintain"),
+        ("bob", "read", "read"),
+        ("dave", "write", "release-manager"),
+    ] {
+        fake.collaborators.insert(
+            ("acme/widget".to_string(), login.to_string()),
+            (permission.to_string(), role_name.to_string()),
+        );
+    }
+    fake
+}
+
+async fn count_and_fail_during_outage(
+    State(fake): State<Shared>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let (outage, malformed, delay) = {
+        let mut fake = fake.lock().unwrap();
+        fake.requests += 1;
+        (fake.outage, fake.malformed, fake.delay)
+    };
+    tokio::time::sleep(delay).await;
+    if outage {
+        (StatusCode::BAD_GATEWAY, "upstream down").into_response()
+    } else if malformed {
+        (StatusCode::OK, "<html>login</html>").into_response()
+    } else {
+        next.run(req).await
+    }
+}
+
+async fn user(State(fake): State<Shared>, headers: HeaderMap) -> Response {
+    let token = headers
+        .get("authorization")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .unwrap_or_default();
+    let mut fake = fake.lock().unwrap();
+    fake.user_requests += 1;
+    match fake.tokens.get(token) {
+        Some(login) => Json(json!({ "login": login, "id": 1, "name": login })).into_response(),
+        None => (
+            StatusCode::UNAUTHORIZED,
+            Json(json!({ "message": "Bad credentials" })),
+        )
+            .into_response(),
+    }
+}
+
+async fn org_membership(
+    State(fake): State<Shared>,
+    Path((org, login)): Path<(String, String)>,
+) -> Response {
+    match fake.lock().unwrap().org_memberships.get(&(org, login)) {
+        Some(state) => Json(json!({ "state": state })).into_response(),
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+#[derive(Deserialize)]
+struct Page {
+    per_page: usize,
+    page: usize,
+}
+
+async fn outside_collaborators(
+    State(fake): State<Shared>,
+    Path(org): Path<String>,
+    Query(Page { per_page, page }): Query<Page>,
+) -> Response {
+    let collaborators = fake
+        .lock()
+        .unwrap()
+        .outside_collaborators
+        .get(&org)
+        .cloned()
+        .unwrap_or_default();
+    let collaborators = collaborators
+        .into_iter()
+        .skip((page - 1) * per_page)
+        .take(per_page)
+        .map(|login| json!({ "login": login }))
+        .collect::<Vec<_>>();
+    Json(collaborators).into_response()
+}
+
+async fn team_membership(
+    State(fake): State<Shared>,
+    Path((org, team, login)): Path<(String, String, String)>,
+) -> Response {
+    match fake
+        .lock()
+        .unwrap()
+        .team_memberships
+        .get(&(org, team, login))
+    {
+        Some(state) => Json(json!({ "state": state })).into_response(),
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+async fn collaborator_permission(
+    State(fake): State<Shared>,
+    Path((owner, repo, login)): Path<(String, String, String)>,
+) -> Response {
+    match fake
+        .lock()
+        .unwrap()
+        .collaborators
+        .get(&(format!("{owner}/{repo}"), login))
+    {
+        Some((permission, role_name)) => {
+            Json(json!({ "permission": permission, "role_name": role_name })).into_response()
+        }
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+async fn spawn_fake_github(fake: FakeGitHub) -> (Shared, GitHubApi) {
+    let fake = Arc::new(Mutex::new(fake));
+    let app = Router::new()
+        .route("/user", routing::get(user))
+        .route(
+            "/orgs/{org}/memberships/{login}",
+            routing::get(org_membership),
+        )
+        .route(
+            "/orgs/{org}/outside_collaborators",
+            routing::get(outside_collaborators),
+        )
+        .route(
+            "/orgs/{org}/teams/{team}/memberships/{login}",
+            routing::get(team_membership),
+        )
+        .route(
+            "/repos/{owner}/{repo}/collaborators/{login}/permission",
+            routing::get(collaborator_permission),
+        )
+        .layer(middleware::from_fn_with_state(
+            fake.clone(),
+            count_and_fail_during_outage,
+        ))
+        .with_state(fake.clone());
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+    let api = GitHubApi::new(format!("http://{addr}"));
+    (fake, api)
+}
+
+async fn setup() -> (Shared, GitHubAuth) {
+    let (fake, api) = spawn_fake_github(fixture()).await;
+    let rules = AuthRules::from_yaml(RULES).unwrap();
+    let auth = GitHubAuth::new_from_config(rules)
+        .with_api(api)
+        .with_service_token("service-token");
+    (fake, auth)
+}
+
+fn token(login: &str) -> RawAuthorization {
+    let value = HeaderValue::from_str(&format!("{login}-token")).unwrap();
+    RawAuthorization::decode(&mut std::iter::once(&value)).unwrap()
+}
+
+fn name(name: &str) -> CrateName {
+    name.parse().unwrap()
+}
+
+async fn read(auth: &GitHubAuth, login: &str, crate_name: &str) -> Result<(), StatusCode> {
+    auth.readable(Some(&token(login)), &name(crate_name))
+        .await
+        .map(drop)
+        .map_err(|e| e.error_type)
+}
+
+async fn write(auth: &GitHubAuth, login: &str, crate_name: &str) -> Result<(), StatusCode> {
+    auth.writable(&token(login), &name(crate_name), &Mutation::Owners)
+        .await
+        .map(drop)
+        .map_err(|e| e.error_type)
+}
+
+fn requests(fake: &Shared) -> usize {
+    fake.lock().unwrap().requests
+}
+
+#[tokio::test]
+async fn is_matches_only_the_named_user() {
+    let (_, auth) = setup().await;
+    assert_eq!(read(&auth, "alice", "by-user").await, Ok(()));
+    assert_eq!(write(&auth, "alice", "by_user").await, Ok(()));
+    assert_eq!(
+        read(&auth, "bob", "by-user").await,
+        Err(StatusCode::FORBIDDEN)
+    );
+}
+
+#[tokio::test]
+async fn in_orgs_denies_valid_tokens_outside_the_org() {
+    let (_, auth) = setup().await;
+    assert_eq!(read(&auth, "alice", "by-org").await, Ok(()));
+    assert_eq!(
+        read(&auth, "bob", "by-org").await,
+        Err(StatusCode::FORBIDDEN)
+    );
+    assert_eq!(
+        write(&auth, "bob", "by-org").await,
+        Err(StatusCode::FORBIDDEN)
+    );
+}
+
+#[tokio::test]
+async fn in_orgs_counts_pending_members_and_outside_collaborators_only_when_asked() {
+    let (_, auth) = setup().await;
+    assert_eq!(
+        read(&auth, "frank", "by-org").await,
+        Err(StatusCode::FORBIDDEN)
+    );
+    assert_eq!(read(&auth, "frank", "with-pending").await, Ok(()));
+    assert_eq!(
+        read(&auth, "gina", "by-org").await,
+        Err(StatusCode::FORBIDDEN)
+    );
+    assert_eq!(read(&auth, "gina", "with-collaborators").await, Ok(()));
+    assert_eq!(
+        read(&auth, "bob", "with-collaborators").await,
+        Err(StatusCode::FORBIDDEN)
+    );
+}
+
+#[tokio::test]
+async fn login_is_resolved_once_per_token() {
+    let (fake, auth) = setup().await;
+    for crate_name in ["by-user", "by-org", "by-team", "combined"] {
+        assert_eq!(read(&auth, "alice", crate_name).await, Ok(()));
+        assert_eq!(write(&auth, "alice", crate_name).await, Ok(()));
+    }
+    assert_eq!(fake.lock().unwrap().user_requests, 1);
+}
+
+#[tokio::test]
+async fn in_team_requires_an_active_membership() {
+    let (_, auth) = setup().await;
+    assert_eq!(read(&auth, "alice", "by-team").await, Ok(()));
+    assert_eq!(
+        read(&auth, "carol", "by-team").await,
+        Err(StatusCode::FORBIDDEN)
+    );
+    assert_eq!(
+        read(&auth, "bob", "by-team").await,
+        Err(StatusCode::FORBIDDEN)
+    );
+}
+
+#[tokio::test]
+async fn repo_permission_compares_levels() {
+    let (_, auth) = setup().await;
+    assert_eq!(write(&auth, "alice", "by-repo").await, Ok(()));
+    assert_eq!(read(&auth, "bob", "by-repo").await, Ok(()));
+    assert_eq!(
+        write(&auth, "bob", "by-repo").await,
+        Err(StatusCode::FORBIDDEN)
+    );
+    // custom role inheriting from write
+    assert_eq!(write(&auth, "dave", "by-repo").await, Ok(()));
+    assert_eq!(
+        read(&auth, "erin", "by-repo").await,
+        Err(StatusCode::FORBIDDEN)
+    );
+}
+
+#[tokio::test]
+async fn anyone_and_not() {
+    let (_, auth) = setup().await;
+    assert_eq!(read(&auth, "bob", "public").await, Ok(()));
+    assert_eq!(
+        write(&auth, "bob", "public").await,
+        Err(StatusCode::FORBIDDEN)
+    );
+    assert_eq!(read(&auth, "bob", "outsiders").await, Ok(()));
+    assert_eq!(
+        read(&auth, "alice", "outsiders").await,
+        Err(StatusCode::FORBIDDEN)
+    );
+}
+
+#[tokio::test]
+async fn anonymous_reads() {
+    let (fake, auth) = setup().await;
+    assert!(auth.readable(None, &name("public")).await.is_ok());
+    for crate_name in ["outsiders", "combined", "unlisted"] {
+        let e = auth.readable(None, &name(crate_name)).await.unwrap_err();
+        assert_eq!(e.error_type, StatusCode::UNAUTHORIZED);
+    }
+    assert_eq!(requests(&fake), 0);
+
+    let auth = auth.with_public_read(true);
+    assert!(auth.readable(None, &name("by-org")).await.is_ok());
+    assert_eq!(
+        write(&auth, "bob", "public").await,
+        Err(StatusCode::FORBIDDEN)
+    );
+}
+
+/// `config.json` as served for `auth`.
+fn config_json(auth: &GitHubAuth) -> serde_json::Value {
+    let config = Config::new(HttpProtocol::Https, "crates.example.com")
+        .with_auth_required(auth.reads_need_auth());
+    serde_json::to_value(config).unwrap()
+}
+
+#[tokio::test]
+async fn cargo_is_told_to_send_tokens_unless_everything_is_public() {
+    let (_, auth) = setup().await;
+    // `public` alone does not do, the other crates need a token
+    assert_eq!(config_json(&auth)["auth-required"], true);
+    let auth = auth.with_public_read(true);
+    assert_eq!(config_json(&auth)["auth-required"], false);
+
+    // `MORE` is where further crates go
+    let public = "version: 2
+crates:
+  public:
+    read: !anyone
+    write: !is { user: alice }
+MORE
+default:
+    read: !any_of
+      - !is { user: alice }
+      - !anyone
+    write: !is { user: alice }
+";
+    let rules = |more: &str| AuthRules::from_yaml(&public.replace("MORE\n", more)).unwrap();
+    let auth = GitHubAuth::new_from_config(rules(""));
+    assert_eq!(config_json(&auth)["auth-required"], false);
+
+    auth.reload(rules(
+        "  hidden:\n    read: !anyone\n    download: !is { user: alice }\n    write: !anyone\n",
+    ));
+    assert_eq!(config_json(&auth)["auth-required"], true);
+    auth.reload(rules(
+        "namespaces:\n  acme:\n    read: !in_orgs { org: acme }\n    write: !anyone\n",
+    ));
+    assert_eq!(config_json(&auth)["auth-required"], true);
+    // without a default, unlisted crates are nobody's
+    let mut listed = rules("");
+    listed.default = None;
+    auth.reload(listed);
+    assert_eq!(config_json(&auth)["auth-required"], true);
+}
+
+#[tokio::test]
+async fn any_of_and_all_of() {
+    let (_, auth) = setup().await;
+    assert_eq!(read(&auth, "alice", "combined").await, Ok(()));
+    assert_eq!(read(&auth, "carol", "combined").await, Ok(()));
+    assert_eq!(
+        read(&auth, "bob", "combined").await,
+        Err(StatusCode::FORBIDDEN)
+    );
+    assert_eq!(write(&auth, "alice", "combined").await, Ok(()));
+    assert_eq!(
+        write(&auth, "carol", "combined").await,
+        Err(StatusCode::FORBIDDEN)
+    );
+}
+
+#[tokio::test]
+async fn operations_have_their_own_rules() {
+    let (_, auth) = setup().await;
+    let vers = semver::Version::new(1, 0, 0);
+    let publish = Mutation::Publish {
+        vers: &vers,
+        cksum: "00",
+        new: false,
+    };
+    let yank = Mutation::Yank { vers: &vers };
+    let allowed = async |login: &str, mutation: &Mutation<'_>| {
+        auth.writable(&token(login), &name("split"), mutation)
+            .await
+            .is_ok()
+    };
+    assert!(allowed("alice", &publish).await);
+    assert!(allowed("alice", &yank).await);
+    assert!(!allowed("alice", &Mutation::Owners).await);
+    assert!(!allowed("carol", &publish).await);
+    assert!(allowed("carol", &yank).await);
+    assert!(allowed("carol", &Mutation::Owners).await);
+
+    assert_eq!(read(&auth, "carol", "split").await, Ok(()));
+    let download = auth
+        .downloadable(Some(&token("carol")), &name("split"))
+        .await;
+    assert_eq!(download.unwrap_err().error_type, StatusCode::FORBIDDEN);
+    // without a download rule, read decides
+    assert!(
+        auth.downloadable(Some(&token("carol")), &name("by-org"))
+            .await
+            .is_ok()
+    );
+
+    let permissions = auth
+        .permissions(Some(&token("carol")), &name("split"))
+        .await
+        .unwrap();
+    assert_eq!(
+        permissions,
+        Permissions {
+            read: true,
+            download: false,
+            write: vec![Scope::Yank, Scope::ChangeOwners],
+        }
+    );
+}
+
+#[test]
+fn writes_need_a_rule_each() {
+    let rules =
+        "version: 2\ncrates:\n  x:\n    read: !anyone\n    publish: !anyone\n    yank: !anyone\n";
+    let e = AuthRules::from_yaml(rules).unwrap_err();
+    assert!(
+        e.to_string()
+            .contains("publish, yank and owners must all be given")
+    );
+    let rules = format!("{rules}    owners: !anyone\n");
+    assert!(AuthRules::from_yaml(&rules).is_ok());
+}
+
+#[tokio::test]
+async fn explanations_show_the_failing_sub_rule() {
+    let (_, auth) = setup().await;
+    let explanation = auth
+        .explain("carol", &name("combined"), Access::Publish)
+        .await
+        .unwrap();
+    assert!(!explanation.allowed);
+    assert_eq!(explanation.matched.as_deref(), Some("crates.combined"));
+    assert_eq!(explanation.source, Source::Fresh);
+    assert_eq!(
+        explanation.trace.unwrap().lines(),
+        [
+            "fails all_of",
+            "  holds in_orgs(acme)",
+            "  fails not",
+            "    holds is(carol)",
+        ]
+    );
+    let explanation = auth
+        .explain("carol", &name("combined"), Access::Publish)
+        .await
+        .unwrap();
+    assert_eq!(explanation.source, Source::Cached);
+
+    let explanation = auth
+        .explain("carol", &name("unlisted"), Access::Read)
+        .await
+        .unwrap();
+    assert!(explanation.matched.is_none() && explanation.trace.is_none());
+}
+
+#[tokio::test]
+async fn logins_are_not_checked_without_a_service_token() {
+    let (fake, api) = spawn_fake_github(fixture()).await;
+    let rules = AuthRules::from_yaml(RULES).unwrap();
+    let auth = GitHubAuth::new_from_config(rules).with_api(api);
+    let e = auth
+        .readable_by("alice", &name("by-org"))
+        .await
+        .unwrap_err();
+    assert_eq!(e.error_type, StatusCode::INTERNAL_SERVER_ERROR);
+    let e = auth.registry_user(None, "alice").await.unwrap_err();
+    assert_eq!(e.error_type, StatusCode::INTERNAL_SERVER_ERROR);
+    assert_eq!(requests(&fake), 0);
+}
+
+#[tokio::test]
+async fn debug_reasons_do_not_name_rules() {
+    let (_, auth) = setup().await;
+    let auth = auth.with_debug_reasons(true);
+    let e = auth
+        .writable(&token("carol"), &name("combined"), &Mutation::Owners)
+        .await
+        .unwrap_err();
+    assert_eq!(
+        e.contexts,
+        ["the owners rule for this crate does not allow you"]
+    );
+    let e = auth
+        .readable(Some(&token("alice")), &name("unlisted"))
+        .await
+        .unwrap_err();
+    assert_eq!(e.contexts, ["no rule covers this crate"]);
+}
+
+#[tokio::test]
+async fn unknown_crates_and_invalid_tokens_are_forbidden() {
+    let (_, auth) = setup().await;
+    assert_eq!(
+        read(&auth, "alice", "unlisted").await,
+        Err(StatusCode::FORBIDDEN)
+    );
+    assert_eq!(
+        read(&auth, "mallory", "public").await,
+        Err(StatusCode::FORBIDDEN)
+    );
+}
+
+#[tokio::test]
+async fn outages_fail_closed_and_are_not_cached() {
+    let (fake, auth) = setup().await;
+    fake.lock().unwrap().outage = true;
+    let e = auth
+        .readable(Some(&token("bob")), &name("outsiders"))
+        .await
+        .unwrap_err();
+    assert_eq!(e.error_type, StatusCode::SERVICE_UNAVAILABLE);
+    assert!(e.retry_after.is_some());
+    fake.lock().unwrap().outage = false;
+    assert_eq!(read(&auth, "bob", "outsiders").await, Ok(()));
+}
+
+#[tokio::test]
+async fn decisions_are_cached_until_they_expire() {
+    let (fake, auth) = setup().await;
+    let auth = auth.with_cache_ttl(Duration::from_millis(300));
+    assert_eq!(read(&auth, "alice", "by-org").await, Ok(()));
+    assert_eq!(
+        read(&auth, "bob", "by-org").await,
+        Err(StatusCode::FORBIDDEN)
+    );
+    let after_miss = requests(&fake);
+    assert_eq!(read(&auth, "alice", "by-org").await, Ok(()));
+    assert_eq!(
+        read(&auth, "bob", "by-org").await,
+        Err(StatusCode::FORBIDDEN)
+    );
+    assert_eq!(requests(&fake), after_miss);
+    tokio::time::sleep(Duration::from_millis(600)).await;
+    assert_eq!(read(&auth, "alice", "by-org").await, Ok(()));
+    assert!(requests(&fake) > after_miss);
+}
+
+#[tokio::test]
+async fn denials_expire_sooner_and_can_be_flushed() {
+    let (fake, auth) = setup().await;
+    let auth = auth.with_cache_config(AuthCacheConfig {
+        positive_ttl: 60,
+        negative_ttl: 0,
+        capacity: 100,
+    });
+    assert_eq!(read(&auth, "alice", "by-org").await, Ok(()));
+    assert_eq!(
+        read(&auth, "bob", "by-org").await,
+        Err(StatusCode::FORBIDDEN)
+    );
+    let before = requests(&fake);
+    assert_eq!(read(&auth, "alice", "by-org").await, Ok(()));
+    assert_eq!(requests(&fake), before);
+    assert_eq!(
+        read(&auth, "bob", "by-org").await,
+        Err(StatusCode::FORBIDDEN)
+    );
+    assert!(requests(&fake) > before);
+    let stats = auth.cache_stats();
+    assert_eq!((stats.hits, stats.misses), (1, 3));
+
+    auth.flush(Some("bob"), None);
+    let before = requests(&fake);
+    assert_eq!(read(&auth, "alice", "by-org").await, Ok(()));
+    assert_eq!(requests(&fake), before);
+    auth.flush(Some("alice"), None);
+    assert_eq!(read(&auth, "alice", "by-org").await, Ok(()));
+    assert!(requests(&fake) > before);
+
+    let before = requests(&fake);
+    auth.flush(None, Some(&name("by-org")));
+    assert_eq!(read(&auth, "alice", "by-org").await, Ok(()));
+    assert!(requests(&fake) > before);
+
+    // both given: only that login's decisions about that crate
+    assert_eq!(read(&auth, "alice", "by-team").await, Ok(()));
+    auth.flush(Some("alice"), Some(&name("by-team")));
+    let before = requests(&fake);
+    assert_eq!(read(&auth, "alice", "by-org").await, Ok(()));
+    assert_eq!(requests(&fake), before);
+    assert_eq!(read(&auth, "alice", "by-team").await, Ok(()));
+    assert!(requests(&fake) > before);
+}
+
+#[tokio::test]
+async fn last_known_decision_is_served_during_an_outage() {
+    let (fake, auth) = setup().await;
+    let auth = auth
+        .with_cache_ttl(Duration::from_millis(100))
+        .with_stale_if_error(Duration::from_secs(60));
+    assert_eq!(read(&auth, "alice", "by-org").await, Ok(()));
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    fake.lock().unwrap().outage = true;
+    assert_eq!(read(&auth, "alice", "by-org").await, Ok(()));
+    assert_eq!(
+        read(&auth, "bob", "by-org").await,
+        Err(StatusCode::SERVICE_UNAVAILABLE)
+    );
+}
+
+#[tokio::test]
+async fn unexpected_responses_are_bad_gateway_and_not_an_outage() {
+    let (fake, auth) = setup().await;
+    let auth = auth
+        .with_cache_ttl(Duration::from_millis(100))
+        .with_stale_if_error(Duration::from_secs(60));
+    assert_eq!(read(&auth, "alice", "by-org").await, Ok(()));
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    fake.lock().unwrap().malformed = true;
+    // neither the last known decision nor a retryable 503
+    assert_eq!(
+        read(&auth, "alice", "by-org").await,
+        Err(StatusCode::BAD_GATEWAY)
+    );
+    fake.lock().unwrap().malformed = false;
+    assert_eq!(read(&auth, "alice", "by-org").await, Ok(()));
+}
+
+#[tokio::test]
+async fn reload_drops_cached_decisions() {
+    let (_, auth) = setup().await;
+    assert_eq!(read(&auth, "alice", "by-user").await, Ok(()));
+    let rules = RULES.replace("!is { user: alice }", "!is { user: bob }");
+    auth.reload(AuthRules::from_yaml(&rules).unwrap());
+    assert_eq!(
+        read(&auth, "alice", "by-user").await,
+        Err(StatusCode::FORBIDDEN)
+    );
+    assert_eq!(read(&auth, "bob", "by-user").await, Ok(()));
+}
+
+#[tokio::test]
+async fn reload_discards_evaluations_of_the_old_rules_in_flight() {
+    let (fake, auth) = setup().await;
+    fake.lock().unwrap().delay = Duration::from_millis(200);
+    let in_flight = tokio::spawn({
+        let auth = auth.clone();
+        async move { read(&auth, "alice", "by-user").await }
+    });
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    let rules = RULES.replace("!is { user: alice }", "!is { user: bob }");
+    auth.reload(AuthRules::from_yaml(&rules).unwrap());
+    // answered by the rules it started with, but not cached past the reload
+    assert_eq!(in_flight.await.unwrap(), Ok(()));
+    fake.lock().unwrap().delay = Duration::ZERO;
+    assert_eq!(
+        read(&auth, "alice", "by-user").await,
+        Err(StatusCode::FORBIDDEN)
+    );
+}
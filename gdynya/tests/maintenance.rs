@@ -0,0 +1,66 @@
+//! Read-only mode and the error cargo shows while it is on.
+
+use axum::{
+    http::{Method, StatusCode, header},
+    response::IntoResponse,
+};
+use gdynya::maintenance::{Maintenance, MaintenanceConfig, is_blocked};
+use serde_json::Value;
+
+#[tokio::test]
+async fn rejects_with_a_message_cargo_shows() {
+    let maintenance = Maintenance::new(None);
+    assert!(maintenance.check().is_ok());
+
+    maintenance.enable(
+        MaintenanceConfig {
+            message: "moving to a new bucket".to_string(),
+            retry_after: 120,
+        },
+        Some("alice".to_string()),
+    );
+    assert_eq!(maintenance.current().unwrap().by.as_deref(), Some("alice"));
+    let response = maintenance.check().unwrap_err().into_response();
+    assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(response.headers()[header::RETRY_AFTER], "120");
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    let body: Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(body["errors"][0]["detail"], "moving to a new bucket");
+
+    // the signal flips it back and forth
+    assert!(!maintenance.toggle(MaintenanceConfig::default()));
+    assert!(maintenance.check().is_ok());
+    assert!(maintenance.toggle(MaintenanceConfig::default()));
+    assert_eq!(maintenance.current().unwrap().by, None);
+}
+
+#[test]
+fn turns_away_changes_to_crates() {
+    assert!(is_blocked(&Method::PUT, "/crates/new"));
+    assert!(is_blocked(&Method::DELETE, "/crates/foo/1.0.0/yank"));
+    assert!(is_blocked(&Method::PUT, "/crates/foo/1.0.0/yank"));
+    assert!(is_blocked(&Method::PUT, "/crates/foo/owners"));
+    assert!(is_blocked(&Method::DELETE, "/crates/foo/owners"));
+    assert!(is_blocked(&Method::PUT, "/admin/crates/foo/owners"));
+    assert!(is_blocked(&Method::DELETE, "/admin/crates/foo/1.0.0"));
+    assert!(is_blocked(&Method::DELETE, "/admin/crates/foo/1.0.0/yank"));
+    assert!(is_blocked(&Method::PUT, "/admin/crates/foo/1.0.0/yank"));
+
+    assert!(!is_blocked(&Method::GET, "/crates/foo/1.0.0"));
+    assert!(!is_blocked(&Method::GET, "/crates/foo/owners"));
+}
+
+#[test]
+fn lets_tokens_and_admin_fixes_through() {
+    assert!(!is_blocked(&Method::DELETE, "/tokens/abc"));
+    assert!(!is_blocked(&Method::PUT, "/tokens"));
+    assert!(!is_blocked(&Method::DELETE, "/keys/abc"));
+    assert!(!is_blocked(&Method::PUT, "/trusted_publishing/tokens"));
+    assert!(!is_blocked(&Method::DELETE, "/trusted_publishing/tokens"));
+    assert!(!is_blocked(&Method::PUT, "/admin/blocked/foo"));
+    assert!(!is_blocked(&Method::DELETE, "/admin/freezes/release"));
+    assert!(!is_blocked(&Method::DELETE, "/admin/cache"));
+    assert!(!is_blocked(&Method::DELETE, "/admin/maintenance"));
+}
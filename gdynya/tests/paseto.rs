@@ -0,0 +1,221 @@
+//! Cargo's asymmetric tokens signed with a registered key.
+
+mod common;
+
+use axum::http::StatusCode;
+use base64::{Engine, prelude::BASE64_URL_SAFE_NO_PAD};
+use common::{MemoryStore, name, token};
+use gdynya::{
+    auth::{
+        Auth, Mutation, Provider,
+        github::GitHubAuth,
+        paseto::{KeyRecord, PasetoAuth, PasetoConfig, PublicKeys, key_id},
+        rules::AuthRules,
+    },
+    store::Store,
+};
+use p384::ecdsa::{Signature, SigningKey, signature::Signer};
+use serde_json::json;
+use time::{OffsetDateTime, format_description::well_known::Rfc3339};
+
+const REGISTRY_URL: &str = "sparse+https://crates.example.com/";
+const CKSUM: &str = "8f434346648f6b96df89dda901c5176b10a6d83961dd3c1ac88b59b2dc327aa4";
+
+const RULES: &str = r#"
+version: 2
+crates:
+  shared:
+    read: !is {user: alice}
+    write: !is {user: alice}
+"#;
+
+struct Signer384 {
+    key: SigningKey,
+    kid: String,
+}
+
+impl Signer384 {
+    fn new(seed: u8) -> Self {
+        let key = SigningKey::from_slice(&[seed; 48]).unwrap();
+        let kid = key_id(&public_key(&key));
+        Self { key, kid }
+    }
+
+    fn sign(&self, message: serde_json::Value, url: &str) -> String {
+        let message = serde_json::to_vec(&message).unwrap();
+        let footer = serde_json::to_vec(&json!({"url": url, "kid": self.kid})).unwrap();
+        let public_key = self.key.verifying_key().to_encoded_point(true);
+        let pae = pae(&[public_key.as_bytes(), b"v3.public.", &message, &footer, b""]);
+        let signature: Signature = self.key.sign(&pae);
+        let payload = [message, signature.to_bytes().to_vec()].concat();
+        format!(
+            "v3.public.{}.{}",
+            BASE64_URL_SAFE_NO_PAD.encode(payload),
+            BASE64_URL_SAFE_NO_PAD.encode(footer)
+        )
+    }
+}
+
+fn public_key(key: &SigningKey) -> String {
+    let point = key.verifying_key().to_encoded_point(true);
+    format!(
+        "k3.public.{}",
+        BASE64_URL_SAFE_NO_PAD.encode(point.as_bytes())
+    )
+}
+
+fn pae(pieces: &[&[u8]]) -> Vec<u8> {
+    let mut out = (pieces.len() as u64).to_le_bytes().to_vec();
+    for piece in pieces {
+        out.extend_from_slice(&(piece.len() as u64).to_le_bytes());
+        out.extend_from_slice(piece);
+    }
+    out
+}
+
+fn iat(offset: i64) -> String {
+    (OffsetDateTime::now_utc() + time::Duration::seconds(offset))
+        .format(&Rfc3339)
+        .unwrap()
+}
+
+async fn paseto(signer: &Signer384, owner: &str) -> PasetoAuth<MemoryStore> {
+    let store = MemoryStore::default();
+    store
+        .put_key(&KeyRecord {
+            kid: signer.kid.clone(),
+            owner: owner.to_string(),
+            name: "laptop".to_string(),
+            public_key: public_key(&signer.key),
+            created_at: 0,
+        })
+        .await
+        .unwrap();
+    let github = GitHubAuth::new_from_config(AuthRules::from_yaml(RULES).unwrap())
+        .with_service_token("service-token");
+    let config = PasetoConfig {
+        registry_url: REGISTRY_URL.to_string(),
+        iat_window: 60,
+    };
+    PasetoAuth::new(PublicKeys::new(store, github), Some(config))
+}
+
+fn publish_message() -> serde_json::Value {
+    json!({
+        "iat": iat(0),
+        "mutation": "publish",
+        "name": "shared",
+        "vers": "1.0.0",
+        "cksum": CKSUM,
+    })
+}
+
+fn publish(vers: &semver::Version) -> Mutation<'_> {
+    Mutation::Publish {
+        vers,
+        cksum: CKSUM,
+        new: false,
+    }
+}
+
+#[tokio::test]
+async fn signed_tokens_act_as_the_key_owner() {
+    let signer = Signer384::new(1);
+    let auth = paseto(&signer, "alice").await;
+    let read = token(&signer.sign(json!({"iat": iat(0)}), REGISTRY_URL));
+    auth.readable(Some(&read), &name("shared")).await.unwrap();
+
+    let vers = semver::Version::new(1, 0, 0);
+    let write = token(&signer.sign(publish_message(), REGISTRY_URL));
+    auth.writable(&write, &name("shared"), &publish(&vers))
+        .await
+        .unwrap();
+
+    let mallory = Signer384::new(2);
+    let auth = paseto(&mallory, "mallory").await;
+    let read = token(&mallory.sign(json!({"iat": iat(0)}), REGISTRY_URL));
+    let result = auth.readable(Some(&read), &name("shared")).await;
+    assert_eq!(result.unwrap_err().error_type, StatusCode::FORBIDDEN);
+}
+
+#[tokio::test]
+async fn mutation_tokens_are_bound_to_one_write() {
+    let signer = Signer384::new(1);
+    let auth = paseto(&signer, "alice").await;
+    let vers = semver::Version::new(1, 0, 0);
+    let write = token(&signer.sign(publish_message(), REGISTRY_URL));
+
+    let other_vers = semver::Version::new(1, 0, 1);
+    let other_cksum = Mutation::Publish {
+        vers: &vers,
+        cksum: "00",
+        new: false,
+    };
+    for (crate_name, mutation) in [
+        ("shared", publish(&other_vers)),
+        ("shared", other_cksum),
+        ("shared", Mutation::Yank { vers: &vers }),
+        ("other", publish(&vers)),
+    ] {
+        let result = auth.writable(&write, &name(crate_name), &mutation).await;
+        assert_eq!(result.unwrap_err().error_type, StatusCode::FORBIDDEN);
+    }
+    let result = auth.readable(Some(&write), &name("shared")).await;
+    assert_eq!(result.unwrap_err().error_type, StatusCode::FORBIDDEN);
+
+    auth.writable(&write, &name("shared"), &publish(&vers))
+        .await
+        .unwrap();
+    let replayed = auth
+        .writable(&write, &name("shared"), &publish(&vers))
+        .await;
+    assert_eq!(replayed.unwrap_err().error_type, StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn failed_writes_still_know_who_signed_them() {
+    let signer = Signer384::new(2);
+    let auth = paseto(&signer, "mallory").await;
+    let vers = semver::Version::new(1, 0, 0);
+    let write = token(&signer.sign(publish_message(), REGISTRY_URL));
+
+    // the rules deny mallory, but the token is hers
+    let result = auth
+        .writable(&write, &name("shared"), &publish(&vers))
+        .await;
+    assert_eq!(result.unwrap_err().error_type, StatusCode::FORBIDDEN);
+    assert!(auth.authenticate(Some(&write)).await.is_err());
+    let principal = auth
+        .identify(&write, &name("shared"), &publish(&vers))
+        .await
+        .unwrap();
+    assert_eq!(principal.provider, Provider::Paseto);
+    assert_eq!(principal.login.as_deref(), Some("mallory"));
+    assert_eq!(principal.token_id, Some(signer.kid.clone()));
+    // and only for the write it was signed for
+    let other = auth.identify(&write, &name("other"), &publish(&vers)).await;
+    assert_eq!(other.unwrap_err().error_type, StatusCode::FORBIDDEN);
+}
+
+#[tokio::test]
+async fn invalid_tokens_are_rejected() {
+    let signer = Signer384::new(1);
+    let auth = paseto(&signer, "alice").await;
+    let unknown = Signer384::new(3);
+    let mut tampered = signer.sign(json!({"iat": iat(0)}), REGISTRY_URL);
+    tampered.replace_range(12..14, "AA");
+    for invalid in [
+        signer.sign(json!({"iat": iat(-600)}), REGISTRY_URL),
+        signer.sign(json!({"iat": iat(600)}), REGISTRY_URL),
+        signer.sign(
+            json!({"iat": iat(0)}),
+            "sparse+https://elsewhere.example.com/",
+        ),
+        signer.sign(json!({"iat": iat(0), "challenge": "abc"}), REGISTRY_URL),
+        unknown.sign(json!({"iat": iat(0)}), REGISTRY_URL),
+        tampered,
+    ] {
+        let result = auth.readable(Some(&token(&invalid)), &name("shared")).await;
+        assert_eq!(result.unwrap_err().error_type, StatusCode::UNAUTHORIZED);
+    }
+}
@@ -0,0 +1,270 @@
+//! Rules file parsing, crate lookup and reloading.
+
+use gdynya::auth::{
+    github::{GitHubAuth, RepoPermissionLevel, Rule},
+    rules::{AuthRules, RulesError},
+};
+
+const RULES: &str = r#"
+any:
+  read: !any_of
+    - !in_team { org: acme, team: platform }
+    - !anyone
+  write: !anyone
+all:
+  read: !all_of
+    - !in_orgs { org: acme }
+    - !repo_permission { repo: acme/widget, level: triage }
+  write: !anyone
+none:
+  read: !not { rule: !is { user: mallory } }
+  write: !anyone
+"#;
+
+fn read(rules: &AuthRules, name: &str) -> Rule {
+    rules.crates[name].read.clone()
+}
+
+#[test]
+fn parses_combinators_and_their_operands() {
+    let rules = AuthRules::from_yaml(RULES).unwrap();
+
+    let Rule::AnyOf(any) = read(&rules, "any") else {
+        panic!("{:?}", read(&rules, "any"));
+    };
+    assert!(matches!(
+        &any[..],
+        [Rule::InTeam { org, team }, Rule::Anyone] if org == "acme" && team == "platform"
+    ));
+
+    let Rule::AllOf(all) = read(&rules, "all") else {
+        panic!("{:?}", read(&rules, "all"));
+    };
+    assert!(matches!(
+        &all[..],
+        [
+            Rule::InOrgs { org, .. },
+            Rule::RepoPermission { repo, level: RepoPermissionLevel::Triage },
+        ] if org == "acme" && repo == "acme/widget"
+    ));
+
+    let Rule::Not { rule } = read(&rules, "none") else {
+        panic!("{:?}", read(&rules, "none"));
+    };
+    assert!(matches!(*rule, Rule::Is { user } if user == "mallory"));
+}
+
+#[test]
+fn repo_permission_levels_are_ordered() {
+    use RepoPermissionLevel::*;
+    assert!(Read < Triage && Triage < Write && Write < Maintain && Maintain < Admin);
+    assert!(serde_yaml::from_str::<RepoPermissionLevel>("owner").is_err());
+}
+
+const ANYONE: &str = "read: !anyone\n    write: !anyone";
+
+fn rules(src: &str) -> AuthRules {
+    AuthRules::from_yaml(&src.replace("RULE", ANYONE)).unwrap()
+}
+
+/// Which entry `name` is looked up under, as `crates.<key>`, `namespaces.<key>` or `default`.
+fn found(rules: &AuthRules, name: &str) -> Option<String> {
+    let rule = rules.get(name)?;
+    let crates = rules
+        .crates
+        .iter()
+        .map(|(key, r)| (format!("crates.{key}"), r));
+    let namespaces = rules
+        .namespaces
+        .iter()
+        .map(|(key, r)| (format!("namespaces.{key}"), r));
+    let default = rules.default.iter().map(|r| ("default".to_string(), r));
+    crates
+        .chain(namespaces)
+        .chain(default)
+        .find(|(_, r)| std::ptr::eq(*r, rule))
+        .map(|(key, _)| key)
+}
+
+#[test]
+fn looks_up_exact_then_namespace_then_glob_then_default() {
+    let layered = rules(
+        "version: 2
+crates:
+  acme-core:
+    RULE
+  acme-*:
+    RULE
+  acme-*-macros:
+    RULE
+namespaces:
+  acme-:
+    RULE
+  acme-internal-:
+    RULE
+default:
+    RULE
+",
+    );
+    assert_eq!(
+        found(&layered, "acme-core").as_deref(),
+        Some("crates.acme-core")
+    );
+    // the longest prefix wins, and any prefix wins over a glob
+    assert_eq!(
+        found(&layered, "acme-internal-db").as_deref(),
+        Some("namespaces.acme-internal-")
+    );
+    assert_eq!(
+        found(&layered, "acme-util").as_deref(),
+        Some("namespaces.acme-")
+    );
+    assert_eq!(found(&layered, "other").as_deref(), Some("default"));
+
+    let globs = rules(
+        r#"version: 2
+crates:
+  acme-*:
+    RULE
+  acme-*-macros:
+    RULE
+  "*-macros":
+    RULE
+"#,
+    );
+    // the most specific glob wins, ties go to the lexically first pattern
+    assert_eq!(
+        found(&globs, "acme-core-macros").as_deref(),
+        Some("crates.acme-*-macros")
+    );
+    assert_eq!(found(&globs, "acme-core").as_deref(), Some("crates.acme-*"));
+    assert_eq!(
+        found(&globs, "serde-macros").as_deref(),
+        Some("crates.*-macros")
+    );
+    assert_eq!(found(&globs, "serde"), None);
+}
+
+#[test]
+fn namespaces_claim_whole_words() {
+    let acme = rules("version: 2\nnamespaces:\n  acme:\n    RULE\n");
+    assert_eq!(found(&acme, "acme").as_deref(), Some("namespaces.acme"));
+    assert_eq!(
+        found(&acme, "acme-core").as_deref(),
+        Some("namespaces.acme")
+    );
+    // names come normalized, `acme_core` is looked up as `acme-core`
+    assert_eq!(found(&acme, "acmefoo"), None);
+    assert_eq!(found(&acme, "acm"), None);
+
+    let dashed = rules("version: 2\nnamespaces:\n  acme-:\n    RULE\n");
+    assert_eq!(
+        found(&dashed, "acme-core").as_deref(),
+        Some("namespaces.acme-")
+    );
+    assert_eq!(found(&dashed, "acme"), None);
+}
+
+#[test]
+fn reads_the_legacy_layout_as_crates() {
+    let legacy = rules("foo:\n    RULE\nacme-*:\n    RULE\n");
+    assert_eq!(found(&legacy, "foo").as_deref(), Some("crates.foo"));
+    assert_eq!(
+        found(&legacy, "acme-core").as_deref(),
+        Some("crates.acme-*")
+    );
+}
+
+#[test]
+fn tells_the_legacy_layout_from_the_sections_by_the_version() {
+    let namespaces = rules("version: 2\nnamespaces:\n  acme-:\n    RULE\n");
+    assert!(namespaces.crates.is_empty());
+    assert_eq!(
+        found(&namespaces, "acme-core").as_deref(),
+        Some("namespaces.acme-")
+    );
+    let default = rules("version: 2\ndefault:\n    RULE\n");
+    assert!(default.crates.is_empty());
+    assert_eq!(found(&default, "anything").as_deref(), Some("default"));
+
+    // without it, section names are crate names like any other
+    let legacy = rules("default:\n    RULE\ncrates:\n    RULE\nversion:\n    RULE\n");
+    assert!(legacy.default.is_none());
+    assert_eq!(found(&legacy, "default").as_deref(), Some("crates.default"));
+    assert_eq!(found(&legacy, "crates").as_deref(), Some("crates.crates"));
+    assert_eq!(found(&legacy, "version").as_deref(), Some("crates.version"));
+    assert_eq!(found(&legacy, "anything"), None);
+
+    // sections and crate names do not mix
+    let mixed = "version: 2\ndefault:\n    RULE\nfoo:\n    RULE\n".replace("RULE", ANYONE);
+    assert!(AuthRules::from_yaml(&mixed).is_err());
+    let unknown = "version: 3\ndefault:\n    RULE\n".replace("RULE", ANYONE);
+    let RulesError::Invalid(problems) = AuthRules::from_yaml(&unknown).unwrap_err() else {
+        panic!("expected invalid rules");
+    };
+    assert_eq!(problems[0].path, ["version"]);
+}
+
+#[test]
+fn globs_match_any_run_of_characters() {
+    let matches = |pattern: &str, name: &str| {
+        rules(&format!(
+            "version: 2\ncrates:\n  \"{pattern}\":\n    RULE\n"
+        ))
+        .get(name)
+        .is_some()
+    };
+    assert!(matches("acme-*", "acme-"));
+    assert!(matches("acme-*", "acme-core"));
+    assert!(!matches("acme-*", "acme"));
+    assert!(matches("*-macros", "serde-macros"));
+    assert!(!matches("*-macros", "serde-macros-impl"));
+    assert!(matches("a*b*c", "abc"));
+    assert!(matches("a*b*c", "a-b-b-c"));
+    assert!(!matches("a*b*c", "a-c-b"));
+    assert!(matches("exact", "exact"));
+    assert!(!matches("exact", "exactly"));
+    // both sides of a `*` cannot claim the same characters
+    assert!(!matches("ab*ba", "aba"));
+}
+
+#[test]
+fn problems_point_at_the_rule_under_its_section() {
+    let src = "version: 2
+crates:
+  acme:
+    read: !anyone
+    write: !anyone
+namespaces:
+  # the same key, but here it lacks write rules
+  acme:
+    read: !anyone
+default:
+    read: !anyone
+";
+    let RulesError::Invalid(problems) = AuthRules::from_yaml(src).unwrap_err() else {
+        panic!("expected invalid rules");
+    };
+    let lines = problems
+        .iter()
+        .map(|problem| problem.line(src))
+        .collect::<Vec<_>>();
+    assert_eq!(lines, [Some(8), Some(10)]);
+
+    let legacy = "acme:\n  read: !anyone\n";
+    let RulesError::Invalid(problems) = AuthRules::from_yaml(legacy).unwrap_err() else {
+        panic!("expected invalid rules");
+    };
+    assert_eq!(problems[0].path, ["acme"]);
+    assert_eq!(problems[0].line(legacy), Some(1));
+}
+
+#[tokio::test]
+async fn reload_swaps_the_rules_in_effect() {
+    let auth = GitHubAuth::new_from_config(rules("foo:\n    RULE\n"));
+    assert!(auth.rules().get("foo").is_some());
+
+    auth.reload(rules("bar:\n    RULE\n"));
+    assert!(auth.rules().get("foo").is_none());
+    assert!(auth.rules().get("bar").is_some());
+}
@@ -0,0 +1,295 @@
+//! Exchanging CI OIDC tokens for publish tokens, with the issuer's keys read from a file or
+//! served from the test process.
+
+use std::sync::{
+    Arc,
+    atomic::{AtomicUsize, Ordering},
+};
+
+use axum::{
+    Router,
+    http::{HeaderValue, StatusCode},
+    routing,
+};
+use base64::{Engine, prelude::BASE64_URL_SAFE_NO_PAD};
+use gdynya::{
+    api_schema::CrateName,
+    auth::{
+        Auth, Mutation,
+        github::GitHubAuth,
+        oidc::{OidcIssuer, TrustedPublishing, TrustedPublishingConfig},
+        rules::AuthRules,
+    },
+    axum_aux::RawAuthorization,
+};
+use headers::Header;
+use jsonwebtoken::{Algorithm, EncodingKey};
+use serde_json::json;
+use tokio::net::TcpListener;
+
+const ISSUER: &str = "https://token.actions.example.com";
+const AUDIENCE: &str = "gdynya";
+const SIGNING_KEY: &[u8] = include_bytes!("fixtures/oidc-signing-key.pem");
+
+const VERS: semver::Version = semver::Version::new(1, 0, 0);
+const PUBLISH: Mutation = Mutation::Publish {
+    vers: &VERS,
+    cksum: "0000",
+    new: false,
+};
+
+const RULES: &str = r#"
+version: 2
+crates:
+  released:
+    read: !is {user: alice}
+    write: !is {user: alice}
+    trusted_publishers:
+      - issuer: https://token.actions.example.com
+        repository: acme/released
+        workflow: release.yml
+        ref: refs/tags/v*
+  other:
+    read: !is {user: alice}
+    write: !is {user: alice}
+"#;
+
+async fn publishing() -> TrustedPublishing {
+    publishing_from(OidcIssuer {
+        issuer: ISSUER.to_string(),
+        audience: AUDIENCE.to_string(),
+        jwks_url: None,
+        jwks_file: Some(
+            concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/oidc-jwks.json").into(),
+        ),
+    })
+    .await
+}
+
+async fn publishing_from(issuer: OidcIssuer) -> TrustedPublishing {
+    let config = TrustedPublishingConfig {
+        issuers: vec![issuer],
+        ..Default::default()
+    };
+    let github = GitHubAuth::new_from_config(AuthRules::from_yaml(RULES).unwrap())
+        .with_service_token("service-token");
+    TrustedPublishing::new(&config, github).await.unwrap()
+}
+
+fn now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+fn claims() -> serde_json::Value {
+    json!({
+        "iss": ISSUER,
+        "aud": AUDIENCE,
+        "sub": "repo:acme/released:ref:refs/tags/v1.0.0",
+        "iat": now(),
+        "exp": now() + 300,
+        "repository": "acme/released",
+        "workflow_ref": "acme/released/.github/workflows/release.yml@refs/tags/v1.0.0",
+        "ref": "refs/tags/v1.0.0",
+    })
+}
+
+fn sign(claims: &serde_json::Value) -> String {
+    sign_as("test", claims)
+}
+
+fn sign_as(kid: &str, claims: &serde_json::Value) -> String {
+    let mut header = jsonwebtoken::Header::new(Algorithm::ES256);
+    header.kid = Some(kid.to_string());
+    jsonwebtoken::encode(
+        &header,
+        claims,
+        &EncodingKey::from_ec_pem(SIGNING_KEY).unwrap(),
+    )
+    .unwrap()
+}
+
+fn with(patch: serde_json::Value) -> serde_json::Value {
+    let mut claims = claims();
+    for (key, value) in patch.as_object().unwrap() {
+        claims[key] = value.clone();
+    }
+    claims
+}
+
+fn token(value: &str) -> RawAuthorization {
+    let value = HeaderValue::from_str(value).unwrap();
+    RawAuthorization::decode(&mut std::iter::once(&value)).unwrap()
+}
+
+fn name(name: &str) -> CrateName {
+    name.parse().unwrap()
+}
+
+async fn exchange(
+    auth: &TrustedPublishing,
+    claims: &serde_json::Value,
+) -> Result<RawAuthorization, StatusCode> {
+    auth.exchange(&sign(claims), &name("released"))
+        .await
+        .map(|issued| token(&issued.token))
+        .map_err(|e| e.error_type)
+}
+
+#[tokio::test]
+async fn matching_workflow_can_publish_its_crate_only() {
+    let auth = publishing().await;
+    let publish_token = exchange(&auth, &claims()).await.unwrap();
+
+    for new in [true, false] {
+        let publish = Mutation::Publish {
+            vers: &VERS,
+            cksum: "0000",
+            new,
+        };
+        auth.writable(&publish_token, &name("released"), &publish)
+            .await
+            .unwrap();
+    }
+    auth.readable(Some(&publish_token), &name("released"))
+        .await
+        .unwrap();
+    let denied = [
+        auth.writable(
+            &publish_token,
+            &name("released"),
+            &Mutation::Yank { vers: &VERS },
+        )
+        .await,
+        auth.writable(&publish_token, &name("released"), &Mutation::Owners)
+            .await,
+        auth.writable(&publish_token, &name("other"), &PUBLISH)
+            .await,
+        auth.readable(Some(&publish_token), &name("other")).await,
+    ];
+    for result in denied {
+        assert_eq!(result.unwrap_err().error_type, StatusCode::FORBIDDEN);
+    }
+}
+
+#[tokio::test]
+async fn claims_must_match_a_trusted_publisher() {
+    let auth = publishing().await;
+    for patch in [
+        json!({"repository": "mallory/released"}),
+        json!({"workflow_ref": "acme/released/.github/workflows/ci.yml@refs/tags/v1.0.0"}),
+        json!({"ref": "refs/heads/main"}),
+    ] {
+        assert_eq!(
+            exchange(&auth, &with(patch)).await.err(),
+            Some(StatusCode::FORBIDDEN)
+        );
+    }
+    let untrusted = auth.exchange(&sign(&claims()), &name("other")).await;
+    assert_eq!(untrusted.err().unwrap().error_type, StatusCode::FORBIDDEN);
+}
+
+#[tokio::test]
+async fn invalid_oidc_tokens_are_rejected() {
+    let auth = publishing().await;
+    for patch in [
+        json!({"exp": now() - 3600}),
+        json!({"aud": "someone-else"}),
+        json!({"iss": "https://issuer.example.org"}),
+    ] {
+        assert_eq!(
+            exchange(&auth, &with(patch)).await.err(),
+            Some(StatusCode::UNAUTHORIZED)
+        );
+    }
+
+    let mut tampered = sign(&claims());
+    tampered.pop();
+    let result = auth.exchange(&tampered, &name("released")).await;
+    assert_eq!(result.err().unwrap().error_type, StatusCode::UNAUTHORIZED);
+
+    // signed with the JWKS itself as a shared secret
+    let forged = jsonwebtoken::encode(
+        &jsonwebtoken::Header::new(Algorithm::HS256),
+        &claims(),
+        &EncodingKey::from_secret(include_bytes!("fixtures/oidc-jwks.json")),
+    )
+    .unwrap();
+    let result = auth.exchange(&forged, &name("released")).await;
+    assert_eq!(result.err().unwrap().error_type, StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn the_key_decides_the_algorithm_not_the_token() {
+    let auth = publishing().await;
+    let signed = sign(&claims());
+    let (_, rest) = signed.split_once('.').unwrap();
+    for alg in ["ES384", "RS256", "PS256", "EdDSA"] {
+        let header = json!({"typ": "JWT", "alg": alg, "kid": "test"});
+        let header = BASE64_URL_SAFE_NO_PAD.encode(header.to_string());
+        let result = auth
+            .exchange(&format!("{header}.{rest}"), &name("released"))
+            .await;
+        let e = result.err().unwrap();
+        assert_eq!(e.error_type, StatusCode::UNAUTHORIZED);
+        assert!(
+            e.message.contains("its key is not for"),
+            "{alg}: {}",
+            e.message
+        );
+    }
+}
+
+#[tokio::test]
+async fn unknown_keys_do_not_refetch_the_jwks_every_time() {
+    let fetches = Arc::new(AtomicUsize::new(0));
+    let app = Router::new().route(
+        "/jwks",
+        routing::get({
+            let fetches = fetches.clone();
+            || async move {
+                fetches.fetch_add(1, Ordering::Relaxed);
+                include_str!("fixtures/oidc-jwks.json")
+            }
+        }),
+    );
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+    let auth = publishing_from(OidcIssuer {
+        issuer: ISSUER.to_string(),
+        audience: AUDIENCE.to_string(),
+        jwks_url: Some(format!("http://{addr}/jwks")),
+        jwks_file: None,
+    })
+    .await;
+
+    assert!(exchange(&auth, &claims()).await.is_ok());
+    for i in 0..5 {
+        let result = auth
+            .exchange(
+                &sign_as(&format!("unknown-{i}"), &claims()),
+                &name("released"),
+            )
+            .await;
+        assert_eq!(result.err().unwrap().error_type, StatusCode::UNAUTHORIZED);
+    }
+    assert_eq!(fetches.load(Ordering::Relaxed), 1);
+}
+
+#[tokio::test]
+async fn revoked_publish_tokens_stop_working() {
+    let auth = publishing().await;
+    let publish_token = exchange(&auth, &claims()).await.unwrap();
+    auth.revoke(&publish_token).await.unwrap();
+    let result = auth
+        .writable(&publish_token, &name("released"), &PUBLISH)
+        .await;
+    assert_eq!(result.unwrap_err().error_type, StatusCode::UNAUTHORIZED);
+
+    let made_up = token("gdo_AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA");
+    let result = auth.readable(Some(&made_up), &name("released")).await;
+    assert_eq!(result.unwrap_err().error_type, StatusCode::UNAUTHORIZED);
+}
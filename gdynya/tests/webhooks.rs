@@ -0,0 +1,139 @@
+//! Webhook deliveries to a receiver served from the test process.
+
+mod common;
+
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use axum::{
+    Router,
+    body::Bytes,
+    extract::State,
+    http::{HeaderMap, StatusCode},
+    routing,
+};
+use common::name;
+use gdynya::{
+    audit::{Action, AuditRecord},
+    auth::{Principal, Provider},
+    webhook::{Event, EventKind, SIGNATURE_HEADER, WebhookConfig, Webhooks, signature},
+};
+use tokio::net::TcpListener;
+
+const SECRET: &str = "hook-secret";
+
+#[derive(Default)]
+struct Receiver {
+    // responses still to fail with 500
+    failures: usize,
+    received: Vec<Event>,
+}
+
+type Shared = Arc<Mutex<Receiver>>;
+
+async fn receive(State(receiver): State<Shared>, headers: HeaderMap, body: Bytes) -> StatusCode {
+    let signed = headers
+        .get(SIGNATURE_HEADER)
+        .and_then(|value| value.to_str().ok());
+    if signed != Some(signature(SECRET, &body).as_str()) {
+        return StatusCode::UNAUTHORIZED;
+    }
+    let mut receiver = receiver.lock().unwrap();
+    if receiver.failures > 0 {
+        receiver.failures -= 1;
+        return StatusCode::INTERNAL_SERVER_ERROR;
+    }
+    receiver
+        .received
+        .push(serde_json::from_slice(&body).unwrap());
+    StatusCode::NO_CONTENT
+}
+
+async fn spawn_receiver(failures: usize) -> (Shared, String) {
+    let receiver = Arc::new(Mutex::new(Receiver {
+        failures,
+        ..Default::default()
+    }));
+    let app = Router::new()
+        .route("/hook", routing::post(receive))
+        .with_state(receiver.clone());
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+    (receiver, format!("http://{addr}/hook"))
+}
+
+fn hook(url: &str, crates: &[&str], events: Vec<EventKind>) -> WebhookConfig {
+    WebhookConfig {
+        url: url.to_string(),
+        secret: SECRET.to_string(),
+        crates: crates.iter().map(ToString::to_string).collect(),
+        events,
+        max_attempts: 3,
+    }
+}
+
+fn published(crate_name: &str) -> Event {
+    let record = AuditRecord::new(Action::Publish)
+        .with_crate(&name(crate_name))
+        .with_version(&semver::Version::new(1, 0, 0))
+        .by(&Principal::new(Provider::GitHub, "alice"));
+    Event::of(&record).unwrap()
+}
+
+async fn settle() {
+    tokio::time::sleep(Duration::from_millis(300)).await;
+}
+
+#[tokio::test]
+async fn failed_deliveries_are_retried_and_logged() {
+    let (receiver, url) = spawn_receiver(1).await;
+    let webhooks =
+        Webhooks::new(vec![hook(&url, &[], Vec::new())]).with_backoff(Duration::from_millis(10));
+    let event = published("foo");
+    webhooks.emit(event.clone());
+    settle().await;
+
+    assert_eq!(receiver.lock().unwrap().received, vec![event.clone()]);
+    let deliveries = webhooks.deliveries();
+    assert_eq!(deliveries.len(), 2);
+    assert!(deliveries[0].delivered && deliveries[0].attempt == 2);
+    assert_eq!(deliveries[1].status, Some(500));
+    assert!(
+        deliveries
+            .iter()
+            .all(|delivery| delivery.event_id == event.id)
+    );
+}
+
+#[tokio::test]
+async fn hooks_only_get_the_crates_and_events_they_ask_for() {
+    let (receiver, url) = spawn_receiver(0).await;
+    let webhooks = Webhooks::new(vec![hook(&url, &["acme-*"], vec![EventKind::Publish])]);
+    webhooks.emit(published("other"));
+    webhooks.emit(published("acme_widget"));
+    let yank = AuditRecord::new(Action::Yank)
+        .with_crate(&name("acme-widget"))
+        .with_version(&semver::Version::new(1, 0, 0));
+    webhooks.emit(Event::of(&yank).unwrap());
+    settle().await;
+
+    let received = receiver.lock().unwrap().received.clone();
+    assert_eq!(received.len(), 1);
+    assert_eq!(received[0].crate_name, "acme-widget");
+
+    // failed and unrelated changes are not announced
+    let denied = AuditRecord::new(Action::Publish)
+        .with_crate(&name("acme-widget"))
+        .with_result::<()>(&Err(gdynya::HttpError {
+            error_type: StatusCode::FORBIDDEN,
+            message: "forbidden".to_string(),
+            verbose_message: String::new(),
+            contexts: Vec::new(),
+            retry_after: None,
+        }));
+    assert!(Event::of(&denied).is_none());
+    assert!(Event::of(&AuditRecord::new(Action::CreateToken)).is_none());
+}